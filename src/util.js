@@ -22,6 +22,41 @@ export const steno_keys = [
     "-D", "-Z"
 ];
 
+// the description of extended English Stenotype, with the '^' and '+'
+// keys and a second number and star key (see EXTENDED_STENOTYPE in
+// system.rs). pass this to loadJson for dictionaries that use them.
+export const extended_stenotype = JSON.stringify({
+    name: "Extended English Stenotype",
+    keys: "#- ^- +- S- T- K- P- W- H- R- A- O- *- -* -E -U -F -R -P -B -L -G -T -S -D -Z -#",
+    implicit_hyphen_keys: "A- O- *- -* -E -U",
+    number_key: "#-",
+    numbers: "S-:1 T-:2 P-:3 H-:4 A-:5 O-:0 -F:6 -P:7 -L:8 -T:9",
+    undo_stroke: "*"
+});
+
+// the description of Palantype (see PALANTYPE in system.rs)
+export const palantype = JSON.stringify({
+    name: "Palantype",
+    keys: "S- C- P- T- H- +- M- F- R- N- L- Y- O- E- A- U -I -. -N -L -C -M -F -R -P -T -+ -S -H",
+    implicit_hyphen_keys: "O- E- A- U -I -."
+});
+
+// converts the keys of a steno system description into a list like
+// steno_keys, where the implicit hyphen keys don't have dashes.
+export function systemKeylist(description) {
+    const system = JSON.parse(description);
+    const implicit_hyphen_keys = (system.implicit_hyphen_keys || "").split(" ");
+    return system.keys.split(" ")
+	.filter(key => key.length > 0)
+	.map(key => implicit_hyphen_keys.includes(key) ? key.replace("-", "") : key);
+}
+
+// bytes per packed stroke, unless the steno system says otherwise. 0
+// stands for varints. this matches StrokeWidth in system.rs.
+export function strokeWidth(keys = steno_keys) {
+    return keys.length <= 24 ? 3 : 4;
+}
+
 const number_keys = {
     "0": "O-",
     "1": "S-",
@@ -50,14 +85,14 @@ export function keylistToStroke(list) {
     return stroke;
 }
 
-export function strokeToText(stroke) {
+export function strokeToText(stroke, keys = steno_keys) {
     let text = "";
     let needs_separator = true;
 
     // since the first key is stored in the lsb, we can simply shift
     // the stroke right by one each time and check bit 0 each time,
     // to go through all keys in the right order.
-    for (const key of steno_keys) {
+    for (const key of keys) {
 	if (stroke & 1) {
 	    // startswith is not needed here, since key is guaranteed
 	    // to have at least one character, so this is safe to do
@@ -73,16 +108,31 @@ export function strokeToText(stroke) {
 		needs_separator = false;
 	    }
 	}
-	stroke >>= 1;
+	stroke >>>= 1;
     }
     return text;
 }
 
-export function packedStrokesToText(strokes) {
+export function packedStrokesToText(strokes, keys = steno_keys, width = strokeWidth(keys)) {
     let texts = [];
-    for (let i = 0; i < strokes.length; i += 3) {
-	let stroke = strokes[i] | (strokes[i+1] << 8) | (strokes[i+2] << 16);
-	texts.push(strokeToText(stroke));
+    let i = 0;
+    while (i < strokes.length) {
+	let stroke = 0;
+	if (width == 0) {
+	    // varint: 7 bits per byte, the top bit says if there's more
+	    let shift = 0;
+	    do {
+		stroke |= (strokes[i] & 0x7F) << shift;
+		shift += 7;
+	    } while (strokes[i++] & 0x80);
+	}
+	else {
+	    for (let byte = 0; byte < width; byte++) {
+		stroke |= strokes[i + byte] << (8 * byte);
+	    }
+	    i += width;
+	}
+	texts.push(strokeToText(stroke, keys));
     }
     return texts.join("/");
 }
@@ -137,12 +187,22 @@ export function textToStroke(text) {
     return stroke;
 }
 
-export function strokeListToPackedStrokes(stroke_list) {
+export function strokeListToPackedStrokes(stroke_list, keys = steno_keys, width = strokeWidth(keys)) {
     let strokes = [];
-    for (const stroke of stroke_list) {
-        strokes.push(stroke & 0xFF);
-        strokes.push((stroke >> 8) & 0xFF);
-        strokes.push((stroke >> 16) & 0xFF);
+    for (let stroke of stroke_list) {
+	if (width == 0) {
+	    stroke >>>= 0;
+	    while (stroke >= 0x80) {
+		strokes.push((stroke & 0x7F) | 0x80);
+		stroke >>>= 7;
+	    }
+	    strokes.push(stroke);
+	}
+	else {
+	    for (let byte = 0; byte < width; byte++) {
+		strokes.push((stroke >> (8 * byte)) & 0xFF);
+	    }
+	}
     }
 
     return new Uint8Array(strokes);
@@ -2,14 +2,16 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
-import { packedStrokesToText, textToStroke } from './util';
+import { packedStrokesToText, textToStroke, steno_keys, systemKeylist } from './util';
 
 let text_decoder = new TextDecoder("utf-8");
 let text_encoder = new TextEncoder("utf-8");
 
 let global_module;
 
-export async function initialize (dictionary_data = undefined) {
+// keys is the key list of the steno system the dictionary was loaded
+// with (see systemKeylist in util.js)
+export async function initialize (dictionary_data = undefined, keys = steno_keys) {
 
     let url = './helpers.wasm';
 
@@ -42,13 +44,14 @@ export async function initialize (dictionary_data = undefined) {
 
     // instanciate the module as well, given the dictionary
     if (dictionary_data) {
-	let instance = await instanciate(global_module);
+	let instance = await instanciate(global_module, keys);
 	return prepare_instance_for_querying(instance, dictionary_data);
     }
 }
 
 // takes a promise for a module, returns a promise for an instance.
-async function instanciate(module) {
+// keys are used to turn the strokes of results into text.
+async function instanciate(module, keys = steno_keys) {
 
     // there is a bit of a chicken-and-egg problem here, where we want the module import functions
     // to capture the modules memory, so there always in sync, but we need to provide the functions
@@ -60,15 +63,35 @@ async function instanciate(module) {
 
     // i'm going to try and do the same thing with the results array
     let results = [];
+    // how the strokes in the dictionary are packed, see StrokeWidth in
+    // system.rs. this is set once we know which dictionary we're querying.
+    let stroke_width = 3;
+    function set_stroke_width (width) {
+	stroke_width = width;
+    }
+    // whether the wasm module formats the strokes itself, see query in
+    // wasm-module/src/lib.rs. if it does, yield_result gets text instead
+    // of packed strokes.
+    let stroke_format = 0;
+    function set_stroke_format (format) {
+	stroke_format = format;
+    }
     let last_error;
     
-    function logErr (message_offset, message_length, details_offset, details_length, line) {
+    function logErr (message_offset, message_length, details_offset, details_length, line,
+		     input_offset, input_line, input_column, excerpt_offset, excerpt_length) {
 	if (memory) {
 	    const message_buffer = new Uint8Array(memory.buffer, message_offset, message_length);
 	    const details_buffer = new Uint8Array(memory.buffer, details_offset, details_length);
 
 	    const message = text_decoder.decode(message_buffer);
-	    const details = text_decoder.decode(details_buffer);
+	    let details = text_decoder.decode(details_buffer);
+
+	    // input_line is 0 if the error isn't about a specific place in the input
+	    if (input_line > 0) {
+		const excerpt = text_decoder.decode(new Uint8Array(memory.buffer, excerpt_offset, excerpt_length));
+		details += ` (line ${input_line}, column ${input_column}, byte ${input_offset}: "${excerpt}")`;
+	    }
 	    
 	    console.log(`WebAssembly module panicked with '${message} (${details})' on line ${line}`);
 	    last_error = { message: message, details: details };
@@ -84,18 +107,94 @@ async function instanciate(module) {
 
     // strokes_offset is a ptr, strokes_length is in units of the contained type (ie 4 bytes)
     // handily, this is just how the constructor for Uint32Array works!
-    function yield_result (string_offset, string_length, strokes_offset, strokes_length) {
-	let string = text_decoder.decode(new Uint8Array(memory.buffer, string_offset, string_length));
+    // source is the index of the dictionary in the stack that the result came from.
+    // exact is 0 if the result only matched because case, accents or formatting were ignored.
+    // attachment says how the translation attaches to the words around it.
+    // match_start and match_length are the bytes of the translation that
+    // matched the query. they're turned into [start, end] positions in
+    // the string, for highlighting.
+    const attachment_names = ["none", "suffix", "prefix", "infix", "glue", "punctuation"];
+    function yield_result (string_offset, string_length, strokes_offset, strokes_length, source, exact, attachment,
+			   match_start, match_length) {
+	let string_bytes = new Uint8Array(memory.buffer, string_offset, string_length);
+	let string = text_decoder.decode(string_bytes);
+	let start = text_decoder.decode(string_bytes.subarray(0, match_start)).length;
+	let end = start + text_decoder.decode(string_bytes.subarray(match_start, match_start + match_length)).length;
 	let strokes = new Uint8Array(memory.buffer, strokes_offset, strokes_length);
-	results.push([packedStrokesToText(strokes), string]);
+	if (stroke_format == 0) {
+	    results.push([packedStrokesToText(strokes, keys, stroke_width), string, source, exact == 1, attachment_names[attachment], [start, end]]);
+	}
+	else {
+	    results.push([text_decoder.decode(strokes), string, source, exact == 1, attachment_names[attachment], [start, end]]);
+	}
+    }
+
+    // problems with single strokes, found while loading a dictionary.
+    // severity is 0 for warnings and 1 for errors, problem is one of
+    // the values of Problem in validation.rs.
+    const problem_names = ["unknown key", "keys out of steno order", "empty stroke", "duplicate key",
+			   "misplaced hyphen", "missing hyphen", "misplaced number key"];
+    const diagnostics = [];
+    function yield_diagnostic (dictionary, entry, strokes_offset, strokes_length, offset, severity, problem) {
+	let strokes = text_decoder.decode(new Uint8Array(memory.buffer, strokes_offset, strokes_length));
+	diagnostics.push({
+	    dictionary: dictionary,
+	    entry: entry,
+	    strokes: strokes,
+	    offset: offset,
+	    severity: severity == 1 ? "error" : "warning",
+	    problem: problem_names[problem]
+	});
     }
 
-    let instance = await WebAssembly.instantiate(await module, { env: { logErr: logErr, yield_result: yield_result }});
+    // entries of the same dictionary that ended up with the same strokes
+    const collisions = [];
+    function yield_collision (dictionary, entry, strokes_offset, strokes_length,
+			      translation_offset, translation_length,
+			      previous_translation_offset, previous_translation_length) {
+	const decode = (offset, length) => text_decoder.decode(new Uint8Array(memory.buffer, offset, length));
+	collisions.push({
+	    dictionary: dictionary,
+	    entry: entry,
+	    strokes: decode(strokes_offset, strokes_length),
+	    translation: decode(translation_offset, translation_length),
+	    previous_translation: decode(previous_translation_offset, previous_translation_length)
+	});
+    }
+
+    // the result of normalize_stroke_text, this only ever holds one value
+    const normalized_strokes = [];
+    function yield_normalized_strokes (text_offset, text_length, strokes_offset, strokes_length) {
+	normalized_strokes.splice(0, normalized_strokes.length, {
+	    text: text_decoder.decode(new Uint8Array(memory.buffer, text_offset, text_length)),
+	    // copy these, they're on the wasm stack
+	    strokes: new Uint8Array(memory.buffer, strokes_offset, strokes_length).slice()
+	});
+    }
+
+    // the result of memory_usage, in bytes. this only ever holds one value
+    const memory_usage = [];
+    function yield_memory_usage (strokes_table, strings_table, folded_table, visible_table,
+				 translation_index, length_index, substring_index, outline_index, total) {
+	memory_usage.splice(0, memory_usage.length, {
+	    strokes_table: strokes_table,
+	    strings_table: strings_table,
+	    folded_table: folded_table,
+	    visible_table: visible_table,
+	    translation_index: translation_index,
+	    length_index: length_index,
+	    substring_index: substring_index,
+	    outline_index: outline_index,
+	    total: total
+	});
+    }
+
+    let instance = await WebAssembly.instantiate(await module, { env: { logErr: logErr, yield_result: yield_result, yield_diagnostic: yield_diagnostic, yield_collision: yield_collision, yield_normalized_strokes: yield_normalized_strokes, yield_memory_usage: yield_memory_usage }});
 
     // store reference to memory so that logErr (and yield_result) work
     memory = instance.exports.memory;
 
-    return {instance: instance, results: results, get_last_error: get_last_error};
+    return {instance: instance, results: results, diagnostics: diagnostics, collisions: collisions, get_last_error: get_last_error, set_stroke_width: set_stroke_width, set_stroke_format: set_stroke_format, normalized_strokes: normalized_strokes, memory_usage: memory_usage};
 }
 
 function prepare_instance_for_querying(instance_info, dictionary_data) {
@@ -119,11 +218,28 @@ function prepare_instance_for_querying(instance_info, dictionary_data) {
     let wasm_data = new Uint8Array(instance.exports.memory.buffer, base_offset, data_size);
     wasm_data.set(dictionary_data);
 
+    // the stroke width is the third value of the usize buffer, which
+    // comes right after the 12-byte header
+    const stroke_width = new DataView(wasm_data.buffer, base_offset).getUint32(12 + 2 * 4, true);
+    instance_info.set_stroke_width(stroke_width);
+
+    // let the wasm module write out the strokes, so they look the same
+    // everywhere (1 means plover's way of writing them, with digits)
+    const stroke_format = 1;
+    instance_info.set_stroke_format(stroke_format);
+
 
     let data_start = base_offset;
     // define the two query functions here, so they can capture
     // all necessary variables and gain correct scoping automatically
-    function lookup(query) {
+    // mode is one of the keys of lookup_modes. with "folded", case and
+    // accents are ignored, so "monday" also finds "Monday", and with
+    // "visible", plover's formatting is, so "ing" also finds "{^ing}".
+    // "substring" finds every translation that contains the query.
+    // the fourth field of each result says whether it matched exactly,
+    // the fifth how the translation attaches to the words around it, and
+    // the sixth which part of the translation matched, as [start, end].
+    function lookup(query, mode = "exact") {
 
 	const start = performance.now();
 
@@ -139,7 +255,7 @@ function prepare_instance_for_querying(instance_info, dictionary_data) {
 	try {
 	    instance.exports.query(query_start, encoded_query.length,
 				data_start,
-				0);
+				lookup_modes[mode], stroke_format);
 	}
 	catch (e) {
 	    let last_error = instance_info.get_last_error();
@@ -172,7 +288,7 @@ function prepare_instance_for_querying(instance_info, dictionary_data) {
 	try {
 	    instance.exports.query(query_start, query.length,
 				data_start,
-				1);
+				1, stroke_format);
 	}
 	catch (e) {
 	    let last_error = instance_info.get_last_error();
@@ -188,11 +304,239 @@ function prepare_instance_for_querying(instance_info, dictionary_data) {
 	return results.slice();
     }
 
+    // all outlines starting with the given packed strokes (like for
+    // find_strokes), page_size of them at a time, the shortest ones
+    // first. returns { total, results }, like complete.
+    function complete_strokes(strokes, page = 0, page_size = 20) {
+
+	const start = performance.now();
+
+	// limit length to 100 bytes, since that's how much is reserved
+	const prefix = strokes.subarray(0, 100);
+
+	let wasm_prefix = new Uint8Array(instance.exports.memory.buffer, query_start, prefix.length);
+	wasm_prefix.set(prefix);
+
+	results.splice(0, results.length);
+	let total;
+	try {
+	    total = instance.exports.outline_prefix_search(query_start, prefix.length,
+							   data_start,
+							   page * page_size, page_size,
+							   stroke_format);
+	}
+	catch (e) {
+	    let last_error = instance_info.get_last_error();
+	    if (last_error) {
+		throw last_error;
+	    }
+	    else {
+		throw `Error in WebAssembly module: ${e} (this probably shouldn't have happened)`;
+	    }
+	}
+
+	console.log(`outline prefix search took ${performance.now() - start}ms`);
+	return { total: total, results: results.slice() };
+    }
+
+    // all outlines whose strokes match the patterns. each pattern is
+    // { required, forbidden }, two strokes as returned by textToStroke:
+    // the keys the stroke must have, and the ones it mustn't have. with
+    // position "whole", the outline has to have one stroke for each
+    // pattern, with "anywhere", the patterns can match any strokes in a
+    // row, and with "at", they have to start at the stroke with the given
+    // index. at most 12 patterns fit into the space reserved for queries.
+    function find_pattern(patterns, position = "anywhere", stroke_index = 0) {
+
+	const start = performance.now();
+
+	// the wasm module reads these as u32s, so they need to be aligned
+	const patterns_start = Math.ceil(query_start / 4) * 4;
+	const max_patterns = Math.floor((query_start + 100 - patterns_start) / 8);
+	patterns = patterns.slice(0, max_patterns);
+
+	let wasm_patterns = new Uint32Array(instance.exports.memory.buffer, patterns_start, 2 * patterns.length);
+	patterns.forEach((pattern, index) => {
+	    wasm_patterns[2 * index] = pattern.required;
+	    wasm_patterns[2 * index + 1] = pattern.forbidden;
+	});
+
+	results.splice(0, results.length);
+	try {
+	    instance.exports.pattern_search(patterns_start, patterns.length,
+					    pattern_positions[position], stroke_index,
+					    data_start, stroke_format);
+	}
+	catch (e) {
+	    let last_error = instance_info.get_last_error();
+	    if (last_error) {
+		throw last_error;
+	    }
+	    else {
+		throw `Error in WebAssembly module: ${e} (this probably shouldn't have happened)`;
+	    }
+	}
+
+	console.log(`pattern search took ${performance.now() - start}ms`);
+	return results.slice();
+    }
+
+    // like lookup and find_strokes, but the best results come first:
+    // the ones with the fewest strokes, then the fewest keys, then the
+    // ones without the asterisk, and then the ones from dictionaries with
+    // a higher priority. returns { total, results } for the given page,
+    // like complete.
+    function ranked_lookup(query, page = 0, page_size = 20) {
+	// limit length to 100 bytes, since that's how much is reserved
+	return ranked_query(text_encoder.encode(query).subarray(0, 100), 0, page, page_size);
+    }
+
+    function ranked_find_strokes(strokes, page = 0, page_size = 20) {
+	return ranked_query(strokes.subarray(0, 100), 1, page, page_size);
+    }
+
+    function ranked_query(query, find_stroke, page, page_size) {
+
+	const start = performance.now();
+
+	let wasm_query = new Uint8Array(instance.exports.memory.buffer, query_start, query.length);
+	wasm_query.set(query);
+
+	results.splice(0, results.length);
+	let total;
+	try {
+	    total = instance.exports.ranked_query(query_start, query.length,
+						  data_start, find_stroke,
+						  page * page_size, page_size,
+						  stroke_format);
+	}
+	catch (e) {
+	    let last_error = instance_info.get_last_error();
+	    if (last_error) {
+		throw last_error;
+	    }
+	    else {
+		throw `Error in WebAssembly module: ${e} (this probably shouldn't have happened)`;
+	    }
+	}
+
+	console.log(`ranked query took ${performance.now() - start}ms`);
+	return { total: total, results: results.slice() };
+    }
+
+    // all translations starting with prefix, page_size of them at a time.
+    // returns { total, results }, where total is the number of matches
+    // on all pages together.
+    function complete(prefix, page = 0, page_size = 20) {
+
+	const start = performance.now();
+
+	// limit length to 100 bytes, since that's how much is reserved
+	const encoded_prefix = text_encoder.encode(prefix).subarray(0, 100);
+
+	let wasm_prefix = new Uint8Array(instance.exports.memory.buffer, query_start, encoded_prefix.length);
+	wasm_prefix.set(encoded_prefix);
+
+	results.splice(0, results.length);
+	let total;
+	try {
+	    total = instance.exports.prefix_search(query_start, encoded_prefix.length,
+						   data_start,
+						   page * page_size, page_size,
+						   stroke_format);
+	}
+	catch (e) {
+	    let last_error = instance_info.get_last_error();
+	    if (last_error) {
+		throw last_error;
+	    }
+	    else {
+		throw `Error in WebAssembly module: ${e} (this probably shouldn't have happened)`;
+	    }
+	}
+
+	console.log(`prefix search took ${performance.now() - start}ms`);
+	return { total: total, results: results.slice() };
+    }
+
+    // translations that are at most max_distance typos away from query,
+    // like "receive" for "recieve", the closest ones first. at most limit
+    // results are returned, and limit can be 100 at most. only
+    // translations of up to 32 characters can be found this way.
+    function fuzzy_lookup(query, max_distance = 2, limit = 20) {
+
+	const start = performance.now();
+
+	// limit length to 100 bytes, since that's how much is reserved
+	const encoded_query = text_encoder.encode(query).subarray(0, 100);
+
+	let wasm_query = new Uint8Array(instance.exports.memory.buffer, query_start, encoded_query.length);
+	wasm_query.set(encoded_query);
+
+	results.splice(0, results.length);
+	try {
+	    instance.exports.fuzzy_query(query_start, encoded_query.length,
+					 data_start,
+					 max_distance, limit,
+					 stroke_format);
+	}
+	catch (e) {
+	    let last_error = instance_info.get_last_error();
+	    if (last_error) {
+		throw last_error;
+	    }
+	    else {
+		throw `Error in WebAssembly module: ${e} (this probably shouldn't have happened)`;
+	    }
+	}
+
+	console.log(`fuzzy lookup took ${performance.now() - start}ms`);
+	return results.slice();
+    }
+
+    // how many bytes the dictionary uses for each of its tables and
+    // indexes, and in total
+    function get_memory_usage() {
+	instance.exports.memory_usage(data_start);
+	return instance_info.memory_usage[0];
+    }
+
+    // turns strokes as someone typed them into { text, strokes }, where
+    // text is how plover would write them and strokes can be passed to
+    // find_strokes. throws an error if the strokes don't make sense.
+    function normalize_strokes(text) {
+	const encoded_text = text_encoder.encode(text).subarray(0, 100);
+
+	let wasm_text = new Uint8Array(instance.exports.memory.buffer, query_start, encoded_text.length);
+	wasm_text.set(encoded_text);
+
+	if (!instance.exports.normalize_stroke_text(query_start, encoded_text.length)) {
+	    throw instance_info.get_last_error();
+	}
+	return instance_info.normalized_strokes[0];
+    }
+
     // return wasm_data as well, so that the caller can store it if they want
-    return { lookup: lookup, find_strokes: find_strokes, data: wasm_data };
+    return { lookup: lookup, find_strokes: find_strokes, ranked_lookup: ranked_lookup, ranked_find_strokes: ranked_find_strokes, find_pattern: find_pattern, complete: complete, complete_strokes: complete_strokes, fuzzy_lookup: fuzzy_lookup, get_memory_usage: get_memory_usage, normalize_strokes: normalize_strokes, stroke_width: stroke_width, data: wasm_data };
 }
 
-export async function loadJson (json) {
+// the values of find_stroke in query (wasm-module/src/lib.rs) that look up translations
+const lookup_modes = { "exact": 0, "folded": 2, "visible": 3, "substring": 4 };
+
+// the values of position in pattern_search (wasm-module/src/lib.rs)
+const pattern_positions = { "whole": 0, "anywhere": 1, "at": 2 };
+
+// matches DuplicatePolicy in lib.rs
+const duplicate_policies = { "last-wins": 0, "first-wins": 1, "keep-all": 2 };
+
+// if strict is set, the dictionary is only loaded if all of its strokes
+// are written exactly the way plover would write them. duplicates says
+// which entry to use if several entries have the same strokes. if
+// tolerant is set, trailing commas and // comments are allowed. system
+// is the json description of the steno system the dictionary is written
+// for (see system.rs), the default is English Stenotype. a system can
+// have at most 32 keys.
+export async function loadJson (json, strict = false, duplicates = "last-wins", tolerant = false, system = undefined) {
 
     const wasm_page_size = 65536;
 
@@ -202,8 +546,9 @@ export async function loadJson (json) {
     const instance_info = await instanciate(global_module);
     const wasm = instance_info.instance;
     const data = text_encoder.encode(json);
+    const system_data = system ? text_encoder.encode(system) : new Uint8Array(0);
 
-    const pages_needed = Math.ceil(data.length / wasm_page_size);
+    const pages_needed = Math.ceil((data.length + system_data.length) / wasm_page_size);
 
     const num_base_pages = wasm.exports.memory.grow(pages_needed);
     const base_offset = num_base_pages * 65536;
@@ -212,12 +557,15 @@ export async function loadJson (json) {
     let memoryarray = new Uint8Array(wasm.exports.memory.buffer);
 
     memoryarray.subarray(base_offset, base_offset + data.length).set(data);
+    const system_offset = base_offset + data.length;
+    memoryarray.subarray(system_offset, system_offset + system_data.length).set(system_data);
 
     console.log("before wasm");
     const start = performance.now();
     let info_ptr;
     try {
-	info_ptr = wasm.exports.load_json(base_offset, data.length);
+	wasm.exports.load_steno_system(system_offset, system_data.length);
+	info_ptr = wasm.exports.load_json(base_offset, data.length, strict ? 1 : 0, duplicate_policies[duplicates], tolerant ? 1 : 0);
     }
     // TODO: unify error handling
     catch (e) {
@@ -250,7 +598,11 @@ export async function loadJson (json) {
     // function.
 
     // convenience: load the new dictionary into a query-mode instance
-    let dictionary = prepare_instance_for_querying(await instanciate(global_module), data_array);
+    const keys = system ? systemKeylist(system) : steno_keys;
+    let dictionary = prepare_instance_for_querying(await instanciate(global_module, keys), data_array);
+    dictionary.keys = keys;
+    dictionary.diagnostics = instance_info.diagnostics.slice();
+    dictionary.collisions = instance_info.collisions.slice();
 
     return dictionary;
 }
@@ -0,0 +1,305 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// turns packed strokes back into text, the way plover writes them: keys
+// in steno order, with a hyphen in front of the right bank if there's
+// no vowel (or other implicit hyphen key) to tell the sides apart. the
+// result can always be parsed back into the same stroke.
+//
+// normalize_stroke_text does the round trip for strokes that people
+// type in, so that "TFT", "t-ft" and "T-FT" all end up the same.
+
+use crate::{InternalError, InternalResult, InputPosition, StenoSystem, MAX_STROKE_SIZE, error};
+use crate::validation::check_stroke;
+
+#[derive(Clone, Copy)]
+pub struct FormatOptions<'a> {
+    // goes between the strokes of a multi-stroke entry
+    pub separator: &'a [u8],
+    // if this is set, strokes with the number key are written with
+    // digits where possible, like "50". otherwise they're written with
+    // the keys, like "#AO".
+    pub numbers: bool
+}
+
+impl Default for FormatOptions<'static> {
+    fn default() -> FormatOptions<'static> {
+        FormatOptions {
+            separator: b"/",
+            numbers: true
+        }
+    }
+}
+
+fn append(buffer: &mut [u8], pos: &mut usize, bytes: &[u8]) -> InternalResult<()> {
+    let end = *pos + bytes.len();
+    if end > buffer.len() {
+        return Err(error!(b"Sorry, these strokes are too long to be shown.", b""));
+    }
+
+    buffer[*pos..end].copy_from_slice(bytes);
+    *pos = end;
+    Ok(())
+}
+
+// writes a single stroke into buffer, starting at pos. pos is moved past
+// the text, like with StenoSystem::parse_stroke.
+pub fn format_stroke(system: &StenoSystem, stroke: u32, numbers: bool, buffer: &mut [u8], pos: &mut usize) -> InternalResult<()> {
+    let is_pressed = |key: usize| stroke & (1 << key) != 0;
+
+    // plover only writes digits if there's something to turn into one,
+    // "#" on its own stays the way it is
+    let number_key = system.number_key().filter(|&key| is_pressed(key));
+    let use_digits = numbers
+        && number_key.is_some()
+        && (0..system.num_keys()).any(|key| is_pressed(key) && system.key_digit(key).is_some());
+
+    // where the parser would look for the next key (see parse_stroke)
+    let mut next_key = 0;
+    let mut hyphen_written = false;
+    let mut past_implicit_hyphen = false;
+
+    for key in (0..system.num_keys()).filter(|&key| is_pressed(key)) {
+        if use_digits && Some(key) == number_key {
+            continue;
+        }
+
+        let digit = system.key_digit(key).filter(|_| use_digits);
+        let label = match &digit {
+            Some(digit) => core::slice::from_ref(digit),
+            None => system.key_label(key)
+        };
+
+        // the hyphen is needed if nothing else says we're on the right
+        // bank yet, or if the label would be read as an earlier key
+        // (like the right star in extended English Stenotype)
+        if system.is_right_side_key(key) && !hyphen_written {
+            let needs_hyphen = (!past_implicit_hyphen && !system.is_implicit_hyphen_key(key))
+                || system.find_key(label, next_key) != Some(key);
+
+            if needs_hyphen {
+                append(buffer, pos, b"-")?;
+                hyphen_written = true;
+            }
+        }
+
+        append(buffer, pos, label)?;
+
+        if system.is_implicit_hyphen_key(key) {
+            past_implicit_hyphen = true;
+        }
+        next_key = key + 1;
+    }
+
+    Ok(())
+}
+
+// writes packed strokes (see StrokeWidth) into buffer, with the separator
+// between them. returns the length of the text.
+pub fn format_strokes(system: &StenoSystem, strokes: &[u8], options: &FormatOptions, buffer: &mut [u8]) -> InternalResult<usize> {
+    let stroke_width = system.stroke_width();
+    let mut read_pos = 0;
+    let mut write_pos = 0;
+
+    while read_pos < strokes.len() {
+        // an empty stroke is formatted as nothing at all, so write_pos
+        // can't tell whether this is the first stroke
+        if read_pos > 0 {
+            append(buffer, &mut write_pos, options.separator)?;
+        }
+
+        let stroke = stroke_width.unpack(strokes, &mut read_pos)
+            .ok_or(error!(b"Internal error: the dictionary data is broken.", b"The strokes end in the middle of a stroke."))?;
+        format_stroke(system, stroke, options.numbers, buffer, &mut write_pos)?;
+    }
+
+    Ok(write_pos)
+}
+
+// parses strokes the way someone might type them (with or without the
+// hyphen, with digits or the number key, in lower case) and writes them
+// out again the way plover would, as text and packed (see StrokeWidth).
+// unlike StenoSystem::parse_stroke, this complains about everything it
+// can't make sense of, instead of skipping it. returns the lengths of
+// the text and the packed strokes. lower case letters in input are made
+// upper case in place, unless the system has keys with lower case labels.
+pub fn normalize_stroke_text(system: &StenoSystem, input: &mut [u8], options: &FormatOptions, text: &mut [u8], strokes: &mut [u8]) -> InternalResult<(usize, usize)> {
+    for byte in input.iter_mut() {
+        if byte.is_ascii_lowercase() && system.find_key(&[*byte], 0).is_none() {
+            *byte = byte.to_ascii_uppercase();
+        }
+    }
+
+    // spaces around the strokes don't hurt anyone
+    let start = input.iter().position(|byte| !byte.is_ascii_whitespace()).unwrap_or(input.len());
+    let end = input.iter().rposition(|byte| !byte.is_ascii_whitespace()).map_or(start, |end| end + 1);
+
+    let mut text_length = 0;
+    let mut strokes_length = 0;
+    let mut stroke_start = start;
+
+    loop {
+        let stroke_end = input[stroke_start..end].iter()
+            .position(|&byte| byte == b'/')
+            .map_or(end, |length| stroke_start + length);
+        let stroke_text = &input[stroke_start..stroke_end];
+
+        if let Some((offset, problem)) = check_stroke(system, stroke_text) {
+            return Err(InternalError {
+                message: b"Sorry, we don't understand these strokes.",
+                details: problem.description(),
+                line: line!(),
+                position: Some(InputPosition::new(input, stroke_start + offset))
+            });
+        }
+
+        let stroke = system.parse_stroke(stroke_text, &mut 0);
+
+        let mut packed = [0u8; MAX_STROKE_SIZE];
+        let length = system.stroke_width().pack(stroke, &mut packed);
+        append(strokes, &mut strokes_length, &packed[..length])?;
+
+        if text_length > 0 {
+            append(text, &mut text_length, options.separator)?;
+        }
+        format_stroke(system, stroke, options.numbers, text, &mut text_length)?;
+
+        if stroke_end == end {
+            break;
+        }
+        stroke_start = stroke_end + 1;
+    }
+
+    Ok((text_length, strokes_length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EXTENDED_STENOTYPE, ENGLISH_STENOTYPE, PALANTYPE, Problem};
+
+    fn format(system: &StenoSystem, text: &[u8], options: &FormatOptions) -> String {
+        let mut strokes = Vec::new();
+        let mut pos = 0;
+        while pos < text.len() {
+            let stroke = system.parse_stroke(text, &mut pos);
+            let mut packed = [0u8; crate::MAX_STROKE_SIZE];
+            let length = system.stroke_width().pack(stroke, &mut packed);
+            strokes.extend_from_slice(&packed[..length]);
+        }
+
+        let mut buffer = [0u8; 256];
+        let length = format_strokes(system, &strokes, options, &mut buffer).unwrap();
+        String::from_utf8(buffer[..length].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_format_strokes() {
+        let system = &ENGLISH_STENOTYPE;
+        let options = FormatOptions::default();
+        assert_eq!(format(system, b"TEFT", &options), "TEFT");
+        assert_eq!(format(system, b"TFT", &options), "T-FT");
+        assert_eq!(format(system, b"-G", &options), "-G");
+        assert_eq!(format(system, b"S-S", &options), "S-S");
+        assert_eq!(format(system, b"*S", &options), "*S");
+        assert_eq!(format(system, b"PHRO*FR/TEFT/-G", &options), "PHRO*FR/TEFT/-G");
+        assert_eq!(format(system, b"KPWHREPLGS", &options), "KPWHREPLGS");
+
+        // numbers
+        assert_eq!(format(system, b"#AO", &options), "50");
+        assert_eq!(format(system, b"50", &options), "50");
+        assert_eq!(format(system, b"#-F", &options), "-6");
+        assert_eq!(format(system, b"#S-F", &options), "1-6");
+        assert_eq!(format(system, b"#STK", &options), "12K");
+        assert_eq!(format(system, b"#", &options), "#");
+        assert_eq!(format(system, b"#-D", &options), "#-D");
+
+        let options = FormatOptions { separator: b" ", numbers: false };
+        assert_eq!(format(system, b"50/T-FT", &options), "#AO T-FT");
+        assert_eq!(format(system, b"1-6", &options), "#S-F");
+
+        // an empty stroke still gets its separator
+        let options = FormatOptions::default();
+        let mut strokes = Vec::new();
+        for stroke in [0, system.parse_stroke(b"TEFT", &mut 0)] {
+            let mut packed = [0u8; crate::MAX_STROKE_SIZE];
+            let length = system.stroke_width().pack(stroke, &mut packed);
+            strokes.extend_from_slice(&packed[..length]);
+        }
+        let mut buffer = [0u8; 16];
+        let text_length = format_strokes(system, &strokes, &options, &mut buffer).unwrap();
+        assert_eq!(&buffer[..text_length], b"/TEFT");
+    }
+
+    #[test]
+    fn test_format_other_systems() {
+        let options = FormatOptions::default();
+
+        let system = &EXTENDED_STENOTYPE;
+        assert_eq!(format(system, b"-*", &options), "-*");
+        assert_eq!(format(system, b"A-*", &options), "A-*");
+        assert_eq!(format(system, b"A**", &options), "A**");
+        assert_eq!(format(system, b"^-F", &options), "^-F");
+        assert_eq!(format(system, b"+TEFT-#", &options), "+TEFT#");
+        assert_eq!(format(system, b"50", &options), "50");
+
+        let system = &PALANTYPE;
+        assert_eq!(format(system, b"CAT", &options), "CAT");
+        assert_eq!(format(system, b"C-T", &options), "C-T");
+        assert_eq!(format(system, b"SIN", &options), "SIN");
+        assert_eq!(format(system, b"-.", &options), ".");
+    }
+
+    // the text and packed strokes, or the offset and details of the error
+    type Normalized = Result<(String, Vec<u8>), (usize, &'static [u8])>;
+
+    fn normalize(system: &StenoSystem, input: &str) -> Normalized {
+        let mut input = input.as_bytes().to_vec();
+        let mut text = [0u8; 256];
+        let mut strokes = [0u8; 256];
+        match normalize_stroke_text(system, &mut input, &FormatOptions::default(), &mut text, &mut strokes) {
+            Ok((text_length, strokes_length)) => Ok((
+                String::from_utf8(text[..text_length].to_vec()).unwrap(),
+                strokes[..strokes_length].to_vec()
+            )),
+            Err(error) => Err((error.position.unwrap().offset as usize, error.details))
+        }
+    }
+
+    #[test]
+    fn test_normalize_stroke_text() {
+        let system = &ENGLISH_STENOTYPE;
+        let tft = Ok((String::from("T-FT"), vec![0x04, 0x20, 0x08]));
+        assert_eq!(normalize(system, "T-FT"), tft);
+        assert_eq!(normalize(system, "TFT"), tft);
+        assert_eq!(normalize(system, "t-ft"), tft);
+        assert_eq!(normalize(system, " TFT "), tft);
+        assert_eq!(normalize(system, "#AO").unwrap().0, "50");
+        assert_eq!(normalize(system, "50").unwrap().0, "50");
+        assert_eq!(normalize(system, "tefT/g").unwrap().0, "TEFT/-G");
+        assert_eq!(normalize(system, "PHRO*FR/TEFT").unwrap().1.len(), 6);
+
+        assert_eq!(normalize(system, "TEXT"), Err((2, Problem::UnknownKey.description())));
+        assert_eq!(normalize(system, "TEFT/EA"), Err((6, Problem::OutOfOrder.description())));
+        assert_eq!(normalize(system, "TEFT/"), Err((5, Problem::EmptyStroke.description())));
+        assert_eq!(normalize(system, ""), Err((0, Problem::EmptyStroke.description())));
+        assert_eq!(normalize(system, "AA"), Err((1, Problem::DuplicateKey.description())));
+
+        // extended English Stenotype needs 4 bytes per stroke
+        let (text, strokes) = normalize(&EXTENDED_STENOTYPE, "a-*").unwrap();
+        assert_eq!(text, "A-*");
+        assert_eq!(strokes.len(), 4);
+    }
+
+    #[test]
+    fn test_buffer_too_small() {
+        let system = &ENGLISH_STENOTYPE;
+        let strokes = [0xFF, 0xFF, 0x7F];
+        let mut buffer = [0u8; 8];
+        assert!(format_strokes(system, &strokes, &FormatOptions::default(), &mut buffer).is_err());
+        // a stroke that stops halfway
+        let mut buffer = [0u8; 64];
+        assert!(format_strokes(system, &strokes[..2], &FormatOptions::default(), &mut buffer).is_err());
+    }
+}
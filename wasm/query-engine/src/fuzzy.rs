@@ -0,0 +1,198 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// edit distances for fuzzy lookups. this counts how many characters have
+// to be inserted, deleted, replaced or swapped with their neighbour to get
+// from one string to the other, so "recieve" is 1 away from "receive". to
+// keep this cheap (and free of allocations), it only works for strings of
+// up to MAX_LENGTH characters.
+
+pub const MAX_LENGTH: usize = 32;
+
+// the most matches Closest can keep track of
+pub const MAX_RESULTS: usize = 100;
+
+// the characters of a string, if it's valid utf-8 and short enough
+pub struct Characters {
+    characters: [char; MAX_LENGTH],
+    length: usize
+}
+
+impl Characters {
+    pub fn new(text: &[u8]) -> Option<Characters> {
+        let text = core::str::from_utf8(text).ok()?;
+        let mut characters = Characters {
+            characters: ['\0'; MAX_LENGTH],
+            length: 0
+        };
+
+        for character in text.chars() {
+            *characters.characters.get_mut(characters.length)? = character;
+            characters.length += 1;
+        }
+
+        Some(characters)
+    }
+
+    pub fn as_slice(&self) -> &[char] {
+        &self.characters[..self.length]
+    }
+}
+
+// the number of characters in a utf-8 string (continuation bytes don't count)
+pub fn character_count(text: &[u8]) -> usize {
+    text.iter().filter(|&&byte| byte & 0xC0 != 0x80).count()
+}
+
+// the edit distance between a and b, or None if it's more than max_distance
+pub fn distance(a: &[char], b: &[char], max_distance: usize) -> Option<usize> {
+    if a.len().abs_diff(b.len()) > max_distance {
+        return None;
+    }
+
+    // the last three rows of the usual dynamic programming table (the one
+    // before the previous one is needed for swaps)
+    let mut before_previous = [0usize; MAX_LENGTH + 1];
+    let mut previous = [0usize; MAX_LENGTH + 1];
+    let mut current = [0usize; MAX_LENGTH + 1];
+
+    for (j, cell) in previous[..=b.len()].iter_mut().enumerate() {
+        *cell = j;
+    }
+
+    for i in 1..=a.len() {
+        current[0] = i;
+        let mut row_minimum = i;
+
+        for j in 1..=b.len() {
+            let cost = (a[i - 1] != b[j - 1]) as usize;
+            let mut value = (previous[j] + 1)
+                .min(current[j - 1] + 1)
+                .min(previous[j - 1] + cost);
+
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                value = value.min(before_previous[j - 2] + 1);
+            }
+
+            current[j] = value;
+            row_minimum = row_minimum.min(value);
+        }
+
+        // it only gets worse from here
+        if row_minimum > max_distance {
+            return None;
+        }
+
+        before_previous = previous;
+        previous = current;
+    }
+
+    Some(previous[b.len()]).filter(|&distance| distance <= max_distance)
+}
+
+// the closest matches found so far, sorted by distance. matches with the
+// same distance stay in the order they were added in. the items are
+// whatever the caller needs to find the match again.
+pub struct Closest {
+    matches: [(usize, usize); MAX_RESULTS],
+    length: usize,
+    capacity: usize
+}
+
+impl Closest {
+    pub fn new(capacity: usize) -> Closest {
+        Closest {
+            matches: [(0, 0); MAX_RESULTS],
+            length: 0,
+            capacity: capacity.min(MAX_RESULTS)
+        }
+    }
+
+    // the largest distance a new match can have and still get in, or
+    // None if nothing can. max_distance is the caller's own limit.
+    pub fn max_distance(&self, max_distance: usize) -> Option<usize> {
+        if self.length < self.capacity {
+            return Some(max_distance);
+        }
+
+        // a match with the same distance as the worst one would come
+        // after it, so it has to be closer than that
+        let &(worst, _) = self.as_slice().last()?;
+        worst.checked_sub(1).map(|distance| distance.min(max_distance))
+    }
+
+    // adds a match, and drops the worst one if there are too many
+    pub fn add(&mut self, distance: usize, item: usize) {
+        let position = self.as_slice().partition_point(|&(other, _)| other <= distance);
+        if position == self.capacity {
+            return;
+        }
+
+        if self.length < self.capacity {
+            self.length += 1;
+        }
+        self.matches.copy_within(position..self.length - 1, position + 1);
+        self.matches[position] = (distance, item);
+    }
+
+    pub fn as_slice(&self) -> &[(usize, usize)] {
+        &self.matches[..self.length]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn distance_between(a: &str, b: &str, max_distance: usize) -> Option<usize> {
+        let a = Characters::new(a.as_bytes()).unwrap();
+        let b = Characters::new(b.as_bytes()).unwrap();
+        distance(a.as_slice(), b.as_slice(), max_distance)
+    }
+
+    #[test]
+    fn test_distance() {
+        assert_eq!(distance_between("receive", "receive", 2), Some(0));
+        assert_eq!(distance_between("recieve", "receive", 2), Some(1));
+        assert_eq!(distance_between("definately", "definitely", 2), Some(1));
+        assert_eq!(distance_between("tset", "test", 2), Some(1));
+        assert_eq!(distance_between("tst", "test", 2), Some(1));
+        assert_eq!(distance_between("teest", "test", 2), Some(1));
+        assert_eq!(distance_between("kitten", "sitting", 3), Some(3));
+        assert_eq!(distance_between("kitten", "sitting", 2), None);
+        assert_eq!(distance_between("", "ab", 2), Some(2));
+        assert_eq!(distance_between("grün", "grun", 1), Some(1));
+        assert_eq!(distance_between("a", "abcd", 2), None);
+
+        assert!(Characters::new("x".repeat(MAX_LENGTH + 1).as_bytes()).is_none());
+        assert!(Characters::new(b"\xff").is_none());
+        assert_eq!(character_count("grün".as_bytes()), 4);
+    }
+
+    #[test]
+    fn test_closest() {
+        let mut closest = Closest::new(3);
+        assert_eq!(closest.max_distance(2), Some(2));
+
+        closest.add(2, 10);
+        closest.add(1, 11);
+        closest.add(2, 12);
+        assert_eq!(closest.as_slice(), [(1, 11), (2, 10), (2, 12)]);
+        assert_eq!(closest.max_distance(2), Some(1));
+
+        closest.add(0, 13);
+        assert_eq!(closest.as_slice(), [(0, 13), (1, 11), (2, 10)]);
+        closest.add(1, 14);
+        assert_eq!(closest.as_slice(), [(0, 13), (1, 11), (1, 14)]);
+        assert_eq!(closest.max_distance(2), Some(0));
+
+        closest.add(0, 15);
+        closest.add(0, 16);
+        assert_eq!(closest.as_slice(), [(0, 13), (0, 15), (0, 16)]);
+        assert_eq!(closest.max_distance(2), None);
+
+        assert_eq!(Closest::new(0).max_distance(2), None);
+        assert_eq!(Closest::new(usize::MAX).capacity, MAX_RESULTS);
+    }
+}
@@ -6,44 +6,61 @@ use core::iter::Iterator;
 use core::hash::Hasher;
 use core::borrow::Borrow;
 use wyhash::{wyhash, WyHash};
+use crate::varint;
 
+// entry layout: the length of the rest of the entry (see varint.rs),
+// the key, the tag (only if the table has tags), and a 4-byte value.
 pub struct Entry<'a> {
     offset: usize,
     length: usize,
+    key_offset: usize,
     pub key: &'a [u8],
+    // always 0 for tables without tags
+    pub tag: u8,
     pub value: u32
 }
 
 impl<'a> Entry<'a> {
-    fn new_with_length(data: &'a [u8], offset: usize, length: usize) -> Entry<'a> {
-        let key_start = offset + 2;
-        let key_end = offset + length - 4;
-        let value_start = key_end;
+    // length is the length of the whole entry, including the header
+    fn new_with_length(data: &'a [u8], offset: usize, header_size: usize, length: usize, tag_size: usize) -> Entry<'a> {
+        let key_start = offset + header_size;
+        let key_end = offset + length - 4 - tag_size;
+        let value_start = key_end + tag_size;
 
         let key = &data[key_start .. key_end];
+        let tag = if tag_size > 0 { data[key_end] } else { 0 };
         let value_bytes = &data[value_start .. value_start + 4];
         let value = u32::from_ne_bytes(value_bytes.try_into().unwrap());
 
         Entry {
             length,
             offset,
+            key_offset: key_start,
             key,
+            tag,
             value
         }
     }
 
-    pub fn new(data: &'a [u8], offset: usize) -> Entry<'a> {
-        let length = u16::from_ne_bytes(
-            data[offset .. offset + 2].try_into().unwrap())
-            as usize;
+    pub fn new(data: &'a [u8], offset: usize, tag_size: usize) -> Entry<'a> {
+        let (rest_length, header_size) = varint::read(data, offset);
 
-        Entry::new_with_length(data, offset, length)
+        Entry::new_with_length(data, offset, header_size, header_size + rest_length, tag_size)
     }
 
     pub fn get_offset(&self) -> usize {
         self.offset
     }
 
+    pub fn get_length(&self) -> usize {
+        self.length
+    }
+
+    // where the key starts in the data of the table
+    pub fn get_key_offset(&self) -> usize {
+        self.key_offset
+    }
+
     pub fn to_handle(self) -> EntryHandle {
         EntryHandle {
             offset: self.offset,
@@ -57,6 +74,7 @@ impl<'a> Entry<'a> {
 // entry values can be conveniently set on the hashtable. (this
 // wouldn't be possible otherwise, since entry contains an immutable
 // borrow into the hashtable, but we need a mutable borrow to modify.
+#[derive(Clone, Copy)]
 pub struct EntryHandle {
     offset: usize,
     length: usize
@@ -66,11 +84,12 @@ pub struct BucketEntryIterator<'a> {
     starting_offset: usize,
     offset: usize,
     end: usize,
+    tag_size: usize,
     data: &'a [u8]
 }
 
 impl<'a> BucketEntryIterator<'a> {
-    fn new<'b>(bucket_index: usize, buckets: &'b [usize], data: &'a [u8]) -> BucketEntryIterator<'a> {
+    fn new<'b>(bucket_index: usize, buckets: &'b [usize], data: &'a [u8], tag_size: usize) -> BucketEntryIterator<'a> {
         let offset = buckets[bucket_index];
         let data_length = data.as_ref().len();
         let end = *buckets.get(bucket_index + 1).unwrap_or(&data_length);
@@ -79,6 +98,7 @@ impl<'a> BucketEntryIterator<'a> {
             starting_offset: offset,
             offset: offset,
             end,
+            tag_size,
             data
         }
     }
@@ -94,17 +114,18 @@ impl<'a> Iterator for BucketEntryIterator<'a> {
 
         let data = self.data.as_ref();
 
-        let length = data[self.offset] as usize + ((data[self.offset + 1] as usize) << 8);
+        let (rest_length, header_size) = varint::read(data, self.offset);
 
         // this is only needed during initialization
-        if length == 0 {
+        if rest_length == 0 {
             return None;
         }
 
         let entry_offset = self.offset;
+        let length = header_size + rest_length;
         self.offset += length;
 
-        Some(Entry::new_with_length(data, entry_offset, length))
+        Some(Entry::new_with_length(data, entry_offset, header_size, length, self.tag_size))
     }
 }
 
@@ -116,7 +137,11 @@ where
 {
     num_entries: usize,
     num_keybytes_total: usize,
+    // the headers can have different lengths depending on whether
+    // the table has tags, so we count both
+    num_headerbytes_total: [usize; 2],
     load_factor: f64,
+    tag_size: usize,
     keys: I
 }
 
@@ -131,15 +156,23 @@ where
 
         let mut num_entries = 0;
         let mut num_keybytes_total = 0;
+        let mut num_headerbytes_total = [0, 0];
         for key in keys_firstpass {
+            let key_length = key.count();
             num_entries += 1;
-            num_keybytes_total += key.count();
+            num_keybytes_total += key_length;
+
+            for (tag_size, total) in num_headerbytes_total.iter_mut().enumerate() {
+                *total += varint::size(key_length + tag_size + size_of::<u32>());
+            }
         }
 
         HashTableMaker {
             num_entries,
             num_keybytes_total,
+            num_headerbytes_total,
             load_factor: 10.0,
+            tag_size: 0,
             keys
         }
     }
@@ -148,15 +181,22 @@ where
         self.load_factor = load_factor;
     }
 
+    // reserves an extra byte in every entry that can be used to
+    // store some information along with the value.
+    pub fn enable_tags(&mut self) {
+        self.tag_size = 1;
+    }
+
     pub fn get_buckets_length(&self) -> usize {
         let num_buckets = (self.num_entries as f64 / self.load_factor) as usize;
 
-        num_buckets
+        // small dictionaries would otherwise end up without any buckets
+        num_buckets.max(1)
     }
 
     pub fn get_data_length(&self) -> usize {
-        let num_headerbytes_total = self.num_entries * 2;
-        let num_payload_bytes_total = self.num_entries * 4;
+        let num_headerbytes_total = self.num_headerbytes_total[self.tag_size];
+        let num_payload_bytes_total = self.num_entries * (4 + self.tag_size);
         let data_length = num_headerbytes_total + self.num_keybytes_total + num_payload_bytes_total;
 
         data_length
@@ -182,11 +222,11 @@ where
             *bucket = 0;
         }
 
-        let header_size = 2;
-        let payload_size = size_of::<u32>();
+        let payload_size = self.tag_size + size_of::<u32>();
 
         for key in self.keys.clone() {
-            let total_size = header_size + key.clone().count() + payload_size;
+            let rest_length = key.clone().count() + payload_size;
+            let total_size = varint::size(rest_length) + rest_length;
 
             let index = get_bucket_index_from_iterator(key, buckets);
             buckets[index] += total_size;
@@ -197,11 +237,11 @@ where
         // a simple prefix sum.
         //
         // we will also initialize each bucket array with an empty marker.
-        // (normally, the first two bytes indicate the length of the first
-        // entry in the bucket, so that we can quickly jump to the next one.
-        // since this length includes these first two bytes, it is never 0
-        // once the first entry is populated, and can thus serve as an
-        // emptyness marker.)
+        // (normally, the header at the start of the bucket indicates the
+        // length of the first entry, so that we can quickly jump to the
+        // next one. since there is always at least the value after the
+        // header, it is never 0 once the first entry is populated, and
+        // can thus serve as an emptyness marker.)
 
         let mut offset = 0;
         for bucket in buckets.iter_mut() {
@@ -213,8 +253,7 @@ where
             if bucket_size > 0 {
                 // initialize the corresponding data section
                 // TODO: do we need to do this or can we assume the buffer is zeroed? (1/2)
-                data[*bucket .. *bucket + 2].copy_from_slice(
-                    &0_u16.to_ne_bytes());
+                data[*bucket] = 0;
             }
         }
 
@@ -224,26 +263,23 @@ where
 
             // find the next empty spot
             loop {
-                let length = u16::from_ne_bytes(
-                    (&data[offset .. offset + 2]).try_into().unwrap());
+                let (rest_length, header_size) = varint::read(data, offset);
 
-                if length == 0 {
+                if rest_length == 0 {
                     break;
                 }
 
-                offset += length as usize;
+                offset += header_size + rest_length;
             }
 
             let initial_offset = offset;
 
             let key_length = key.clone().count();
-            let length = header_size + key_length + payload_size;
-            assert!(length < 0xFFFF);
+            let rest_length = key_length + payload_size;
 
             // set length
-            data[offset .. offset + 2].copy_from_slice( 
-                &(length as u16).to_ne_bytes());
-            offset += 2;
+            let header_size = varint::write(data, offset, rest_length);
+            offset += header_size;
 
             // copy key
             let mut write_pos = offset;
@@ -253,27 +289,33 @@ where
             }
             offset += key_length;
 
+            // tags start out as 0
+            if self.tag_size > 0 {
+                data[offset] = 0;
+                offset += 1;
+            }
+
             // set payload to 0xffffffff (to indicate that it has not
             // been set yet)
             data[offset .. offset + 4].copy_from_slice(
                 &u32::MAX.to_ne_bytes());
             offset += 4;
 
-            assert_eq!(offset - initial_offset, length);
+            assert_eq!(offset - initial_offset, header_size + rest_length);
 
             let bucket_end = *buckets.get(index + 1).unwrap_or(&data.len());
             let this_bucket_is_full = offset == bucket_end;
             if !this_bucket_is_full {
                 // set empty marker for the next entry
                 // TODO: do we need to do this or can we assume the buffer is zeroed? (2/2)
-                data[offset .. offset + 2].copy_from_slice(
-                    &0_u16.to_ne_bytes());
+                data[offset] = 0;
             }
         }
 
         HashTable {
             buckets,
-            data
+            data,
+            tag_size: self.tag_size
         }
     }
 }
@@ -313,13 +355,32 @@ fn get_bucket_index_from_iterator(iterator: impl Iterator<Item = impl Borrow<u8>
 // values are always u32
 pub struct HashTable<'a> {
     pub buckets: &'a [usize],
-    pub data: &'a mut [u8]
+    pub data: &'a mut [u8],
+    // 1 if the entries have tags, 0 otherwise
+    pub tag_size: usize
 }
 
 impl<'a> HashTable<'a> {
     pub fn get_bucket_iterator<'b, 'c>(&'c self, key: &'b [u8]) -> BucketEntryIterator<'c> {
         let index = get_bucket_index(key, self.buckets);
-        BucketEntryIterator::new(index, self.buckets, self.data)
+        BucketEntryIterator::new(index, self.buckets, self.data, self.tag_size)
+    }
+
+    pub fn get_bucket_iterator_by_index(&self, index: usize) -> BucketEntryIterator<'_> {
+        BucketEntryIterator::new(index, self.buckets, self.data, self.tag_size)
+    }
+
+    // start and end offset of the bucket's entries in the data array
+    pub fn get_bucket_range(&self, index: usize) -> (usize, usize) {
+        let start = self.buckets[index];
+        let end = *self.buckets.get(index + 1).unwrap_or(&self.data.len());
+        (start, end)
+    }
+
+    // offset is the offset of the entry in the data array, as
+    // returned by Entry::get_offset
+    pub fn get_entry(&self, offset: usize) -> Entry<'_> {
+        Entry::new(self.data, offset, self.tag_size)
     }
 
     pub fn get_bucket_iterator_from_key_iterator<'c, I, K>(&'c self, key: I) -> BucketEntryIterator<'c>
@@ -328,7 +389,7 @@ impl<'a> HashTable<'a> {
         K: Borrow<u8>
     {
         let index = get_bucket_index_from_iterator(key, self.buckets);
-        BucketEntryIterator::new(index, self.buckets, self.data)
+        BucketEntryIterator::new(index, self.buckets, self.data, self.tag_size)
     }
 
     pub fn get_values<'c>(&'c self, key: &'c [u8]) -> impl Iterator<Item = u32> + 'c {
@@ -370,6 +431,12 @@ impl<'a> HashTable<'a> {
 
         value_bytes.copy_from_slice(&value.to_ne_bytes());
     }
+
+    pub fn set_tag(&mut self, entry_handle: EntryHandle, tag: u8) {
+        assert!(self.tag_size > 0);
+        let entry_end = entry_handle.offset + entry_handle.length;
+        self.data[entry_end - 5] = tag;
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,550 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// the json parser. it is written as a state machine that gets fed one
+// byte at a time, so that it can be used in two ways:
+//
+//  - in-place, where the intermediate format is written back into the
+//    buffer that holds the json (this is what load_json_internal does)
+//  - streaming, where the json arrives in chunks of arbitrary size, and
+//    only the intermediate format is kept around (StreamingLoader)
+//
+// note: this is not a full-fledged json parser. it is specifically
+// designed for reading plover dicionaries, and it will fail when
+// passed otherwise valid json that does not fit this schema.
+//
+// in tolerant mode, it also accepts a few things that aren't json, but
+// that people tend to put into their dictionaries when editing them by
+// hand: trailing commas and // comments.
+
+use crate::{InternalError, InternalResult, InputPosition, DataStructuresContainer, Collision, LoadOptions, PARSER_ERROR};
+use crate::{build_container, error, varint};
+use crate::validation::{Diagnostic, validate_intermediate, strict_error};
+
+// where the parser writes the intermediate format to
+pub trait IntermediateStorage {
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn push(&mut self, byte: u8) -> InternalResult<()>;
+    // overwrites a byte that has already been pushed
+    // (needed for the length headers)
+    fn set(&mut self, offset: usize, byte: u8);
+    fn as_slice(&self) -> &[u8];
+}
+
+pub(crate) static BYTE_ORDER_MARK: &[u8] = b"\xEF\xBB\xBF";
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    BeforeObject,
+    // read is the number of bytes of the byte order mark we've seen
+    ByteOrderMark { read: u8 },
+    // right after the '{', where the object may also end
+    BeforeFirstKey,
+    BeforeKey,
+    AfterKey,
+    BeforeValue,
+    AfterValue,
+    InString,
+    Escape,
+    UnicodeEscape { digits: u8, value: u32 },
+    // a high surrogate was just read, which should be followed by
+    // the \u escape for a low surrogate
+    AfterHighSurrogate { high: u32 },
+    AfterHighSurrogateBackslash { high: u32 },
+    LowSurrogate { high: u32, digits: u8, value: u32 },
+    // the first '/' of a comment, and the comment itself. afterwards,
+    // we go back to state_before_comment.
+    CommentStart,
+    Comment,
+    Done
+}
+
+pub struct JsonParser {
+    state: State,
+    state_before_comment: State,
+    tolerant: bool,
+    // whether the current string is a key (strokes) or a value (translation)
+    in_strokes: bool,
+    length_header_offset: usize,
+    // position of the next byte, for error messages
+    offset: usize,
+    line: u32,
+    line_start: usize
+}
+
+impl JsonParser {
+    pub fn new(tolerant: bool) -> JsonParser {
+        JsonParser {
+            state: State::BeforeObject,
+            state_before_comment: State::BeforeObject,
+            tolerant,
+            in_strokes: false,
+            length_header_offset: 0,
+            offset: 0,
+            line: 1,
+            line_start: 0
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    pub fn step(&mut self, byte: u8, storage: &mut impl IntermediateStorage) -> InternalResult<()> {
+        self.process(byte, storage)?;
+
+        self.offset += 1;
+        if byte == b'\n' {
+            self.line += 1;
+            self.line_start = self.offset;
+        }
+
+        Ok(())
+    }
+
+    // call this after the last byte, to make sure that the data is complete.
+    pub fn finish(&self) -> InternalResult<()> {
+        let state = match self.state {
+            State::CommentStart | State::Comment => self.state_before_comment,
+            state => state
+        };
+
+        match state {
+            State::Done => Ok(()),
+            State::BeforeObject | State::ByteOrderMark { .. } => Err(self.error(b"Parser error: no data found")),
+            State::BeforeFirstKey | State::BeforeKey | State::AfterKey | State::BeforeValue | State::AfterValue
+                => Err(self.error(b"Parser error: data incomplete")),
+            _ => Err(self.error(b"Parser error: data ended in the middle of string"))
+        }
+    }
+
+    fn process(&mut self, byte: u8, storage: &mut impl IntermediateStorage) -> InternalResult<()> {
+        let is_whitespace = byte == b' ' || byte == b'\t' || byte == b'\r' || byte == b'\n';
+
+        if self.tolerant && byte == b'/' && self.is_between_tokens() {
+            self.state_before_comment = self.state;
+            self.state = State::CommentStart;
+            return Ok(());
+        }
+
+        match self.state {
+            State::BeforeObject => {
+                if byte == b'{' {
+                    self.state = State::BeforeFirstKey;
+                }
+                else if self.offset == 0 && byte == BYTE_ORDER_MARK[0] {
+                    self.state = State::ByteOrderMark { read: 1 };
+                }
+                else if !is_whitespace {
+                    return Err(self.error(b"Parser error: expected '{'"));
+                }
+            }
+
+            State::ByteOrderMark { read } => {
+                if byte != BYTE_ORDER_MARK[read as usize] {
+                    return Err(self.error(b"Parser error: expected '{'"));
+                }
+
+                self.state = if read as usize + 1 == BYTE_ORDER_MARK.len() {
+                    State::BeforeObject
+                }
+                else {
+                    State::ByteOrderMark { read: read + 1 }
+                };
+            }
+
+            State::BeforeFirstKey | State::BeforeKey => {
+                if byte == b'"' {
+                    self.in_strokes = true;
+                    self.begin_string(storage)?;
+                }
+                else if byte == b'}' && (self.state == State::BeforeFirstKey || self.tolerant) {
+                    // either an empty dictionary, or a trailing comma
+                    self.state = State::Done;
+                }
+                else if !is_whitespace {
+                    return Err(self.error(b"Parser error: expected '\"'"));
+                }
+            }
+
+            State::BeforeValue => {
+                if byte == b'"' {
+                    self.in_strokes = false;
+                    self.begin_string(storage)?;
+                }
+                else if !is_whitespace {
+                    return Err(self.error(value_error(byte)));
+                }
+            }
+
+            State::AfterKey => {
+                if byte == b':' {
+                    self.state = State::BeforeValue;
+                }
+                else if !is_whitespace {
+                    return Err(self.error(b"Parser error: expected ':'"));
+                }
+            }
+
+            State::AfterValue => {
+                if byte == b',' {
+                    self.state = State::BeforeKey;
+                }
+                else if byte == b'}' {
+                    self.state = State::Done;
+                }
+                else if !is_whitespace {
+                    return Err(self.error(b"Parser error: expected ','"));
+                }
+            }
+
+            State::InString => {
+                if byte == b'"' {
+                    self.end_string(storage)?;
+                }
+                else if byte == b'\\' {
+                    if self.in_strokes {
+                        // the stroke parser can't handle those, so we have to make sure
+                        // they won't be in there.
+                        return Err(self.error(b"Parser error: escape sequence found in stroke definition"));
+                    }
+                    self.state = State::Escape;
+                }
+                else {
+                    storage.push(byte)?;
+                }
+            }
+
+            State::Escape => {
+                let unescaped = match byte {
+                    b'"' | b'\\' | b'/' => byte,
+                    b'b' => 0x08,
+                    b'f' => 0x0C,
+                    b'n' => b'\n',
+                    b'r' => b'\r',
+                    b't' => b'\t',
+                    b'u' => {
+                        self.state = State::UnicodeEscape { digits: 0, value: 0 };
+                        return Ok(());
+                    }
+                    _ => return Err(self.error(b"Parser error: invalid escape sequence in string"))
+                };
+
+                storage.push(unescaped)?;
+                self.state = State::InString;
+            }
+
+            State::UnicodeEscape { digits, value } => {
+                let value = (value << 4) | self.hex_digit(byte)?;
+
+                if digits < 3 {
+                    self.state = State::UnicodeEscape { digits: digits + 1, value };
+                }
+                else {
+                    self.finish_code_unit(value, storage)?;
+                }
+            }
+
+            State::AfterHighSurrogate { high } => {
+                if byte == b'\\' {
+                    self.state = State::AfterHighSurrogateBackslash { high };
+                }
+                else {
+                    // lone surrogates can't be encoded in utf-8
+                    push_code_point(0xFFFD, storage)?;
+                    self.state = State::InString;
+                    return self.process(byte, storage);
+                }
+            }
+
+            State::AfterHighSurrogateBackslash { high } => {
+                if byte == b'u' {
+                    self.state = State::LowSurrogate { high, digits: 0, value: 0 };
+                }
+                else {
+                    push_code_point(0xFFFD, storage)?;
+                    self.state = State::Escape;
+                    return self.process(byte, storage);
+                }
+            }
+
+            State::LowSurrogate { high, digits, value } => {
+                let value = (value << 4) | self.hex_digit(byte)?;
+
+                if digits < 3 {
+                    self.state = State::LowSurrogate { high, digits: digits + 1, value };
+                }
+                else if (0xDC00 .. 0xE000).contains(&value) {
+                    push_code_point(0x10000 + ((high - 0xD800) << 10) + (value - 0xDC00), storage)?;
+                    self.state = State::InString;
+                }
+                else {
+                    push_code_point(0xFFFD, storage)?;
+                    self.finish_code_unit(value, storage)?;
+                }
+            }
+
+            State::CommentStart => {
+                if byte == b'/' {
+                    self.state = State::Comment;
+                }
+                else {
+                    return Err(self.error(b"Parser error: expected '/' (only // comments are allowed)"));
+                }
+            }
+
+            State::Comment => {
+                if byte == b'\n' {
+                    self.state = self.state_before_comment;
+                }
+            }
+
+            State::Done => {
+                // we don't care about anything after the end of the object
+            }
+        }
+
+        Ok(())
+    }
+
+    // whether we're in between two tokens, where there could be whitespace
+    // (and comments, in tolerant mode)
+    fn is_between_tokens(&self) -> bool {
+        matches!(self.state,
+                 State::BeforeObject | State::BeforeFirstKey | State::BeforeKey
+                 | State::AfterKey | State::BeforeValue | State::AfterValue)
+    }
+
+    // handles the value of a complete \uXXXX escape
+    fn finish_code_unit(&mut self, value: u32, storage: &mut impl IntermediateStorage) -> InternalResult<()> {
+        if (0xD800 .. 0xDC00).contains(&value) {
+            self.state = State::AfterHighSurrogate { high: value };
+            return Ok(());
+        }
+
+        if (0xDC00 .. 0xE000).contains(&value) {
+            push_code_point(0xFFFD, storage)?;
+        }
+        else {
+            push_code_point(value, storage)?;
+        }
+
+        self.state = State::InString;
+        Ok(())
+    }
+
+    fn hex_digit(&self, digit: u8) -> InternalResult<u32> {
+        let value = match digit {
+            b'0' ..= b'9' => digit - b'0',
+            b'a' ..= b'f' => digit - b'a' + 10,
+            b'A' ..= b'F' => digit - b'A' + 10,
+            _ => return Err(self.error(b"Parser error: invalid hex digit in unicode escape"))
+        };
+
+        Ok(value as u32)
+    }
+
+    // we're just going to turn the json strings
+    // into length-prefixed strings (see varint.rs)
+    //
+    // yes, there are going to be lots of unaligned reads,
+    // but these are cheap, and most headers are only one or two
+    // bytes long.
+    fn begin_string(&mut self, storage: &mut impl IntermediateStorage) -> InternalResult<()> {
+        // placeholder for the header, which is filled in by end_string
+        self.length_header_offset = storage.len();
+        storage.push(0)?;
+
+        self.state = State::InString;
+        Ok(())
+    }
+
+    fn end_string(&mut self, storage: &mut impl IntermediateStorage) -> InternalResult<()> {
+        let length = storage.len() - self.length_header_offset - 1;
+        let extra = varint::size(length) - 1;
+
+        // the header doesn't fit into the placeholder, so the string
+        // has to move back a bit to make room
+        if extra > 0 {
+            for _ in 0..extra {
+                storage.push(0)?;
+            }
+
+            let string_start = self.length_header_offset + 1;
+            for offset in (string_start .. string_start + length).rev() {
+                let byte = storage.as_slice()[offset];
+                storage.set(offset + extra, byte);
+            }
+        }
+
+        let mut header = [0u8; varint::MAX_SIZE];
+        let header_size = varint::write(&mut header, 0, length);
+        for (index, &byte) in header[..header_size].iter().enumerate() {
+            storage.set(self.length_header_offset + index, byte);
+        }
+
+        self.state = if self.in_strokes { State::AfterKey } else { State::AfterValue };
+        Ok(())
+    }
+
+    // the excerpt is filled in by whoever feeds us the data, since
+    // we don't get to see more than one byte at a time.
+    fn error(&self, details: &'static [u8]) -> InternalError<'static> {
+        InternalError {
+            message: PARSER_ERROR.as_ref(),
+            details,
+            line: line!(),
+            position: Some(InputPosition {
+                offset: self.offset as u32,
+                line: self.line,
+                column: (self.offset - self.line_start + 1) as u32,
+                excerpt: [0; crate::EXCERPT_LENGTH],
+                excerpt_length: 0
+            })
+        }
+    }
+}
+
+// plover only allows strings as translations, so we try to say what
+// else we found instead
+fn value_error(byte: u8) -> &'static [u8] {
+    match byte {
+        b'0' ..= b'9' | b'-' => b"Parser error: translations have to be strings, but this one is a number",
+        b't' | b'f' => b"Parser error: translations have to be strings, but this one is true or false",
+        b'n' => b"Parser error: translations have to be strings, but this one is null",
+        b'{' | b'[' => b"Parser error: translations have to be strings, but this one is a nested object or array",
+        _ => b"Parser error: expected '\"'"
+    }
+}
+
+fn push_code_point(code_point: u32, storage: &mut impl IntermediateStorage) -> InternalResult<()> {
+    let character = char::from_u32(code_point).unwrap_or('\u{FFFD}');
+    let mut encoded = [0u8; 4];
+
+    for &byte in character.encode_utf8(&mut encoded).as_bytes() {
+        storage.push(byte)?;
+    }
+
+    Ok(())
+}
+
+// writes the intermediate format into the buffer that the json is read from
+struct InPlaceStorage<'a> {
+    buffer: &'a mut [u8],
+    write_pos: &'a mut usize,
+    // the position of the next byte to be read. we must never write
+    // beyond this, since that's data we still need.
+    read_pos: usize
+}
+
+impl<'a> IntermediateStorage for InPlaceStorage<'a> {
+    fn len(&self) -> usize {
+        *self.write_pos
+    }
+
+    fn push(&mut self, byte: u8) -> InternalResult<()> {
+        // only possible if a string of more than 2 MiB directly follows
+        // a long string without any whitespace in between
+        if *self.write_pos >= self.read_pos {
+            return Err(error!(b"I'm sorry, but we can't handle your dictionary.", b"There is nothing wrong with it, except that it has some really reaaallly long entries very close together, and we ran out of room while converting them."));
+        }
+        self.buffer[*self.write_pos] = byte;
+        *self.write_pos += 1;
+        Ok(())
+    }
+
+    fn set(&mut self, offset: usize, byte: u8) {
+        self.buffer[offset] = byte;
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.buffer[..*self.write_pos]
+    }
+}
+
+// parses the json object starting at read_pos, converting it in-place
+// into the binary intermediate format.
+//
+// the binary format is a list of entries, each of which is a length-prefixed
+// strokes string followed by a length-prefixed translation string. the
+// conversion to the binary format happens in-place, which is not a problem,
+// since the binary format is practically never longer than the original
+// data: every string in the json is enclosed by two quotes and followed by
+// a ':' or ',', which is enough space for the length header of any string
+// shorter than 2 MiB, escape sequences are always longer than the utf-8
+// they stand for, and all other bytes are copied one-to-one.
+// so we can read the original data from the read pointer, convert it,
+// then write it out to the write pointer.
+//
+// INVARIANT: read_pos >= write_pos
+pub(crate) fn parse_json_in_place(buffer: &mut [u8], read_pos: &mut usize, write_pos: &mut usize, tolerant: bool) -> InternalResult<()> {
+    let mut parser = JsonParser::new(tolerant);
+
+    while *read_pos < buffer.len() && !parser.is_done() {
+        let byte = buffer[*read_pos];
+        *read_pos += 1;
+
+        let mut storage = InPlaceStorage {
+            buffer: &mut buffer[..],
+            write_pos: &mut *write_pos,
+            read_pos: *read_pos
+        };
+
+        parser.step(byte, &mut storage)
+            .map_err(|error| error.with_excerpt(buffer, *read_pos - 1))?;
+    }
+
+    parser.finish().map_err(|error| error.with_excerpt(buffer, *read_pos))
+}
+
+// loads a json dictionary that arrives in chunks, for when we can't
+// afford to keep the whole json file in memory. the chunks can be split
+// anywhere, even in the middle of an escape sequence or a utf-8 character.
+// only the intermediate format is stored, and at the end, the hash tables
+// are built from it just like for the other loaders.
+pub struct StreamingLoader<'a, Storage: IntermediateStorage> {
+    parser: JsonParser,
+    storage: Storage,
+    options: LoadOptions<'a>
+}
+
+impl<'a, Storage: IntermediateStorage> StreamingLoader<'a, Storage> {
+    pub fn new(storage: Storage, options: LoadOptions<'a>) -> StreamingLoader<'a, Storage> {
+        StreamingLoader {
+            parser: JsonParser::new(options.tolerant),
+            storage,
+            options
+        }
+    }
+
+    pub fn load_chunk(&mut self, chunk: &[u8]) -> InternalResult<()> {
+        for (index, &byte) in chunk.iter().enumerate() {
+            self.parser.step(byte, &mut self.storage)
+                .map_err(|error| error.with_excerpt(chunk, index))?;
+        }
+
+        Ok(())
+    }
+
+    // checks the strokes of the whole dictionary and builds the hash tables,
+    // once all chunks are there. see load_dictionary_stack_with_report for
+    // what report and report_collision do. in strict mode, this fails if
+    // there were any problems with the strokes.
+    pub fn finish<ContainerType, F, G>(self, report: F, report_collision: G) -> InternalResult<ContainerType>
+        where ContainerType: DataStructuresContainer, F: FnMut(Diagnostic), G: FnMut(Collision)
+    {
+        self.parser.finish()?;
+
+        let intermediate = self.storage.as_slice();
+        let found_problems = validate_intermediate(intermediate, &[intermediate.len()], self.options.system, self.options.strict, report);
+        if self.options.strict && found_problems {
+            return Err(strict_error());
+        }
+
+        build_container(intermediate, &[intermediate.len()], self.options, report_collision)
+    }
+}
@@ -10,11 +10,36 @@
 use core::fmt::Write;
 use core::convert::TryInto;
 use core::borrow::Borrow;
+use core::ops::Range;
 
+mod fold;
+mod format;
+mod fuzzy;
 mod hashtable;
-
+mod json;
+mod markup;
+mod packed;
+mod pattern;
+mod ranking;
+mod rtf;
+mod system;
+mod validation;
+mod varint;
+
+use fold::{Fold, is_folded};
 use hashtable::{HashTableMaker, HashTable};
 
+pub use format::{FormatOptions, format_stroke, format_strokes, normalize_stroke_text};
+pub use json::{IntermediateStorage, StreamingLoader};
+pub use markup::{Attachment, VisibleText, attachment};
+use markup::has_markup;
+pub use pattern::{StrokePattern, PatternPosition};
+pub use ranking::{Rank, Ranking};
+pub use rtf::{load_rtf_internal, load_rtf_with_report};
+use system::MAX_STROKE_SIZE;
+pub use system::{StenoSystem, StrokeWidth, ENGLISH_STENOTYPE, EXTENDED_STENOTYPE, PALANTYPE};
+pub use validation::{Diagnostic, Severity, Problem, check_stroke, parse_stroke_strict};
+
 #[cfg_attr(test, derive(Debug))]
 pub struct InternalError<'a> {
     // message is the part of the error that's meant to be
@@ -23,10 +48,132 @@ pub struct InternalError<'a> {
     // details is the part of the error that's meant to give
     // more precise information on what went wrong.
     pub details: &'a [u8],
-    pub line: u32
+    pub line: u32,
+    // for errors caused by malformed input, this says where in
+    // the input the problem was found.
+    pub position: Option<InputPosition>
+}
+
+const EXCERPT_LENGTH: usize = 32;
+
+// we can't use core::fmt to put the position into the details
+// string (it's way too big), so we just hand over the numbers and
+// a copy of the input around the error, and let the caller format them.
+#[derive(Clone, Copy)]
+#[cfg_attr(test, derive(Debug))]
+pub struct InputPosition {
+    // byte offset into the input
+    pub offset: u32,
+    // 1-based, 0 if unknown
+    pub line: u32,
+    // 1-based, counted in bytes from the start of the line
+    pub column: u32,
+    excerpt: [u8; EXCERPT_LENGTH],
+    excerpt_length: u8
+}
+
+impl InputPosition {
+    // copies a short excerpt of the input starting at offset. this has to
+    // be done right away, since the loader overwrites the input as it goes.
+    // (everything from the read position onwards is still untouched, though.)
+    fn new(buffer: &[u8], offset: usize) -> InputPosition {
+        let mut position = InputPosition {
+            offset: offset as u32,
+            line: 0,
+            column: 0,
+            excerpt: [0u8; EXCERPT_LENGTH],
+            excerpt_length: 0
+        };
+
+        position.set_excerpt(buffer, offset);
+        position
+    }
+
+    fn set_excerpt(&mut self, buffer: &[u8], offset: usize) {
+        let start = offset.min(buffer.len());
+        let mut end = (start + EXCERPT_LENGTH).min(buffer.len());
+
+        // only show the rest of the current line
+        if let Some(newline) = buffer[start..end].iter().position(|&byte| byte == b'\n' || byte == b'\r') {
+            end = start + newline;
+        }
+
+        // don't cut a utf-8 sequence in half
+        if end < buffer.len() {
+            while end > start && (buffer[end] & 0xC0) == 0x80 {
+                end -= 1;
+            }
+        }
+
+        self.excerpt[..end - start].copy_from_slice(&buffer[start..end]);
+        self.excerpt_length = (end - start) as u8;
+    }
+
+    pub fn excerpt(&self) -> &[u8] {
+        &self.excerpt[..self.excerpt_length as usize]
+    }
+}
+
+// keeps track of line numbers while parsing. this can't be done
+// after the fact, since the parser overwrites the data it has already
+// read.
+struct LineCounter {
+    line: u32,
+    line_start: usize,
+    // where the current file starts in the buffer, for dictionary stacks
+    file_start: usize
 }
 
-type InternalResult<T> = Result<T, InternalError<'static>>;
+impl LineCounter {
+    fn new() -> LineCounter {
+        LineCounter::starting_at(0)
+    }
+
+    fn starting_at(file_start: usize) -> LineCounter {
+        LineCounter {
+            line: 1,
+            line_start: file_start,
+            file_start
+        }
+    }
+
+    // call this for every byte that is consumed, with pos pointing just
+    // past that byte.
+    fn count(&mut self, byte: u8, pos: usize) {
+        if byte == b'\n' {
+            self.line += 1;
+            self.line_start = pos;
+        }
+    }
+
+    // fills in line and column for errors that happened on the current line,
+    // and makes the offset relative to the start of the file
+    fn locate(&self, mut error: InternalError<'static>) -> InternalError<'static> {
+        if let Some(position) = error.position.as_mut() {
+            let offset = position.offset as usize;
+            if offset >= self.line_start {
+                position.line = self.line;
+                position.column = (offset - self.line_start + 1) as u32;
+            }
+            if offset >= self.file_start {
+                position.offset = (offset - self.file_start) as u32;
+            }
+        }
+        error
+    }
+}
+
+impl<'a> InternalError<'a> {
+    // for errors that know their position, but not the data around it
+    fn with_excerpt(mut self, buffer: &[u8], offset: usize) -> InternalError<'a> {
+        if let Some(position) = self.position.as_mut() {
+            position.set_excerpt(buffer, offset);
+        }
+        self
+    }
+}
+
+pub type InternalResult<T> = Result<T, InternalError<'static>>;
 
 #[macro_export]
 macro_rules! error {
@@ -34,11 +181,25 @@ macro_rules! error {
         InternalError {
             message: $message.as_ref(),
             details: $details.as_ref(),
-            line: line!()
+            line: line!(),
+            position: None
         }
     };
 }
 
+macro_rules! parser_error {
+    ($buffer:expr, $offset:expr, $details:expr) => {
+        InternalError {
+            message: PARSER_ERROR.as_ref(),
+            details: $details.as_ref(),
+            line: line!(),
+            position: Some(InputPosition::new($buffer, $offset))
+        }
+    };
+}
+
+pub(crate) use parser_error;
+
 static PARSER_ERROR: &'static [u8; 83] = b"Sorry, we couldn't load your dictionary because we don't understand its formatting.";
 
 #[repr(packed(4))]
@@ -107,12 +268,11 @@ impl<'a> Iterator for BufferIterator<'a> {
             return None;
         }
 
-        let length = u16::from_ne_bytes(
-            self.buffer[self.offset .. self.offset + 2]
-            .try_into().unwrap()) as usize;
+        let (length, header_size) = varint::read(self.buffer, self.offset);
+        let start = self.offset + header_size;
 
-        let data = &self.buffer[self.offset + 2 .. self.offset + length];
-        self.offset += length;
+        let data = &self.buffer[start .. start + length];
+        self.offset = start + length;
 
         Some(data)
     }
@@ -145,13 +305,15 @@ impl<'a> Iterator for AllTranslationsIterator<'a> {
 
 #[derive(Clone)]
 struct AllStrokesIterator<'a> {
-    buffer_iterator: BufferIterator<'a>
+    buffer_iterator: BufferIterator<'a>,
+    system: &'a StenoSystem
 }
 
 impl<'a> AllStrokesIterator<'a> {
-    fn new(buffer: &'a [u8]) -> AllStrokesIterator<'a> {
+    fn new(buffer: &'a [u8], system: &'a StenoSystem) -> AllStrokesIterator<'a> {
         AllStrokesIterator {
-            buffer_iterator: BufferIterator::new(buffer)
+            buffer_iterator: BufferIterator::new(buffer),
+            system
         }
     }
 }
@@ -165,7 +327,7 @@ impl<'a> Iterator for AllStrokesIterator<'a> {
 
         if let Some(raw_stroke_data) = strokes {
             //println!("parsing strokes: {}", std::str::from_utf8(raw_stroke_data).unwrap());
-            Some(ParseStrokesIterator::new(raw_stroke_data))
+            Some(ParseStrokesIterator::new(self.system, raw_stroke_data))
         }
         else {
             None
@@ -173,21 +335,62 @@ impl<'a> Iterator for AllStrokesIterator<'a> {
     }
 }
 
+// yields the index of the dictionary each entry came from, in the
+// same order as AllStrokesIterator and AllTranslationsIterator.
+#[derive(Clone)]
+struct AllSourcesIterator<'a> {
+    buffer_iterator: BufferIterator<'a>,
+    // the offset in the intermediate buffer at which each dictionary ends
+    dictionary_ends: &'a [usize],
+    source: usize
+}
+
+impl<'a> AllSourcesIterator<'a> {
+    fn new(buffer: &'a [u8], dictionary_ends: &'a [usize]) -> AllSourcesIterator<'a> {
+        AllSourcesIterator {
+            buffer_iterator: BufferIterator::new(buffer),
+            dictionary_ends,
+            source: 0
+        }
+    }
+}
+
+impl<'a> Iterator for AllSourcesIterator<'a> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        let entry_start = self.buffer_iterator.offset;
+        let _strokes = self.buffer_iterator.next()?;
+        let _translation = self.buffer_iterator.next()?;
+
+        while self.dictionary_ends[self.source] <= entry_start {
+            self.source += 1;
+        }
+
+        Some(self.source as u8)
+    }
+}
+
 #[derive(Clone)]
 struct ParseStrokesIterator<'a> {
+    system: &'a StenoSystem,
     raw_strokes_data: &'a [u8],
     read_index: usize,
-    current_stroke: u32,
-    bytes_left_in_current_stroke: usize,
+    // the stroke we're in the middle of, packed the way the system says
+    current_stroke: [u8; MAX_STROKE_SIZE],
+    current_stroke_length: usize,
+    bytes_read_from_current_stroke: usize,
 }
 
 impl<'a> ParseStrokesIterator<'a> {
-    fn new(raw_strokes_data: &'a [u8]) -> ParseStrokesIterator<'a> {
+    fn new(system: &'a StenoSystem, raw_strokes_data: &'a [u8]) -> ParseStrokesIterator<'a> {
         ParseStrokesIterator {
+            system,
             raw_strokes_data,
             read_index: 0,
-            current_stroke: 0,
-            bytes_left_in_current_stroke: 0,
+            current_stroke: [0; MAX_STROKE_SIZE],
+            current_stroke_length: 0,
+            bytes_read_from_current_stroke: 0,
         }
     }
 }
@@ -196,25 +399,21 @@ impl<'a> Iterator for ParseStrokesIterator<'a> {
     type Item = u8;
 
     fn next(&mut self) -> Option<u8> {
-        if self.bytes_left_in_current_stroke == 0 {
+        if self.bytes_read_from_current_stroke == self.current_stroke_length {
             if self.read_index < self.raw_strokes_data.len() {
-                let stroke = parse_stroke_fast(self.raw_strokes_data, &mut self.read_index);
-                self.current_stroke = stroke >> 8;
-                self.bytes_left_in_current_stroke = 2;
-
-                Some(stroke as u8)
+                let stroke = self.system.parse_stroke(self.raw_strokes_data, &mut self.read_index);
+                self.current_stroke_length = self.system.stroke_width().pack(stroke, &mut self.current_stroke);
+                self.bytes_read_from_current_stroke = 0;
             }
             else {
-                None
+                return None;
             }
         }
-        else { // self.bytes_left_in_current_stroke != 0
-            let stroke = self.current_stroke;
-            self.current_stroke >>= 8;
-            self.bytes_left_in_current_stroke -= 1;
 
-            Some(stroke as u8)
-        }
+        let byte = self.current_stroke[self.bytes_read_from_current_stroke];
+        self.bytes_read_from_current_stroke += 1;
+
+        Some(byte)
     }
 }
 
@@ -229,10 +428,155 @@ pub trait DataStructuresContainer {
     fn get_both_buffers_mut(&mut self) -> (&mut [usize], &mut [u8]);
 }
 
+// the strokes table keeps a tag byte for every entry. the low bits
+// are the index of the dictionary the entry came from, and the top bit
+// is set if the entry is overridden by another one with the same strokes.
+const SOURCE_MASK: u8 = 0x7F;
+const SHADOWED: u8 = 0x80;
+
+pub const MAX_DICTIONARIES: usize = SOURCE_MASK as usize + 1;
+
+// one match, as passed to the callbacks of query_internal and find_strokes_internal
+pub struct QueryResult<'a> {
+    // packed strokes, see StrokeWidth for how they're stored
+    pub strokes: &'a [u8],
+    pub translation: &'a [u8],
+    // index of the dictionary this entry came from (0 is the
+    // dictionary with the highest priority)
+    pub source: u8,
+    // false if the translation only matches the query once both are
+    // folded (see folded_query_internal)
+    pub exact: bool
+}
+
+impl<'a> QueryResult<'a> {
+    // how the translation attaches to the words around it, see markup.rs
+    pub fn attachment(&self) -> Attachment {
+        attachment(self.translation)
+    }
+}
+
+// translations that are worth finding by their visible text
+fn has_hidden_markup(translation: &[u8]) -> bool {
+    has_markup(translation) && VisibleText::new(translation).next().is_some()
+}
+
+// two entries of the same dictionary that end up with the same strokes
+// once they're parsed. this happens if a json object has the same key
+// twice, or if the strokes are spelled differently, like "50" and "#AO",
+// or "T-FT" and "TFT".
+pub struct Collision<'a> {
+    // index of the dictionary in the stack
+    pub dictionary: u8,
+    // index of the later of the two entries within its dictionary
+    pub entry: usize,
+    // the strokes and translation of the later entry, as written in the dictionary
+    pub strokes: &'a [u8],
+    pub translation: &'a [u8],
+    // the translation of the earlier entry
+    pub previous_translation: &'a [u8]
+}
+
+// what to do with entries that collide (see Collision). this only
+// applies within one dictionary: if dictionaries in a stack define the
+// same strokes, the one with the higher priority always wins.
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+#[repr(u8)]
+pub enum DuplicatePolicy {
+    // the entry that comes last is used, which is what plover does
+    LastWins = 0,
+    FirstWins = 1,
+    // all of them are used, so a stroke lookup returns each of them
+    KeepAll = 2
+}
+
+// settings for the loaders. the default is to load English Stenotype
+// dictionaries the way plover would.
+#[derive(Clone, Copy)]
+pub struct LoadOptions<'a> {
+    // which keys there are, and how strokes are written
+    pub system: &'a StenoSystem,
+    // only accept strokes that are written exactly the way plover would
+    // write them (see parse_stroke_strict)
+    pub strict: bool,
+    // which entry to use if several entries of a dictionary have the same strokes
+    pub duplicates: DuplicatePolicy,
+    // accept trailing commas and // comments in json dictionaries
+    pub tolerant: bool
+}
+
+impl Default for LoadOptions<'static> {
+    fn default() -> LoadOptions<'static> {
+        LoadOptions {
+            system: &ENGLISH_STENOTYPE,
+            strict: false,
+            duplicates: DuplicatePolicy::LastWins,
+            tolerant: false
+        }
+    }
+}
+
 // loads a json array into our custom memory format.
-pub fn load_json_internal<ContainerType>(mut buffer: &mut [u8]) -> InternalResult<ContainerType>
+pub fn load_json_internal<ContainerType>(buffer: &mut [u8]) -> InternalResult<ContainerType>
+    where ContainerType: DataStructuresContainer
+{
+    load_dictionary_stack_internal(buffer, &[buffer.len()])
+}
+
+// loads several dictionaries at once, like plover's dictionary stack.
+// the dictionaries are stored back-to-back in buffer, and
+// dictionary_lengths gives the length of each of them. the first
+// dictionary has the highest priority: if several dictionaries define
+// the same strokes, only the definition from the first one of them is
+// used. each dictionary can be either json or RTF/CRE.
+pub fn load_dictionary_stack_internal<ContainerType>(buffer: &mut [u8], dictionary_lengths: &[usize]) -> InternalResult<ContainerType>
     where ContainerType: DataStructuresContainer
 {
+    load_dictionary_stack_with_report(buffer, dictionary_lengths, LoadOptions::default(), |_| {}, |_| {})
+}
+
+// like load_dictionary_stack_internal, but also checks every stroke
+// definition, and calls report for each problem it finds. normally, these
+// are only warnings, and the dictionary still gets loaded. in strict mode,
+// the strokes are checked with parse_stroke_strict instead, and the
+// dictionary is rejected if any of them isn't written the way plover
+// would write it. report_collision is called for every pair of entries
+// of a dictionary that have the same strokes.
+pub fn load_dictionary_stack_with_report<ContainerType, F, G>(buffer: &mut [u8], dictionary_lengths: &[usize], options: LoadOptions,
+                                                              report: F, report_collision: G) -> InternalResult<ContainerType>
+    where ContainerType: DataStructuresContainer, F: FnMut(Diagnostic), G: FnMut(Collision)
+{
+    let mut dictionary_ends = [0usize; MAX_DICTIONARIES];
+    let intermediate_length = parse_dictionary_stack(buffer, dictionary_lengths, options, &mut dictionary_ends)?;
+    let dictionary_ends = &dictionary_ends[..dictionary_lengths.len()];
+
+    let found_problems = validation::validate_intermediate(&buffer[..intermediate_length], dictionary_ends, options.system, options.strict, report);
+    if options.strict && found_problems {
+        return Err(validation::strict_error());
+    }
+
+    // first pass is done, the data is parsed.
+    // we can now use this to start initializing the hash tables.
+    build_container(&buffer[..intermediate_length], dictionary_ends, options, report_collision)
+}
+
+// only checks the dictionaries, without loading them. syntax errors
+// still stop everything, but all problems with the strokes are passed
+// to report.
+pub fn validate_dictionary_stack_internal<F>(buffer: &mut [u8], dictionary_lengths: &[usize], options: LoadOptions, report: F) -> InternalResult<()>
+    where F: FnMut(Diagnostic)
+{
+    let mut dictionary_ends = [0usize; MAX_DICTIONARIES];
+    let intermediate_length = parse_dictionary_stack(buffer, dictionary_lengths, options, &mut dictionary_ends)?;
+
+    validation::validate_intermediate(&buffer[..intermediate_length], &dictionary_ends[..dictionary_lengths.len()], options.system, options.strict, report);
+    Ok(())
+}
+
+// converts all dictionaries into the intermediate format, in-place.
+// returns the length of the intermediate data.
+fn parse_dictionary_stack(buffer: &mut [u8], dictionary_lengths: &[usize], options: LoadOptions, dictionary_ends: &mut [usize; MAX_DICTIONARIES]) -> InternalResult<usize> {
     // in-place parsing turned out to not be possible in the end.
     // so, we're not going to do it.
     //
@@ -241,115 +585,201 @@ pub fn load_json_internal<ContainerType>(mut buffer: &mut [u8]) -> InternalResul
     // memory will get gc'd.
 
     // step 1:
-    // pre-parse the json.
-    // this serves two purposes: first, validating the json input,
+    // pre-parse the dictionaries.
+    // this serves two purposes: first, validating the input,
     // (and converting it into a binary format that's easier to read)
     // and second, determining the sizes of the required packed arrays,
     // so we know how much to allocate.
     //
-    // note: this is not a full-fledged json parser. it is specifically
-    // designed for reading plover dicionaries, and it will fail when
-    // passed otherwise valid json that does not fit this schema.
-    //
-    // the binary format is basically just a '"'-terminated strokes
-    // list, followed by a 0-terminated string, followed by the next
-    // entry. the conversion to the binary format will happen
-    // in-place, which is not a problem, since the binary format
-    // is shorter than the original data format. we'll read the
-    // original data from the read pointer, convert it, then write
-    // it out to the write pointer.
-    //
-    // unfortunately, we can't use an iterator for all this since we
-    // need to be able to write the slice while doing this.
+    // the conversion to the binary format happens in-place, see
+    // json::parse_json_in_place for how this works.
 
-    let mut read_pos = 0;
-    let mut write_pos = 0;
-
-    // INVARIANT: read_pos >= write_pos
-
-    skip_whitespace(buffer, &mut read_pos).or(Err(error!(PARSER_ERROR, b"Parser error: no data found")))?;
-    expect_char(buffer, &mut read_pos, b'{')?;
-    
-    // INVARIANT: read_pos >= write_pos + 1
-
-    loop {
+    if dictionary_lengths.len() > MAX_DICTIONARIES {
+        return Err(error!(b"Sorry, you can't load that many dictionaries at once.", b"The maximum number of dictionaries is 128."));
+    }
 
-        // read key
-        skip_whitespace(buffer, &mut read_pos)?;
+    let total_length = dictionary_lengths.iter().sum::<usize>();
+    if total_length > buffer.len() {
+        return Err(error!(b"Internal error: dictionary lengths don't match the data.", b""));
+    }
 
-        // validate/rewrite the strokes string
-        rewrite_string(&mut buffer, &mut read_pos, &mut write_pos, true)?;
-        // INVARIANT: read_pos >= write_pos + 1
+    // all dictionaries are converted into one single intermediate buffer,
+    // so we have to remember where each of them ends.
 
-        skip_whitespace(buffer, &mut read_pos)?;
-        expect_char(buffer, &mut read_pos, b':')?;
-        skip_whitespace(buffer, &mut read_pos)?;
+    let mut read_pos = 0;
+    let mut write_pos = 0;
 
-        // read value
-        rewrite_string(&mut buffer, &mut read_pos, &mut write_pos, false)?;
-        // INVARIANT: read_pos >= write_pos + 1
-        // (note: we could get better bounds in practice, since each
-        //  string read actually increases the space by one. but we
-        //  don't need these, so this is how it's going to stay.)
+    for (index, length) in dictionary_lengths.iter().enumerate() {
+        let dictionary_start = read_pos;
+        let dictionary_end = dictionary_start + length;
 
-        skip_whitespace(buffer, &mut read_pos)?;
+        // INVARIANT: read_pos >= write_pos (parsing never overtakes itself,
+        // and the next dictionary starts after the end of this one.)
+        let dictionary = &mut buffer[..dictionary_end];
 
-        let byte = buffer.get(read_pos).ok_or(error!(PARSER_ERROR, b"Parser error: data incomplete"))?;
-        if *byte == b'}' {
-            // reached file end
-            //#[allow(unused_assignments)]
-            //read_pos += 1;
-            break;
+        // editors on windows like to start utf-8 files with a byte order mark.
+        // the json parser knows how to deal with those, but we have to look
+        // past it to find out whether this is an RTF file.
+        let content_start = if dictionary[dictionary_start..].starts_with(json::BYTE_ORDER_MARK) {
+            dictionary_start + json::BYTE_ORDER_MARK.len()
+        }
+        else {
+            dictionary_start
+        };
+
+        if dictionary[content_start..].starts_with(b"{\\rtf") {
+            let mut lines = LineCounter::starting_at(dictionary_start);
+            read_pos = content_start;
+            rtf::parse_rtf(dictionary, &mut read_pos, &mut write_pos, &mut lines)
+                .map_err(|error| lines.locate(error))?;
         }
-        expect_char(buffer, &mut read_pos, b',')?;
+        else {
+            json::parse_json_in_place(dictionary, &mut read_pos, &mut write_pos, options.tolerant)?;
+        }
+
+        dictionary_ends[index] = write_pos;
+        read_pos = dictionary_end;
     }
 
-    // first pass is done, the data is parsed.
-    // we can now use this to start initializing the hash tables.
+    Ok(write_pos)
+}
+
+// step 2, shared by all dictionary formats: takes the binary intermediate
+// format produced by the parser, allocates the container and builds the
+// hash tables. dictionary_ends says where in the intermediate buffer each
+// dictionary of the stack ends. the strokes are parsed with options.system.
+fn build_container<ContainerType, F>(intermediate: &[u8], dictionary_ends: &[usize], options: LoadOptions, mut report_collision: F) -> InternalResult<ContainerType>
+    where ContainerType: DataStructuresContainer, F: FnMut(Collision)
+{
     let hash_table_load_factor = 10.0;
 
-    let strokes_iterator = AllStrokesIterator::new(&buffer[..write_pos]);
+    let strokes_iterator = AllStrokesIterator::new(intermediate, options.system);
     let mut strokes_table_maker = HashTableMaker::initialize(strokes_iterator.clone());
     strokes_table_maker.set_load_factor(hash_table_load_factor);
+    strokes_table_maker.enable_tags();
+
+    let sources_iterator = AllSourcesIterator::new(intermediate, dictionary_ends);
 
-    let strings_iterator = AllTranslationsIterator::new(&buffer[..write_pos]);
+    let strings_iterator = AllTranslationsIterator::new(intermediate);
     let mut strings_table_maker = HashTableMaker::initialize(strings_iterator.clone());
     strings_table_maker.set_load_factor(hash_table_load_factor);
 
-    let usize_buffer_length = 2
+    // the translations that folding changes, by their folded form (the
+    // others can be found in the strings table)
+    let folded_iterator = strings_iterator.clone()
+        .filter(|translation| !is_folded(translation.as_slice()))
+        .map(|translation| Fold::new(translation.as_slice()));
+    let mut folded_table_maker = HashTableMaker::initialize(folded_iterator);
+    folded_table_maker.set_load_factor(hash_table_load_factor);
+
+    // the translations with plover formatting, by their visible text
+    let visible_iterator = strings_iterator.clone()
+        .filter(|translation| has_hidden_markup(translation.as_slice()))
+        .map(|translation| VisibleText::new(translation.as_slice()));
+    let mut visible_table_maker = HashTableMaker::initialize(visible_iterator);
+    visible_table_maker.set_load_factor(hash_table_load_factor);
+
+    // one slot for every entry, though shadowed ones get left out later
+    let translation_index_capacity = strings_iterator.clone().count();
+    // and one for every translation that's short enough for fuzzy lookups,
+    // though duplicates get left out as well
+    let length_index_capacity = strings_iterator.clone()
+        .filter(|translation| fuzzy::character_count(translation.as_slice()) <= fuzzy::MAX_LENGTH)
+        .count();
+    // and one for every place a substring can start, in as many bytes as
+    // an offset into the strings table data needs
+    let substring_index_capacity = strings_iterator.clone()
+        .map(|translation| substring_positions(translation.as_slice()).count())
+        .sum::<usize>();
+    let substring_index_width = packed::width(strings_table_maker.get_data_length());
+
+    let usize_buffer_length = CONTAINER_HEADER_LENGTH
         + strokes_table_maker.get_buckets_length()
-        + strings_table_maker.get_buckets_length();
-
-    let u8_buffer_length = 0
-        + strokes_table_maker.get_data_length()
-        + strings_table_maker.get_data_length();
+        + strings_table_maker.get_buckets_length()
+        + folded_table_maker.get_buckets_length()
+        + visible_table_maker.get_buckets_length()
+        + translation_index_capacity
+        + length_index_capacity
+        + translation_index_capacity
+        + translation_index_capacity;
+
+    let u8_buffer_length = strokes_table_maker.get_data_length()
+        + strings_table_maker.get_data_length()
+        + folded_table_maker.get_data_length()
+        + visible_table_maker.get_data_length()
+        + substring_index_capacity * substring_index_width;
 
     let mut container = ContainerType::allocate(usize_buffer_length, u8_buffer_length);
     let (usize_buffer, u8_buffer) = container.get_both_buffers_mut();
+    let (header, usize_buffer) = usize_buffer.split_at_mut(CONTAINER_HEADER_LENGTH);
 
     // store the length of the strokes table arrays, so we'll remember where the
     // strings table arrays start
-    usize_buffer[0] = strokes_table_maker.get_buckets_length();
-    usize_buffer[1] = strokes_table_maker.get_data_length();
+    header[HEADER_STROKES_BUCKETS] = strokes_table_maker.get_buckets_length();
+    header[HEADER_STROKES_DATA] = strokes_table_maker.get_data_length();
+    // and how the strokes are packed, so the query functions know how to read them
+    header[HEADER_STROKE_WIDTH] = options.system.stroke_width() as usize;
+    // and the same for the other tables
+    header[HEADER_STRINGS_BUCKETS] = strings_table_maker.get_buckets_length();
+    header[HEADER_STRINGS_DATA] = strings_table_maker.get_data_length();
+    header[HEADER_FOLDED_BUCKETS] = folded_table_maker.get_buckets_length();
+    header[HEADER_FOLDED_DATA] = folded_table_maker.get_data_length();
+    header[HEADER_VISIBLE_BUCKETS] = visible_table_maker.get_buckets_length();
+    header[HEADER_VISIBLE_DATA] = visible_table_maker.get_data_length();
+    // and which steno system the strokes belong to
+    header[HEADER_SYSTEM_FINGERPRINT] = options.system.fingerprint() as usize;
+
+    let (strokes_buckets, usize_buffer) =
+        usize_buffer.split_at_mut(strokes_table_maker.get_buckets_length());
+
+    let (strings_buckets, usize_buffer) =
+        usize_buffer.split_at_mut(strings_table_maker.get_buckets_length());
+
+    let (folded_buckets, usize_buffer) =
+        usize_buffer.split_at_mut(folded_table_maker.get_buckets_length());
+
+    let (visible_buckets, indexes) =
+        usize_buffer.split_at_mut(visible_table_maker.get_buckets_length());
+
+    let (strokes_data, u8_buffer) = 
+        u8_buffer
+        .split_at_mut(strokes_table_maker.get_data_length());
+
+    let (strings_data, u8_buffer) =
+        u8_buffer
+        .split_at_mut(strings_table_maker.get_data_length());
 
-    let (strokes_buckets, strings_buckets) =
-        usize_buffer[2..]
-        .split_at_mut(strokes_table_maker.get_buckets_length());
+    let (folded_data, u8_buffer) =
+        u8_buffer
+        .split_at_mut(folded_table_maker.get_data_length());
 
-    let (strokes_data, strings_data) = 
+    let (visible_data, substring_index) =
         u8_buffer
-        .split_at_mut(strokes_table_maker.get_data_length());
+        .split_at_mut(visible_table_maker.get_data_length());
 
     // make the hash tables!
     //println!("making strokes table");
     let mut strokes_table = strokes_table_maker.make_hash_table(strokes_buckets, strokes_data);
     //println!("making strings table");
     let mut strings_table = strings_table_maker.make_hash_table(strings_buckets, strings_data);
+    let mut folded_table = folded_table_maker.make_hash_table(folded_buckets, folded_data);
+    let mut visible_table = visible_table_maker.make_hash_table(visible_buckets, visible_data);
 
     //println!("writing values");
 
+    // the strokes as written in the dictionary, for reporting collisions
+    let mut raw_strokes_iterator = BufferIterator::new(intermediate).step_by(2);
+    let mut current_source = 0;
+    let mut entry_index = 0;
+
     // write our values
-    for (strokes, translation) in strokes_iterator.zip(strings_iterator) {
+    for ((strokes, translation), source) in strokes_iterator.zip(strings_iterator).zip(sources_iterator) {
+        let raw_strokes = raw_strokes_iterator.next().unwrap();
+        if source != current_source {
+            current_source = source;
+            entry_index = 0;
+        }
+
         let mut strokes_bucket_iterator = strokes_table.get_bucket_iterator_from_key_iterator(strokes.clone());
         let strokes_entry = strokes_bucket_iterator
             .find(|entry| compare_with_iterator(entry.key, strokes.clone()) && entry.value == u32::MAX)
@@ -368,399 +798,826 @@ pub fn load_json_internal<ContainerType>(mut buffer: &mut [u8]) -> InternalResul
         let strokes_entry_handle = strokes_entry.to_handle();
         let translation_entry_handle = translation_entry.to_handle();
 
+        // entries are written in order, so if another entry of this
+        // dictionary already has the same strokes, it came earlier.
+        let previous_entry = strokes_table.get_bucket_iterator_from_key_iterator(strokes.clone())
+            .filter(|entry| entry.value != u32::MAX && entry.tag == source && compare_with_iterator(entry.key, strokes.clone()))
+            .last();
+
+        if let Some(previous_entry) = previous_entry {
+            report_collision(Collision {
+                dictionary: source,
+                entry: entry_index,
+                strokes: raw_strokes,
+                translation: strings_table.get_entry(translation_offset).key,
+                previous_translation: strings_table.get_entry(previous_entry.value as usize).key
+            });
+        }
+
         strokes_table.set_value(strokes_entry_handle, translation_offset.try_into().unwrap());
+        strokes_table.set_tag(strokes_entry_handle, source);
         strings_table.set_value(translation_entry_handle, strokes_offset.try_into().unwrap());
+
+        if !is_folded(translation.as_slice()) {
+            let folded = Fold::new(translation.as_slice());
+            let folded_entry = folded_table.get_bucket_iterator_from_key_iterator(folded.clone())
+                .find(|entry| compare_with_iterator(entry.key, folded.clone()) && entry.value == u32::MAX)
+                .expect("Populating hash table: no fitting entry found!");
+
+            let folded_entry_handle = folded_entry.to_handle();
+            folded_table.set_value(folded_entry_handle, translation_offset.try_into().unwrap());
+        }
+
+        if has_hidden_markup(translation.as_slice()) {
+            let visible = VisibleText::new(translation.as_slice());
+            let visible_entry = visible_table.get_bucket_iterator_from_key_iterator(visible.clone())
+                .find(|entry| compare_with_iterator(entry.key, visible.clone()) && entry.value == u32::MAX)
+                .expect("Populating hash table: no fitting entry found!");
+
+            let visible_entry_handle = visible_entry.to_handle();
+            visible_table.set_value(visible_entry_handle, translation_offset.try_into().unwrap());
+        }
+
+        entry_index += 1;
     }
 
+    mark_shadowed_entries(&mut strokes_table, options.duplicates);
+
+    // each index goes right after the part of the one before it that's
+    // actually used
+    let translation_index_length = fill_translation_index(indexes, &strokes_table, &strings_table);
+    let (translation_index, indexes) = indexes.split_at_mut(translation_index_length);
+    let length_index_length = fill_length_index(indexes, translation_index, &strings_table);
+    let (_, indexes) = indexes.split_at_mut(length_index_length);
+    let (substring_entries_length, substring_index_length) = fill_substring_index(indexes, substring_index, translation_index, &strings_table);
+    let (_, outline_index) = indexes.split_at_mut(substring_entries_length);
+    header[HEADER_TRANSLATION_INDEX] = translation_index_length;
+    header[HEADER_LENGTH_INDEX] = length_index_length;
+    header[HEADER_SUBSTRING_ENTRIES] = substring_entries_length;
+    header[HEADER_SUBSTRING_INDEX] = substring_index_length;
+    header[HEADER_OUTLINE_INDEX] = fill_outline_index(outline_index, &strokes_table, options.system.stroke_width());
+
     return Ok(container);
 }
 
-// rewrites a json string into a length-prefixed version, un-escaping simple
-// escapes, and checking validity for the stroke strings.
-fn rewrite_string<'a>(buffer: &mut[u8], read_pos: &mut usize, write_pos: &mut usize, is_strokes: bool) -> InternalResult<()> {
+// the translation index lists the entries of the strings table sorted by
+// their translations (byte by byte, which for utf-8 is the same as by code
+// point), so that prefix_search_internal can find all translations starting
+// with something with a binary search. entries with the same translation
+// stay in the order of the strings table. shadowed entries are left out,
+// so they don't count towards the number of matches. returns the number of
+// entries in the index.
+fn fill_translation_index(translation_index: &mut [usize], strokes_table: &HashTable, strings_table: &HashTable) -> usize {
+    let mut length = 0;
+
+    for bucket_index in 0..strings_table.buckets.len() {
+        for entry in strings_table.get_bucket_iterator_by_index(bucket_index) {
+            let strokes_entry = strokes_table.get_entry(entry.value as usize);
+            if strokes_entry.tag & SHADOWED == 0 {
+                translation_index[length] = entry.get_offset();
+                length += 1;
+            }
+        }
+    }
 
+    let translation = |offset: usize| strings_table.get_entry(offset).key;
+    translation_index[..length].sort_unstable_by(|&a, &b| {
+        translation(a).cmp(translation(b))
+            .then(a.cmp(&b))
+    });
 
-    // EXPECTATION: read_pos >= write_pos + 1
+    length
+}
 
-    // we're just going to turn the json strings
-    // into length-prefixed strings (2 bytes)
-    // 
-    // yes, there are going to be lots of unaligned reads,
-    // but worst-case, these should translate to two byte-sized
-    // reads plus a shift and an or. i'd considered making some
-    // sort of variable-length encoding for this, but i don't see
-    // a way to get significant improvements in performance like that.
-    //
-    // I'm just going to put this here in case i need it later
-    // return Err(error!(b"I'm sorry, but we can't handle your dictionary.", b"There is nothing wrong with it, except that it has at least one really reaaallly long entry, and this is not something that our internal format can deal with."));
+// the length index lists every translation of the translation index that
+// isn't too long for fuzzy::distance, once, sorted by the number of
+// characters (and then like the translation index), so that
+// fuzzy_query_internal only has to look at the ones that are about as long
+// as the query. returns the number of entries in the index.
+fn fill_length_index(length_index: &mut [usize], translation_index: &[usize], strings_table: &HashTable) -> usize {
+    let translation = |offset: usize| strings_table.get_entry(offset).key;
+    let mut length = 0;
+    let mut previous: Option<&[u8]> = None;
+
+    for &offset in translation_index {
+        let current = translation(offset);
+        if previous != Some(current) && fuzzy::character_count(current) <= fuzzy::MAX_LENGTH {
+            length_index[length] = offset;
+            length += 1;
+        }
+        previous = Some(current);
+    }
 
-    expect_char(&buffer, read_pos, b'"')?;
-    // INVARIANT: read_pos >= write_pos + 2
+    length_index[..length].sort_unstable_by(|&a, &b| {
+        fuzzy::character_count(translation(a)).cmp(&fuzzy::character_count(translation(b)))
+            .then(translation(a).cmp(translation(b)))
+    });
 
-    let length_header_offset = *write_pos;
-    *write_pos += 2;
-    // INVARIANT: read_pos >= write_pos
+    length
+}
 
-    let mut escape_next = false;
-    let mut num_strokes = 1;
+// the offsets in translation that go into the substring index (only the
+// ones where a character starts, since queries always do as well)
+fn substring_positions(translation: &[u8]) -> impl Iterator<Item = usize> + '_ {
+    translation.iter()
+        .enumerate()
+        .filter(|(_, &byte)| byte & 0xC0 != 0x80)
+        .map(|(position, _)| position)
+}
 
-    loop {
-        // INVARIANT: read_pos >= write_pos and (escape_next == true => read_pos >= write_pos + 1)
-        
-        let byte = *buffer.get(*read_pos).ok_or(error!(PARSER_ERROR, b"Parser error: data ended in the middle of string"))?;
-        *read_pos += 1;
-        // INVARIANT: read_pos >= write_pos + 1 and (escape_next == true => read_pos >= write_pos + 2)
-
-        if escape_next {
-            // INVARIANT: read_pos >= write_pos + 2
-            if byte == b'"' || byte == b'\\' {
-                buffer[*write_pos] = byte;
-                *write_pos += 1;
-                // INVARIANT: read_pos >= write_pos + 1
-            }
-            else {
-                // copy the escape sequence unchanged
-                buffer[*write_pos] = b'\\';
-                buffer[*write_pos+1] = byte;
-                *write_pos += 2;
-                // INVARIANT: read_pos >= write_pos
+// how many bytes from each place in the substring index count for its
+// order. this keeps long translations (especially ones that repeat
+// themselves) from making the sort slow.
+const SUBSTRING_COMPARED_LENGTH: usize = 64;
+
+// the substring index is a suffix array over the strings table data: it
+// lists every place where a substring can start in a translation of the
+// translation index (once for each translation, even if it has several
+// entries), sorted by the next SUBSTRING_COMPARED_LENGTH bytes of the data
+// from there on. so all places where something shows up are next to each
+// other, and substring_search_internal can find them with a binary search.
+// the places are offsets into the data, packed into as few bytes as the
+// data needs (see packed.rs). the data after a place goes on past the end
+// of its translation, so the search has to check for that.
+//
+// to find out which translation a place is in, there's also a list of
+// the strings table entries of these translations, sorted by their
+// offsets. returns the length of that list and the number of places.
+fn fill_substring_index(substring_entries: &mut [usize], substring_index: &mut [u8], translation_index: &[usize], strings_table: &HashTable) -> (usize, usize) {
+    let data: &[u8] = strings_table.data;
+    let width = packed::width(data.len());
+    let mut entries_length = 0;
+    let mut length = 0;
+    let mut previous: Option<&[u8]> = None;
+
+    for &offset in translation_index {
+        let entry = strings_table.get_entry(offset);
+        if previous != Some(entry.key) {
+            substring_entries[entries_length] = offset;
+            entries_length += 1;
+
+            for position in substring_positions(entry.key) {
+                packed::write(substring_index, width, length, entry.get_key_offset() + position);
+                length += 1;
             }
-            escape_next = false;
-            // INVARIANT: escape_next == false and read_pos >= write_pos
         }
+        previous = Some(entry.key);
+    }
 
-        else {
-            // INVARIANT: read_pos >= write_pos + 1
-            if byte == b'"' {
-                break;
-                // INVARIANT: read_pos >= write_pos + 1
-            }
-            else if byte == b'\\' {
-                if is_strokes {
-                    // the stroke parser can't handle those, so we have to make sure
-                    // they won't be in there.
-                    return Err(error!(PARSER_ERROR, b"Parser error: escape sequence found in stroke definition"));
-                }
-                escape_next = true;
-                // INVARIANT: escape_next == true and read_pos >= write_pos + 1
-            }
-            else {
-                buffer[*write_pos] = byte;
-                *write_pos += 1;
-                // INVARIANT: read_pos >= write_pos + 1
-            }
+    substring_entries[..entries_length].sort_unstable();
+
+    let compared = |offset: usize| &data[offset..data.len().min(offset + SUBSTRING_COMPARED_LENGTH)];
+    packed::sort_by(&mut substring_index[..length * width], width, |a, b| {
+        compared(a).cmp(compared(b))
+            .then(a.cmp(&b))
+    });
 
-            // BRANCH INVARIANTS:
-            //   IF: (omitted, control flow doesn't land here)
-            //   ELSE IF: escape_next == true and read_pos >= write_pos + 1
-            //   ELSE: read_pos >= write_pos
-            //
-            // INVARIANT: read_pos >= write_pos and (escape_next == true => read_pos >= write_pos + 1)
+    (entries_length, length)
+}
 
-            if is_strokes && byte == b'/' {
-                num_strokes += 1;
+// the outline index lists the entries of the strokes table sorted by their
+// number of strokes, and then by the strokes themselves (by their values,
+// not by how they're packed). so for each length, all outlines that start
+// with the same strokes are next to each other, and
+// outline_prefix_search_internal can find them with a binary search, the
+// shortest ones first. shadowed entries are left out. returns the number
+// of entries in the index.
+fn fill_outline_index(outline_index: &mut [usize], strokes_table: &HashTable, stroke_width: StrokeWidth) -> usize {
+    let mut length = 0;
+
+    for bucket_index in 0..strokes_table.buckets.len() {
+        for entry in strokes_table.get_bucket_iterator_by_index(bucket_index) {
+            if entry.tag & SHADOWED == 0 {
+                outline_index[length] = entry.get_offset();
+                length += 1;
             }
         }
-
-        // BRANCH INVARIANTS:
-        //   IF: escape_next == false and read_pos >= write_pos
-        //   ELSE: read_pos >= write_pos and (escape_next == true => read_pos >= write_pos + 1)
-        //
-        // INVARIANT: read_pos >= write_pos and (escape_next == true => read_pos >= write_pos + 1)
     }
-    // INVARIANT: read_pos >= write_pos + 1 (there is only one break from the above loop)
 
-    // validation: the max number of strokes is 1000, this restriction comes from BufferIterator
-    if num_strokes > 1000 {
-        return Err(error!(b"I'm sorry, but we can't handle your dictionary.", b"There is nothing wrong with it, except that one definition uses more than a thousand strokes, which is not something we can currently deal with."));
-    }
+    let strokes = |offset: usize| stroke_width.unpack_all(strokes_table.get_entry(offset).key);
+    outline_index[..length].sort_unstable_by(|&a, &b| {
+        strokes(a).count().cmp(&strokes(b).count())
+            .then_with(|| strokes(a).cmp(strokes(b)))
+            .then(a.cmp(&b))
+    });
 
-    let length: u16 = (*write_pos - length_header_offset).try_into()
-        .map_err(|_e| error!(b"I'm sorry, but we can't handle your dictionary.", b"There is nothing wrong with it, except that it has at least one really reaaallly long entry, and this is not something that our internal format can deal with."))?;
+    length
+}
 
-    buffer[length_header_offset .. length_header_offset + 2]
-        .copy_from_slice(&(length as u16).to_ne_bytes());
+// resolves overrides the way plover does: if several entries have the
+// same strokes, the one from the dictionary with the highest priority
+// wins, and within one dictionary, the one that comes last wins (since
+// that's what happens when python loads a json object with duplicate
+// keys), unless duplicates says otherwise. all other entries get marked
+// as shadowed, so the query functions can skip them.
+fn mark_shadowed_entries(strokes_table: &mut HashTable, duplicates: DuplicatePolicy) {
+
+    // entries with the same key always end up in the same bucket, in
+    // the same order as in the intermediate buffer. so an entry is
+    // overridden by another one if that one has a lower source index,
+    // or the same source index and comes later (or earlier) in the bucket.
+    let beats = |entry: &hashtable::Entry, other: &hashtable::Entry| {
+        let source = entry.tag & SOURCE_MASK;
+        let other_source = other.tag & SOURCE_MASK;
+        let beats_within_dictionary = match duplicates {
+            DuplicatePolicy::LastWins => other.get_offset() > entry.get_offset(),
+            DuplicatePolicy::FirstWins => other.get_offset() < entry.get_offset(),
+            DuplicatePolicy::KeepAll => false
+        };
+        other_source < source || (other_source == source && beats_within_dictionary)
+    };
 
-    // INVARIANT: read_pos >= write_pos + 1
+    for bucket_index in 0..strokes_table.buckets.len() {
+        let (mut offset, bucket_end) = strokes_table.get_bucket_range(bucket_index);
 
-    Ok(())
-}
+        while offset < bucket_end {
+            let entry = strokes_table.get_entry(offset);
+            offset += entry.get_length();
 
-fn skip_whitespace(buffer: &[u8], pos: &mut usize) -> InternalResult<()> {
-    while let Some(&byte) = buffer.get(*pos) {
-        if byte != b' '
-            && byte != b'\t'
-            && byte != b'\r'
-            && byte != b'\n'
-         {
-            // character is non-whitespace. don't advance position,
-            // return and let the caller continue.
-            return Ok(());
-        }
+            let is_shadowed = strokes_table.get_bucket_iterator_by_index(bucket_index)
+                .any(|other| other.key == entry.key && beats(&entry, &other));
 
-        *pos += 1;
+            if is_shadowed {
+                let tag = entry.tag | SHADOWED;
+                let handle = entry.to_handle();
+                strokes_table.set_tag(handle, tag);
+            }
+        }
     }
-    return Err(error!(PARSER_ERROR, b"Parser error: data incomplete"));
 }
 
-fn expect_char(buffer: &[u8], pos: &mut usize, expected: u8) -> InternalResult<()> {
-    if let Some(byte) = buffer.get(*pos) {
-        if *byte == expected {
-            *pos += 1;
-            return Ok(());
-        }
-        else {
-            // todo: maybe find a more elegant way to statically determine
-            // the interpolation positions?
-            let message = b"Parser error: expected '$', but got '$'";
-            // copy this onto the stack (i think) so we can format it.
-            let mut formatted_message = *message;
-            formatted_message[24] = expected;
-            formatted_message[37] = *byte;
-            // TODO: if we add wee_alloc, we can output better error messages again
-            //log_err_internal(error!(PARSER_ERROR, &formatted_message));
-            return Err(error!(PARSER_ERROR, message));
-        }
-    }
-    else {
-        let message = b"Parser error: expected '$', but hit the end of data";
-        let mut formatted_message = *message;
-        formatted_message[24] = expected;
-        //log_err_internal(error!(PARSER_ERROR, &formatted_message));
-        return Err(error!(PARSER_ERROR, message));
-    }
-}
-
-// structure of this table:
-//  - starts at ' ', 64 entries long
-//  - it then repeats (so the same but bit 6 is set) for the right bank
-//  - the three low bytes for each entry are the stroke bits
-//  - bit 30 (ie bit 6 of the top byte) is a special bit that distinguishes between left and right bank
-//  - the msb signals a stop condition
-static STOP: u32 = 1 << 31;
-static IGNORE: u32 = 0;
-static NUMBER: u32 = 1 << 0;
-static RIGHT_BANK: u32 = 1 << 30;
-
-static PARSE_STROKE_TABLE: [u32; 128] = [
-    IGNORE, // ' '
-    IGNORE, // '!'
-    STOP, // "
-    NUMBER, // #
-    IGNORE, // $
-    IGNORE, // %
-    IGNORE, // &
-    IGNORE, // ' (this is ignore since json only does ""-delimited strings
-    IGNORE, // (
-    IGNORE, // )
-    (1 << 10) | RIGHT_BANK, // *
-    IGNORE, // +
-    IGNORE, // ,
-    RIGHT_BANK, // -
-    IGNORE, // .
-    STOP, // /
-    NUMBER | (1 << 9) | RIGHT_BANK, // 0
-    NUMBER | (1 << 1), // 1
-    NUMBER | (1 << 2), // 2
-    NUMBER | (1 << 4), // 3
-    NUMBER | (1 << 6), // 4
-    NUMBER | (1 << 8) | RIGHT_BANK, // 5
-    NUMBER | (1 << 13), // 6
-    NUMBER | (1 << 15), // 7
-    NUMBER | (1 << 17), // 8
-    NUMBER | (1 << 19), // 9
-    IGNORE, // :
-    IGNORE, // ;
-    IGNORE, // <
-    IGNORE, // =
-    IGNORE, // >
-    IGNORE, // ?
-    IGNORE, // @
-    (1 << 8) | RIGHT_BANK, // A
-    IGNORE, // B
-    IGNORE, // C
-    IGNORE, // D
-    (1 << 11) | RIGHT_BANK, // E
-    IGNORE, // F
-    IGNORE, // G
-    (1 << 6), // H
-    IGNORE, // I
-    IGNORE, // J
-    (1 << 3), // K
-    IGNORE, // L
-    IGNORE, // M
-    IGNORE, // N
-    (1 << 9) | RIGHT_BANK, // O
-    (1 << 4), // P
-    IGNORE, // Q
-    (1 << 7), // R
-    (1 << 1), // S
-    (1 << 2), // T
-    (1 << 12) | RIGHT_BANK, // U
-    IGNORE, // V
-    (1 << 5), // W
-    IGNORE, // X
-    IGNORE, // Y
-    IGNORE, // Z
-
-    // this is just filler to pad to 64 entries
-    IGNORE, // [
-    IGNORE, // \
-    IGNORE, // ]
-    IGNORE, // ^
-    IGNORE, // _
-
-    // right-bank table starts here
-    // everything here needs to have the RIGHT_BANK bit set,
-    // otherwise we'll fall back to the left bank, which shouldn't happen.
-    RIGHT_BANK | IGNORE, // ' '
-    RIGHT_BANK | IGNORE, // '!'
-    RIGHT_BANK | STOP, // "
-    RIGHT_BANK | NUMBER, // # <- this shouldn't actually appear right bank, but I'm not sure I care
-    RIGHT_BANK | IGNORE, // $
-    RIGHT_BANK | IGNORE, // %
-    RIGHT_BANK | IGNORE, // &
-    RIGHT_BANK | IGNORE, // ' (this is ignore since json only does ""-delimited strings
-    RIGHT_BANK | IGNORE, // (
-    RIGHT_BANK | IGNORE, // )
-    RIGHT_BANK | (1 << 10), // *
-    RIGHT_BANK | IGNORE, // +
-    RIGHT_BANK | IGNORE, // ,
-    RIGHT_BANK, // -
-    RIGHT_BANK | IGNORE, // .
-    RIGHT_BANK | STOP, // /
-    RIGHT_BANK | NUMBER | (1 << 9), // 0
-    RIGHT_BANK | NUMBER | (1 << 1), // 1
-    RIGHT_BANK | NUMBER | (1 << 2), // 2
-    RIGHT_BANK | NUMBER | (1 << 4), // 3
-    RIGHT_BANK | NUMBER | (1 << 6), // 4
-    RIGHT_BANK | NUMBER | (1 << 8), // 5
-    RIGHT_BANK | NUMBER | (1 << 13), // 6
-    RIGHT_BANK | NUMBER | (1 << 15), // 7
-    RIGHT_BANK | NUMBER | (1 << 17), // 8
-    RIGHT_BANK | NUMBER | (1 << 19), // 9
-    RIGHT_BANK | IGNORE, // :
-    RIGHT_BANK | IGNORE, // ;
-    RIGHT_BANK | IGNORE, // <
-    RIGHT_BANK | IGNORE, // =
-    RIGHT_BANK | IGNORE, // >
-    RIGHT_BANK | IGNORE, // ?
-    RIGHT_BANK | IGNORE, // @
-    RIGHT_BANK | (1 << 8), // A
-    RIGHT_BANK | (1 << 16), // B
-    RIGHT_BANK | IGNORE, // C
-    RIGHT_BANK | (1 << 21), // D
-    RIGHT_BANK | (1 << 11), // E
-    RIGHT_BANK | (1 << 13), // F
-    RIGHT_BANK | (1 << 18), // G
-    RIGHT_BANK | IGNORE, // H
-    RIGHT_BANK | IGNORE, // I
-    RIGHT_BANK | IGNORE, // J
-    RIGHT_BANK | IGNORE, // K
-    RIGHT_BANK | (1 << 17), // L
-    RIGHT_BANK | IGNORE, // M
-    RIGHT_BANK | IGNORE, // N
-    RIGHT_BANK | (1 << 9), // O
-    RIGHT_BANK | (1 << 15), // P
-    RIGHT_BANK | IGNORE, // Q
-    RIGHT_BANK | (1 << 14), // R
-    RIGHT_BANK | (1 << 20), // S
-    RIGHT_BANK | (1 << 19), // T
-    RIGHT_BANK | (1 << 12), // U
-    RIGHT_BANK | IGNORE, // V
-    RIGHT_BANK | IGNORE, // W
-    RIGHT_BANK | IGNORE, // X
-    RIGHT_BANK | IGNORE, // Y
-    RIGHT_BANK | (1 << 22), // Z
-
-    RIGHT_BANK | // this is just filler to pad to 64 entries
-    RIGHT_BANK | IGNORE, // [
-    RIGHT_BANK | IGNORE, // \
-    RIGHT_BANK | IGNORE, // ]
-    RIGHT_BANK | IGNORE, // ^
-    RIGHT_BANK | IGNORE, // _
-    
-];
+fn write_utf8(buffer: &mut [u8], pos: &mut usize, code_point: u32) {
+    // read_unicode_escape never returns surrogates, so this can't fail
+    let character = char::from_u32(code_point).unwrap_or('\u{FFFD}');
+    let length = character.encode_utf8(&mut buffer[*pos..]).len();
+    *pos += length;
+}
 
-// pos is a pointer, so the calling code can pick up
-// where we left off
-// TODO: what about zero-length strokes or other malformed input? (error handling??)
-fn parse_stroke_fast(buffer: &[u8], pos: &mut usize) -> u32 {
+// the start of the usize buffer of every container, one group of slots
+// for each table. after this come the buckets of all four hash tables, and
+// then the translation index, the length index, the substring entries and
+// the outline index. the u8 buffer has the data of the four hash tables, in
+// the same order, and then the substring index.
+
+// the lengths of the strokes table arrays, and how the strokes are packed.
+// the js side reads the stroke width as well, so it stays in slot 2.
+const HEADER_STROKES_BUCKETS: usize = 0;
+const HEADER_STROKES_DATA: usize = 1;
+const HEADER_STROKE_WIDTH: usize = 2;
+// the lengths of the strings table arrays and the translation index
+const HEADER_STRINGS_BUCKETS: usize = 3;
+const HEADER_STRINGS_DATA: usize = 4;
+const HEADER_TRANSLATION_INDEX: usize = 5;
+// the lengths of the folded and visible table arrays
+const HEADER_FOLDED_BUCKETS: usize = 6;
+const HEADER_FOLDED_DATA: usize = 7;
+const HEADER_VISIBLE_BUCKETS: usize = 8;
+const HEADER_VISIBLE_DATA: usize = 9;
+// the length of the length index
+const HEADER_LENGTH_INDEX: usize = 10;
+// the length of the substring entries, and the number of places in the
+// substring index
+const HEADER_SUBSTRING_ENTRIES: usize = 11;
+const HEADER_SUBSTRING_INDEX: usize = 12;
+// the length of the outline index
+const HEADER_OUTLINE_INDEX: usize = 13;
+// the fingerprint of the steno system the dictionary was loaded with (cut
+// down to a usize, on both sides)
+const HEADER_SYSTEM_FINGERPRINT: usize = 14;
+const CONTAINER_HEADER_LENGTH: usize = 15;
+
+// how the strokes in this container are packed (this depends on the steno
+// system it was loaded with)
+pub fn get_stroke_width(container: &impl DataStructuresContainer) -> InternalResult<StrokeWidth> {
+    let width = container.get_usize_buffer()[HEADER_STROKE_WIDTH];
+    u8::try_from(width).ok()
+        .and_then(StrokeWidth::from_u8)
+        .ok_or(error!(b"Internal error: the dictionary data is broken.", b"Unknown stroke width."))
+}
 
-    let mut state = 0;
-    let mut stroke = 0;
-    while (state & (1 << 7)) == 0 && *pos < buffer.len() {
-        let byte = buffer[*pos];
-        *pos += 1;
+// system, but with the strokes packed the way they are in this container.
+// fails if the container was loaded with a different system, since its
+// strokes mean something else then.
+pub fn get_container_system(container: &impl DataStructuresContainer, system: &StenoSystem) -> InternalResult<StenoSystem> {
+    let system_error = |details: &'static [u8]| error!(b"Sorry, this dictionary doesn't work with the current steno system.", details);
 
-        // first, cast up to u32, since we'll have to go up anyways.
-        //  (the other option would be downcasting state, but that's
-        //   just unnecessary effort and it doesn't matter anyways)
-        // then, subtract 32, since the table starts there.
-        // then, clear the top 2 bits, in case we get nasty input.
-        // then add in the right-bank bit, which is in state bit 6.
-        // note that this is also the position we need for the lookup!
-        let index = ((byte as u32 - 32) & 63) | (state & (1 << 6));
-        let result = PARSE_STROKE_TABLE[index as usize];
-        stroke |= result & 0x00FFFFFF;
-        state = result >> 24 as u8;
+    if container.get_usize_buffer()[HEADER_SYSTEM_FINGERPRINT] != system.fingerprint() as usize {
+        return Err(system_error(b"The dictionary was loaded with a different steno system."));
     }
 
-    return stroke;
+    system.with_stroke_width(get_stroke_width(container)?)
+        .map_err(system_error)
 }
 
-fn get_hashtables_from_container(container: &mut impl DataStructuresContainer) -> InternalResult<(HashTable, HashTable)> {
+// everything the query functions need, see build_container
+struct Tables<'a> {
+    // strokes -> offset of the translation in the strings table
+    strokes: HashTable<'a>,
+    // translation -> offset of the strokes in the strokes table
+    strings: HashTable<'a>,
+    // folded translation -> offset of the translation in the strings table
+    folded: HashTable<'a>,
+    // visible text (see markup.rs) -> offset of the translation in the strings table
+    visible: HashTable<'a>,
+    // offsets into the strings table, see fill_translation_index
+    translation_index: &'a [usize],
+    // offsets into the strings table, see fill_length_index
+    length_index: &'a [usize],
+    // offsets into the strings table, see fill_substring_index
+    substring_entries: &'a [usize],
+    // packed offsets into the strings table data, see fill_substring_index
+    substring_index: &'a [u8],
+    // offsets into the strokes table, see fill_outline_index
+    outline_index: &'a [usize]
+}
 
-    let (usize_buffer, u8_buffer) = container.get_both_buffers_mut();
+fn get_hashtables_from_container(container: &mut impl DataStructuresContainer) -> InternalResult<Tables<'_>> {
 
-    let strokes_buckets_length = usize_buffer[0];
-    let strokes_data_length = usize_buffer[1];
+    let (usize_buffer, u8_buffer) = container.get_both_buffers_mut();
 
-    let (strokes_buckets, strings_buckets) =
-        usize_buffer[2..]
+    let strokes_buckets_length = usize_buffer[HEADER_STROKES_BUCKETS];
+    let strokes_data_length = usize_buffer[HEADER_STROKES_DATA];
+    let strings_buckets_length = usize_buffer[HEADER_STRINGS_BUCKETS];
+    let strings_data_length = usize_buffer[HEADER_STRINGS_DATA];
+    let translation_index_length = usize_buffer[HEADER_TRANSLATION_INDEX];
+    let folded_buckets_length = usize_buffer[HEADER_FOLDED_BUCKETS];
+    let folded_data_length = usize_buffer[HEADER_FOLDED_DATA];
+    let visible_buckets_length = usize_buffer[HEADER_VISIBLE_BUCKETS];
+    let visible_data_length = usize_buffer[HEADER_VISIBLE_DATA];
+    let length_index_length = usize_buffer[HEADER_LENGTH_INDEX];
+    let substring_entries_length = usize_buffer[HEADER_SUBSTRING_ENTRIES];
+    let substring_index_length = usize_buffer[HEADER_SUBSTRING_INDEX];
+    let outline_index_length = usize_buffer[HEADER_OUTLINE_INDEX];
+
+    let (strokes_buckets, usize_buffer) =
+        usize_buffer[CONTAINER_HEADER_LENGTH..]
         .split_at(strokes_buckets_length);
 
-    let (strokes_data, strings_data) = 
+    let (strings_buckets, usize_buffer) =
+        usize_buffer.split_at(strings_buckets_length);
+
+    let (folded_buckets, usize_buffer) =
+        usize_buffer.split_at(folded_buckets_length);
+
+    let (visible_buckets, usize_buffer) =
+        usize_buffer.split_at(visible_buckets_length);
+
+    let (translation_index, usize_buffer) =
+        usize_buffer.split_at(translation_index_length);
+
+    let (length_index, usize_buffer) =
+        usize_buffer.split_at(length_index_length);
+
+    let (substring_entries, outline_index) =
+        usize_buffer.split_at(substring_entries_length);
+
+    let (strokes_data, u8_buffer) = 
         u8_buffer
         .split_at_mut(strokes_data_length);
 
-    Ok((
-        HashTable {
+    let (strings_data, u8_buffer) =
+        u8_buffer
+        .split_at_mut(strings_data_length);
+
+    let (folded_data, u8_buffer) =
+        u8_buffer
+        .split_at_mut(folded_data_length);
+
+    let (visible_data, substring_index) =
+        u8_buffer
+        .split_at_mut(visible_data_length);
+
+    Ok(Tables {
+        strokes: HashTable {
             buckets: strokes_buckets,
-            data: strokes_data
+            data: strokes_data,
+            tag_size: 1
         },
-        HashTable {
+        strings: HashTable {
             buckets: strings_buckets,
-            data: strings_data
-        }
-    ))
+            data: strings_data,
+            tag_size: 0
+        },
+        folded: HashTable {
+            buckets: folded_buckets,
+            data: folded_data,
+            tag_size: 0
+        },
+        visible: HashTable {
+            buckets: visible_buckets,
+            data: visible_data,
+            tag_size: 0
+        },
+        translation_index,
+        length_index,
+        substring_entries,
+        substring_index: &substring_index[..substring_index_length * packed::width(strings_data_length)],
+        outline_index: &outline_index[..outline_index_length]
+    })
+}
+
+// the results of query_internal, in the order of the hash table
+fn query_matches<'a>(query: &'a [u8], strokes_table: &'a HashTable, strings_table: &'a HashTable) -> impl Iterator<Item = QueryResult<'a>> {
+    strings_table.get_values(query)
+        .map(|strokes_offset| strokes_table.get_entry(strokes_offset as usize))
+        .filter(|strokes_entry| strokes_entry.tag & SHADOWED == 0)
+        .map(move |strokes_entry| QueryResult {
+            strokes: strokes_entry.key,
+            translation: query,
+            source: strokes_entry.tag & SOURCE_MASK,
+            exact: true
+        })
+}
+
+// the result for an entry of the strokes table
+fn strokes_entry_result<'a>(strokes_entry: hashtable::Entry<'a>, strings_table: &'a HashTable) -> QueryResult<'a> {
+    QueryResult {
+        strokes: strokes_entry.key,
+        translation: strings_table.get_entry(strokes_entry.value as usize).key,
+        source: strokes_entry.tag & SOURCE_MASK,
+        exact: true
+    }
+}
+
+// the results of find_strokes_internal, in the order of the hash table
+fn find_strokes_matches<'a>(query: &'a [u8], strokes_table: &'a HashTable, strings_table: &'a HashTable) -> impl Iterator<Item = QueryResult<'a>> {
+    strokes_table.get_bucket_iterator(query)
+        .filter(move |entry| entry.key == query && entry.tag & SHADOWED == 0)
+        .map(move |strokes_entry| strokes_entry_result(strokes_entry, strings_table))
 }
 
 pub fn query_internal<F>(query: &[u8], container: &mut impl DataStructuresContainer, mut yield_result: F) -> InternalResult<()>
-    where F: FnMut(&[u8], &[u8])
+    where F: FnMut(QueryResult)
 {
-    let (strokes_table, strings_table) = get_hashtables_from_container(container)?;
-    for strokes_offset in strings_table.get_values(query) {
-        let strokes = hashtable::Entry::new(strokes_table.data, strokes_offset as usize).key;
-        yield_result(strokes, query);
+    let Tables { strokes: strokes_table, strings: strings_table, .. } = get_hashtables_from_container(container)?;
+    for result in query_matches(query, &strokes_table, &strings_table) {
+        yield_result(result);
     }
 
     Ok(())
 }
 
 pub fn find_strokes_internal<F>(query: &[u8], container: &mut impl DataStructuresContainer, mut yield_result: F) -> InternalResult<()>
-    where F: FnMut(&[u8], &[u8])
+    where F: FnMut(QueryResult)
 {
-    let (strokes_table, strings_table) = get_hashtables_from_container(container)?;
-
-    for strings_offset in strokes_table.get_values(query) {
-        let translation = hashtable::Entry::new(strings_table.data, strings_offset as usize).key;
-        yield_result(query, translation);
+    let Tables { strokes: strokes_table, strings: strings_table, .. } = get_hashtables_from_container(container)?;
+    for result in find_strokes_matches(query, &strokes_table, &strings_table) {
+        yield_result(result);
     }
 
     return Ok(());
 }
 
+// like query_internal, but the results come best first (see ranking.rs),
+// and only the ones from skip to skip + limit are passed to yield_result,
+// like with prefix_search_internal. the asterisk is looked up in system,
+// which has to fit the container (see get_container_system). returns the
+// total number of results.
+pub fn ranked_query_internal<F>(query: &[u8], container: &mut impl DataStructuresContainer, system: &StenoSystem, skip: usize, limit: usize, yield_result: F) -> InternalResult<usize>
+    where F: FnMut(QueryResult)
+{
+    let ranking = Ranking::new(&get_container_system(container, system)?);
+    let Tables { strokes: strokes_table, strings: strings_table, .. } = get_hashtables_from_container(container)?;
+    let matches = || query_matches(query, &strokes_table, &strings_table);
+
+    Ok(ranking::yield_ranked(&ranking, matches, skip, limit, yield_result))
+}
+
+// the same for find_strokes_internal. all results have the same strokes
+// here, so they're really just ordered by dictionary priority.
+pub fn ranked_find_strokes_internal<F>(query: &[u8], container: &mut impl DataStructuresContainer, system: &StenoSystem, skip: usize, limit: usize, yield_result: F) -> InternalResult<usize>
+    where F: FnMut(QueryResult)
+{
+    let ranking = Ranking::new(&get_container_system(container, system)?);
+    let Tables { strokes: strokes_table, strings: strings_table, .. } = get_hashtables_from_container(container)?;
+    let matches = || find_strokes_matches(query, &strokes_table, &strings_table);
+
+    Ok(ranking::yield_ranked(&ranking, matches, skip, limit, yield_result))
+}
+
+// like prefix_search_internal, but finds all outlines that start with the
+// given packed strokes (see StrokeWidth), like "KAUPB/SAOEUPB" and
+// "KAUPB/TKPWREU" for "KAUPB". the matches are numbered in the order of
+// the outline index, so the shortest ones come first. exact is set for the
+// outline that's the same as prefix.
+pub fn outline_prefix_search_internal<F>(prefix: &[u8], container: &mut impl DataStructuresContainer, skip: usize, limit: usize, mut yield_result: F) -> InternalResult<usize>
+    where F: FnMut(QueryResult)
+{
+    let stroke_width = get_stroke_width(container)?;
+    let Tables { strokes: strokes_table, strings: strings_table, outline_index, .. } = get_hashtables_from_container(container)?;
+    let strokes = |offset: usize| stroke_width.unpack_all(strokes_table.get_entry(offset).key);
+    let prefix_strokes = || stroke_width.unpack_all(prefix);
+    let prefix_length = prefix_strokes().count();
+
+    // the outlines of each length are next to each other, sorted by their
+    // strokes, so the ones of each length that start with prefix are as well
+    let mut group_start = outline_index.partition_point(|&offset| strokes(offset).count() < prefix_length);
+    let mut total = 0;
+
+    while group_start < outline_index.len() {
+        let length = strokes(outline_index[group_start]).count();
+        let group = &outline_index[group_start..];
+        let group = &group[..group.partition_point(|&offset| strokes(offset).count() == length)];
+        group_start += group.len();
+
+        let start = group.partition_point(|&offset| strokes(offset).take(prefix_length).lt(prefix_strokes()));
+        let end = start + group[start..].partition_point(|&offset| strokes(offset).take(prefix_length).eq(prefix_strokes()));
+        let matches = &group[start..end];
+
+        // the part of the page that's in here
+        let page_start = skip.saturating_sub(total).min(matches.len());
+        let page_end = skip.saturating_add(limit).saturating_sub(total).clamp(page_start, matches.len());
+
+        for &offset in &matches[page_start..page_end] {
+            let strokes_entry = strokes_table.get_entry(offset);
+            yield_result(QueryResult {
+                exact: strokes_entry.key == prefix,
+                ..strokes_entry_result(strokes_entry, &strings_table)
+            });
+        }
+        total += matches.len();
+    }
+
+    Ok(total)
+}
+
+// finds all outlines whose strokes match the patterns (see pattern.rs), like
+// every outline with a stroke that has -FRPBLG in it. this has to look at
+// every entry, so it's a lot slower than the other lookups.
+pub fn pattern_search_internal<F>(patterns: &[StrokePattern], position: PatternPosition, container: &mut impl DataStructuresContainer, mut yield_result: F) -> InternalResult<()>
+    where F: FnMut(QueryResult)
+{
+    let stroke_width = get_stroke_width(container)?;
+    let Tables { strokes: strokes_table, strings: strings_table, .. } = get_hashtables_from_container(container)?;
+
+    for bucket_index in 0..strokes_table.buckets.len() {
+        let matching_entries = strokes_table.get_bucket_iterator_by_index(bucket_index)
+            .filter(|entry| entry.tag & SHADOWED == 0)
+            .filter(|entry| pattern::matches_outline(patterns, position, stroke_width, entry.key));
+
+        for strokes_entry in matching_entries {
+            yield_result(QueryResult {
+                strokes: strokes_entry.key,
+                translation: strings_table.get_entry(strokes_entry.value as usize).key,
+                source: strokes_entry.tag & SOURCE_MASK,
+                exact: true
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// finds all translations that start with prefix, in the order of the
+// translation index. the matches are numbered from 0, and only the ones
+// from skip to skip + limit are passed to yield_result, so that they can
+// be shown a page at a time. returns the total number of matches.
+pub fn prefix_search_internal<F>(prefix: &[u8], container: &mut impl DataStructuresContainer, skip: usize, limit: usize, mut yield_result: F) -> InternalResult<usize>
+    where F: FnMut(QueryResult)
+{
+    let Tables { strokes: strokes_table, strings: strings_table, translation_index, .. } = get_hashtables_from_container(container)?;
+    let translation = |offset: &usize| strings_table.get_entry(*offset).key;
+
+    // everything that starts with prefix comes right after prefix itself
+    let start = translation_index.partition_point(|offset| translation(offset) < prefix);
+    let end = start + translation_index[start..].partition_point(|offset| translation(offset).starts_with(prefix));
+
+    for &offset in translation_index[start..end].iter().skip(skip).take(limit) {
+        let strings_entry = strings_table.get_entry(offset);
+        let strokes_entry = strokes_table.get_entry(strings_entry.value as usize);
+        yield_result(QueryResult {
+            strokes: strokes_entry.key,
+            translation: strings_entry.key,
+            source: strokes_entry.tag & SOURCE_MASK,
+            exact: strings_entry.key == prefix
+        });
+    }
+
+    Ok(end - start)
+}
+
+// like query_internal, but finds every translation that folds to the same
+// thing as the query (see fold.rs), so "monday" also finds "Monday". exact
+// is set for translations that are the same as the query without folding.
+pub fn folded_query_internal<F>(query: &[u8], container: &mut impl DataStructuresContainer, mut yield_result: F) -> InternalResult<()>
+    where F: FnMut(QueryResult)
+{
+    let Tables { strokes: strokes_table, strings: strings_table, folded: folded_table, .. } = get_hashtables_from_container(container)?;
+    let folded_query = Fold::new(query);
+
+    // translations that are already folded are only in the strings table,
+    // so they're found by looking for the folded query there
+    let unchanged_matches = strings_table.get_bucket_iterator_from_key_iterator(folded_query.clone())
+        .filter(|entry| compare_with_iterator(entry.key, folded_query.clone()));
+
+    // and the others are in the folded table
+    let folded_matches = folded_table.get_bucket_iterator_from_key_iterator(folded_query.clone())
+        .filter(|entry| compare_with_iterator(entry.key, folded_query.clone()))
+        .map(|entry| strings_table.get_entry(entry.value as usize));
+
+    for strings_entry in unchanged_matches.chain(folded_matches) {
+        let strokes_entry = strokes_table.get_entry(strings_entry.value as usize);
+
+        if strokes_entry.tag & SHADOWED == 0 {
+            yield_result(QueryResult {
+                strokes: strokes_entry.key,
+                translation: strings_entry.key,
+                source: strokes_entry.tag & SOURCE_MASK,
+                exact: strings_entry.key == query
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// like query_internal, but also finds translations with plover formatting
+// by their visible text (see markup.rs), so "ing" also finds "{^ing}", and
+// "," finds "{,}". use QueryResult::attachment to find out how they attach
+// to the words around them. exact is set for translations that are the same
+// as the query, formatting and all.
+pub fn visible_text_query_internal<F>(query: &[u8], container: &mut impl DataStructuresContainer, mut yield_result: F) -> InternalResult<()>
+    where F: FnMut(QueryResult)
+{
+    let Tables { strokes: strokes_table, strings: strings_table, visible: visible_table, .. } = get_hashtables_from_container(container)?;
+
+    let exact_matches = strings_table.get_bucket_iterator(query)
+        .filter(|entry| entry.key == query);
+
+    let visible_matches = visible_table.get_bucket_iterator(query)
+        .filter(|entry| entry.key == query)
+        .map(|entry| strings_table.get_entry(entry.value as usize));
+
+    for strings_entry in exact_matches.chain(visible_matches) {
+        let strokes_entry = strokes_table.get_entry(strings_entry.value as usize);
+
+        if strokes_entry.tag & SHADOWED == 0 {
+            yield_result(QueryResult {
+                strokes: strokes_entry.key,
+                translation: strings_entry.key,
+                source: strokes_entry.tag & SOURCE_MASK,
+                exact: strings_entry.key == query
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// finds translations that are at most max_distance edits away from the
+// query (see fuzzy.rs), so "recieve" also finds "receive". the closest
+// ones come first, and yield_result is called with the result and its
+// distance at most limit times. limit can't be more than
+// fuzzy::MAX_RESULTS. translations that are longer than fuzzy::MAX_LENGTH
+// characters can't be found this way, and neither can anything with a
+// query that long.
+pub fn fuzzy_query_internal<F>(query: &[u8], container: &mut impl DataStructuresContainer, max_distance: usize, limit: usize, mut yield_result: F) -> InternalResult<()>
+    where F: FnMut(QueryResult, usize)
+{
+    let Tables { strokes: strokes_table, strings: strings_table, length_index, .. } = get_hashtables_from_container(container)?;
+    let translation = |offset: &usize| strings_table.get_entry(*offset).key;
+
+    let query_characters = match fuzzy::Characters::new(query) {
+        Some(characters) => characters,
+        None => return Ok(())
+    };
+    let query_length = query_characters.as_slice().len();
+
+    // only translations that are about as long as the query can be close enough
+    let start = length_index.partition_point(|offset| fuzzy::character_count(translation(offset)) + max_distance < query_length);
+    let end = length_index.partition_point(|offset| fuzzy::character_count(translation(offset)) <= query_length + max_distance);
+
+    // every translation in the index has at least one entry, so the
+    // closest limit translations are enough for limit results
+    let mut closest = fuzzy::Closest::new(limit);
+
+    for (index, offset) in length_index.iter().enumerate().take(end).skip(start) {
+        let max_distance = match closest.max_distance(max_distance) {
+            Some(max_distance) => max_distance,
+            None => break
+        };
+
+        let candidate = translation(offset);
+        let distance = fuzzy::Characters::new(candidate)
+            .and_then(|characters| fuzzy::distance(query_characters.as_slice(), characters.as_slice(), max_distance));
+
+        // the translation might only have shadowed entries
+        if let Some(distance) = distance {
+            if query_matches(candidate, &strokes_table, &strings_table).next().is_some() {
+                closest.add(distance, index);
+            }
+        }
+    }
+
+    let results = closest.as_slice().iter()
+        .flat_map(|&(distance, index)| {
+            query_matches(translation(&length_index[index]), &strokes_table, &strings_table)
+                .map(move |result| (QueryResult { exact: distance == 0, ..result }, distance))
+        })
+        .take(limit);
+
+    for (result, distance) in results {
+        yield_result(result, distance);
+    }
+
+    Ok(())
+}
+
+// finds every translation that contains the query, like "as a matter of
+// fact" for "a matter". each translation is passed to yield_result once
+// for every entry it has, together with the range of bytes where the query
+// shows up in it first. the results aren't in any particular order. see
+// fill_substring_index for what can't be found this way.
+pub fn substring_search_internal<F>(query: &[u8], container: &mut impl DataStructuresContainer, mut yield_result: F) -> InternalResult<()>
+    where F: FnMut(QueryResult, Range<usize>)
+{
+    let Tables { strokes: strokes_table, strings: strings_table, substring_entries, substring_index, .. } = get_hashtables_from_container(container)?;
+    let data: &[u8] = strings_table.data;
+    let width = packed::width(data.len());
+    let compared = |offset: usize| &data[offset..data.len().min(offset + SUBSTRING_COMPARED_LENGTH)];
+
+    // every translation contains that
+    if query.is_empty() {
+        return Ok(());
+    }
+
+    // just like prefix_search_internal, but with the places. only the
+    // start of a long query counts for the order, the rest gets checked
+    // for each place.
+    let prefix = &query[..query.len().min(SUBSTRING_COMPARED_LENGTH)];
+    let start = packed::partition_point(substring_index, width, |offset| compared(offset) < prefix);
+    let end = start + packed::partition_point(&substring_index[start * width..], width, |offset| compared(offset).starts_with(prefix));
+
+    for index in start..end {
+        let offset = packed::read(substring_index, width, index);
+
+        // the translation this is in is the last one that starts before it
+        let entry_index = substring_entries.partition_point(|&entry_offset| entry_offset <= offset) - 1;
+        let strings_entry = strings_table.get_entry(substring_entries[entry_index]);
+        let matching_translation = strings_entry.key;
+        let position = offset - strings_entry.get_key_offset();
+
+        // the match mustn't go past the end of the translation
+        if !matching_translation[position..].starts_with(query) {
+            continue;
+        }
+
+        // the later places are skipped, the first one has its own entry
+        let shows_up_earlier = matching_translation[..position + query.len() - 1]
+            .windows(query.len())
+            .any(|window| window == query);
+
+        if shows_up_earlier {
+            continue;
+        }
+
+        for result in query_matches(matching_translation, &strokes_table, &strings_table) {
+            yield_result(QueryResult {
+                exact: matching_translation == query,
+                ..result
+            }, position..position + query.len());
+        }
+    }
+
+    Ok(())
+}
+
+// how many bytes a container uses for each of its tables and indexes
+#[derive(Clone, Copy)]
+#[cfg_attr(test, derive(Debug))]
+pub struct MemoryUsage {
+    pub strokes_table: usize,
+    pub strings_table: usize,
+    pub folded_table: usize,
+    pub visible_table: usize,
+    pub translation_index: usize,
+    pub length_index: usize,
+    pub substring_index: usize,
+    pub outline_index: usize,
+    // everything, including the header and unused index slots
+    pub total: usize
+}
+
+pub fn get_memory_usage(container: &mut impl DataStructuresContainer) -> InternalResult<MemoryUsage> {
+    let total = core::mem::size_of_val(container.get_usize_buffer())
+        + container.get_u8_buffer().len();
+
+    let tables = get_hashtables_from_container(container)?;
+    let table_size = |table: &HashTable| core::mem::size_of_val(table.buckets) + table.data.len();
+
+    Ok(MemoryUsage {
+        strokes_table: table_size(&tables.strokes),
+        strings_table: table_size(&tables.strings),
+        folded_table: table_size(&tables.folded),
+        visible_table: table_size(&tables.visible),
+        translation_index: core::mem::size_of_val(tables.translation_index),
+        length_index: core::mem::size_of_val(tables.length_index),
+        substring_index: core::mem::size_of_val(tables.substring_entries) + tables.substring_index.len(),
+        outline_index: core::mem::size_of_val(tables.outline_index),
+        total
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -769,14 +1626,15 @@ mod tests {
     fn test_parse_stroke() {
         // TODO: I've just precomputed these values and checked that they are correct.
         // but there should be a better way.
+        let system = &ENGLISH_STENOTYPE;
         let mut pos = 0;
-        assert_eq!(parse_stroke_fast(b"KPWHREPLGS/", &mut pos), 1476856);
+        assert_eq!(system.parse_stroke(b"KPWHREPLGS/", &mut pos), 1476856);
         pos = 0;
-        assert_eq!(parse_stroke_fast(b"K-FRBL/", &mut pos), 221192);
+        assert_eq!(system.parse_stroke(b"K-FRBL/", &mut pos), 221192);
         pos = 0;
-        assert_eq!(parse_stroke_fast(b"#AO/", &mut pos), 769);
+        assert_eq!(system.parse_stroke(b"#AO/", &mut pos), 769);
         pos = 0;
-        assert_eq!(parse_stroke_fast(b"50/", &mut pos), 769);
+        assert_eq!(system.parse_stroke(b"50/", &mut pos), 769);
     }
 
     struct Container {
@@ -817,35 +1675,10 @@ mod tests {
         }
     }
 
-    const STENO_ORDER: &str = "#STKPWHRAO*EUFRPBLGTSDZ";
-
-    fn format_stroke(stroke: u32) -> String {
-        STENO_ORDER.chars().enumerate()
-            .filter_map(|(i, val)| {
-                if (stroke & (1 << i)) != 0 {
-                    Some(val)
-                }
-                else {
-                    None
-                }
-            })
-            .collect()
-    }
-
     fn format_strokes(strokes: &[u8]) -> String {
-        strokes.chunks_exact(3).map(|stroke_bytes| {
-            format_stroke(
-                   (stroke_bytes[0] as u32)
-                | ((stroke_bytes[1] as u32) << 8)
-                | ((stroke_bytes[2] as u32) << 16)
-            )
-        }).fold(String::new(), |mut accumulator, stroke| {
-            if accumulator.len() > 0 {
-                accumulator.push_str("/");
-            }
-            accumulator.push_str(&stroke);
-            accumulator
-        })
+        let mut buffer = [0u8; 1024];
+        let length = super::format_strokes(&ENGLISH_STENOTYPE, strokes, &FormatOptions::default(), &mut buffer).unwrap();
+        String::from_utf8(buffer[..length].to_vec()).unwrap()
     }
 
     #[test]
@@ -857,17 +1690,1004 @@ mod tests {
 
         println!("hashtable constructed!");
 
-        query_internal(b"implicit", &mut container, |strokes, translation| {
+        query_internal(b"implicit", &mut container, |result| {
             println!("got result: {}, {}",
-                     format_strokes(strokes),
-                     std::str::from_utf8(translation).unwrap_or("<invalid utf-8>"));
+                     format_strokes(result.strokes),
+                     std::str::from_utf8(result.translation).unwrap_or("<invalid utf-8>"));
         }).unwrap();
 
-        let parsed_strokes: Vec<u8> = ParseStrokesIterator::new(b"KPWHREUFLT").collect();
-        find_strokes_internal(&parsed_strokes, &mut container, |strokes, translation| {
+        let parsed_strokes: Vec<u8> = ParseStrokesIterator::new(&ENGLISH_STENOTYPE, b"KPWHREUFLT").collect();
+        find_strokes_internal(&parsed_strokes, &mut container, |result| {
             println!("got result: {}, {}",
-                     format_strokes(strokes),
-                     std::str::from_utf8(translation).unwrap_or("<invalid utf-8>"));
+                     format_strokes(result.strokes),
+                     std::str::from_utf8(result.translation).unwrap_or("<invalid utf-8>"));
+        }).unwrap();
+    }
+
+    fn lookup(query: &str, container: &mut Container) -> Vec<String> {
+        let mut results = Vec::new();
+        query_internal(query.as_bytes(), container, |result| {
+            results.push(format_strokes(result.strokes));
         }).unwrap();
+        results
+    }
+
+    // loads the dictionaries as one stack, the first one has the highest
+    // priority
+    fn load_stack(dictionaries: &[&[u8]]) -> Container {
+        let mut buffer = dictionaries.concat();
+        let lengths: Vec<usize> = dictionaries.iter().map(|dictionary| dictionary.len()).collect();
+        load_dictionary_stack_internal::<Container>(&mut buffer[..], &lengths).unwrap()
+    }
+
+    fn text(bytes: &[u8]) -> String {
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    fn pack(strokes: &str) -> Vec<u8> {
+        ParseStrokesIterator::new(&ENGLISH_STENOTYPE, strokes.as_bytes()).collect()
+    }
+
+    fn find_strokes(strokes: &str, container: &mut Container) -> Vec<String> {
+        let parsed_strokes: Vec<u8> = ParseStrokesIterator::new(&ENGLISH_STENOTYPE, strokes.as_bytes()).collect();
+        let mut results = Vec::new();
+        find_strokes_internal(&parsed_strokes, container, |result| {
+            results.push(String::from_utf8(result.translation.to_vec()).unwrap());
+        }).unwrap();
+        results
+    }
+
+    // an owned copy of a QueryResult, to compare once the query is done
+    // with the container
+    #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+    struct Found {
+        strokes: String,
+        translation: String,
+        source: u8,
+        exact: bool
+    }
+
+    impl From<QueryResult<'_>> for Found {
+        fn from(result: QueryResult) -> Found {
+            Found { strokes: format_strokes(result.strokes), translation: text(result.translation), source: result.source, exact: result.exact }
+        }
+    }
+
+    fn found(strokes: &str, translation: &str, source: u8, exact: bool) -> Found {
+        Found { strokes: String::from(strokes), translation: String::from(translation), source, exact }
+    }
+
+    // runs a query and keeps everything it yields, along with what it
+    // returns (like the total of a paged search)
+    fn collect<R>(query: impl FnOnce(&mut dyn FnMut(QueryResult)) -> InternalResult<R>) -> (R, Vec<Found>) {
+        let mut results = Vec::new();
+        let returned = query(&mut |result| results.push(Found::from(result))).unwrap();
+        (returned, results)
+    }
+
+    #[test]
+    fn test_unescape() {
+        let mut json_dict = br#"{
+            "TEFT": "\u00e9t\u00e9",
+            "TEFTS": "{^}\n{^}\t\/",
+            "SKWRAOEU": "\ud83d\ude00",
+            "SKWRAOEUS": "\"\\",
+            "TKPWHR": "\ud83d"
+        }"#.to_vec();
+        let mut container = load_json_internal::<Container>(&mut json_dict[..]).unwrap();
+
+        assert_eq!(lookup("été", &mut container), vec!["TEFT"]);
+        assert_eq!(find_strokes("TEFTS", &mut container), vec!["{^}\n{^}\t/"]);
+        assert_eq!(find_strokes("SKWRAOEU", &mut container), vec!["\u{1F600}"]);
+        assert_eq!(find_strokes("SKWRAOEUS", &mut container), vec!["\"\\"]);
+        assert_eq!(find_strokes("TKPWHR", &mut container), vec!["\u{FFFD}"]);
+
+        let mut invalid_escape = br#"{"TEFT": "\x"}"#.to_vec();
+        assert!(load_json_internal::<Container>(&mut invalid_escape[..]).is_err());
+    }
+
+    #[test]
+    fn test_rtf_loader() {
+        let mut rtf_dict = b"{\\rtf1\\ansi{\\*\\cxrev100}\\cxdict{\\*\\cxsystem Plover}{\\stylesheet{\\s0 Normal;}}\r\n\
+            {\\*\\cxs TEFT}test\r\n\
+            {\\*\\cxs TEFT/-G}testing\\par\r\n\
+            {\\*\\cxs -G}\\cxds ing\r\n\
+            {\\*\\cxs KPA}\\cxfc \r\n\
+            {\\*\\cxs P-P}{\\cxp. }\r\n\
+            {\\*\\cxs KAF}caf\\'e9\r\n\
+            {\\*\\cxs TKPWAE}{\\*\\cxcomment old entry}gr\\u252?n\\{\\}\r\n\
+            }\r\n".to_vec();
+        let mut container = load_rtf_internal::<Container>(&mut rtf_dict[..], &LoadOptions::default()).unwrap();
+
+        assert_eq!(find_strokes("TEFT", &mut container), vec!["test"]);
+        assert_eq!(find_strokes("TEFT/-G", &mut container), vec!["testing{^\n\n^}"]);
+        assert_eq!(find_strokes("-G", &mut container), vec!["{^}ing"]);
+        assert_eq!(find_strokes("KPA", &mut container), vec!["{-|}"]);
+        assert_eq!(find_strokes("P-P", &mut container), vec!["{.}"]);
+        assert_eq!(lookup("café", &mut container), vec!["KAF"]);
+        assert_eq!(find_strokes("TKPWAE", &mut container), vec!["grün{}"]);
+
+        let mut not_rtf = b"{\"TEFT\": \"test\"}".to_vec();
+        assert!(load_rtf_internal::<Container>(&mut not_rtf[..], &LoadOptions::default()).is_err());
+
+        // the options apply to RTF too
+        let bad_stroke = b"{\\rtf1\\ansi{\\*\\cxs TEFT}test{\\*\\cxs TQEFT}oops}".to_vec();
+        assert!(load_rtf_internal::<Container>(&mut bad_stroke.clone()[..], &LoadOptions::default()).is_ok());
+        let strict = LoadOptions { strict: true, ..LoadOptions::default() };
+        assert!(load_rtf_internal::<Container>(&mut bad_stroke.clone()[..], &strict).is_err());
+
+        // and so do the reports
+        let mut problems = b"{\\rtf1\\ansi{\\*\\cxs TEFT}test{\\*\\cxs TQ-G}oops{\\*\\cxs TEFT}test 2}".to_vec();
+        let mut diagnostics = Vec::new();
+        let mut collisions = Vec::new();
+        let mut container = load_rtf_with_report::<Container, _, _>(&mut problems[..], LoadOptions::default(), |diagnostic| {
+            diagnostics.push((diagnostic.entry, text(diagnostic.strokes), diagnostic.problem));
+        }, |collision| {
+            collisions.push((collision.entry, text(collision.strokes), text(collision.translation), text(collision.previous_translation)));
+        }).unwrap();
+        assert_eq!(diagnostics, vec![(1, String::from("TQ-G"), Problem::UnknownKey)]);
+        assert_eq!(collisions, vec![(2, String::from("TEFT"), String::from("test 2"), String::from("test"))]);
+        assert_eq!(find_strokes("TEFT", &mut container), vec!["test 2"]);
+    }
+
+    #[test]
+    fn test_dictionary_stack() {
+        let user = br#"{"TEFT": "user test", "TEFT": "user test 2"}"#;
+        let main = br#"{"TEFT": "main test", "TEFTS": "main tests"}"#;
+        let commands = b"{\\rtf1\\ansi\r\n{\\*\\cxs TEFTS}rtf tests\r\n{\\*\\cxs PHRO*FR}\\{PLOVER:TOGGLE\\}\r\n}";
+
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(user);
+        buffer.extend_from_slice(main);
+        buffer.extend_from_slice(commands);
+        let lengths = [user.len(), main.len(), commands.len()];
+
+        let mut container = load_dictionary_stack_internal::<Container>(&mut buffer[..], &lengths).unwrap();
+
+        let mut results = Vec::new();
+        let parsed_strokes: Vec<u8> = ParseStrokesIterator::new(&ENGLISH_STENOTYPE, b"TEFT").collect();
+        find_strokes_internal(&parsed_strokes, &mut container, |result| {
+            results.push((String::from_utf8(result.translation.to_vec()).unwrap(), result.source));
+        }).unwrap();
+        assert_eq!(results, vec![("user test 2".to_string(), 0)]);
+
+        assert_eq!(find_strokes("TEFTS", &mut container), vec!["main tests"]);
+        assert_eq!(find_strokes("PHRO*FR", &mut container), vec!["{PLOVER:TOGGLE}"]);
+
+        // overridden entries don't show up in reverse lookups either
+        assert_eq!(lookup("user test", &mut container), Vec::<String>::new());
+        assert_eq!(lookup("main test", &mut container), Vec::<String>::new());
+        assert_eq!(lookup("rtf tests", &mut container), Vec::<String>::new());
+
+        let mut results = Vec::new();
+        query_internal(b"{PLOVER:TOGGLE}", &mut container, |result| {
+            results.push(result.source);
+        }).unwrap();
+        assert_eq!(results, vec![2]);
+
+        // errors are reported relative to the dictionary they happened in
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(main);
+        buffer.extend_from_slice(b"{\n\"TEFT\" \"test\"}");
+        let error = load_dictionary_stack_internal::<Container>(&mut buffer[..], &[main.len(), 15]).err().unwrap();
+        let position = error.position.unwrap();
+        assert_eq!((position.offset, position.line, position.column), (9, 2, 8));
+    }
+
+    impl IntermediateStorage for Vec<u8> {
+        fn len(&self) -> usize {
+            Vec::len(self)
+        }
+
+        fn push(&mut self, byte: u8) -> InternalResult<()> {
+            Vec::push(self, byte);
+            Ok(())
+        }
+
+        fn set(&mut self, offset: usize, byte: u8) {
+            self[offset] = byte;
+        }
+
+        fn as_slice(&self) -> &[u8] {
+            &self[..]
+        }
+    }
+
+    const STRICT: LoadOptions = LoadOptions {
+        system: &ENGLISH_STENOTYPE,
+        strict: true,
+        duplicates: DuplicatePolicy::LastWins,
+        tolerant: false
+    };
+
+    #[test]
+    fn test_validation_report() {
+        let user = br#"{"KAT": "cat", "CAT/TKOG": "cat dog", "TKOG//TKOG": "dogs"}"#;
+        let main = br#"{"TEFT": "test", "WKPT": "what", "RAOA": "raw"}"#;
+
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(user);
+        buffer.extend_from_slice(main);
+        let lengths = [user.len(), main.len()];
+
+        let mut diagnostics = Vec::new();
+        validate_dictionary_stack_internal(&mut buffer.clone()[..], &lengths, LoadOptions::default(), |diagnostic| {
+            diagnostics.push((diagnostic.dictionary, diagnostic.entry, diagnostic.offset, diagnostic.problem, diagnostic.severity));
+        }).unwrap();
+
+        assert_eq!(diagnostics, vec![
+            (0, 1, 0, Problem::UnknownKey, Severity::Error),
+            (0, 2, 5, Problem::EmptyStroke, Severity::Error),
+            (1, 1, 1, Problem::OutOfOrder, Severity::Error),
+            (1, 2, 3, Problem::DuplicateKey, Severity::Warning),
+        ]);
+
+        // the same problems are reported while loading, but the dictionary still loads
+        let mut count = 0;
+        let mut container = load_dictionary_stack_with_report::<Container, _, _>(&mut buffer.clone()[..], &lengths, LoadOptions::default(), |_| count += 1, |_| ()).unwrap();
+        assert_eq!(count, 4);
+        assert_eq!(find_strokes("TEFT", &mut container), vec!["test"]);
+
+        // in strict mode, they stop the dictionary from loading
+        let mut count = 0;
+        let result = load_dictionary_stack_with_report::<Container, _, _>(&mut buffer[..], &lengths, STRICT, |_| count += 1, |_| ());
+        assert!(result.is_err());
+        assert_eq!(count, 4);
+    }
+
+    #[test]
+    fn test_strict_loading() {
+        let dictionary = br#"{"TEFT": "test", "T-P": "tip", "TKPWG": "dog", "KA-T": "cat", "1-9": "19"}"#;
+
+        let mut diagnostics = Vec::new();
+        validate_dictionary_stack_internal(&mut dictionary.to_vec()[..], &[dictionary.len()], STRICT, |diagnostic| {
+            diagnostics.push((diagnostic.entry, diagnostic.offset, diagnostic.problem));
+        }).unwrap();
+
+        assert_eq!(diagnostics, vec![
+            (2, 4, Problem::MissingHyphen),
+            (3, 2, Problem::MisplacedHyphen),
+        ]);
+
+        let mut dictionary = br#"{"TEFT": "test", "T-P": "tip", "TP": "if"}"#.to_vec();
+        let length = dictionary.len();
+        let mut container = load_dictionary_stack_with_report::<Container, _, _>(&mut dictionary[..], &[length], STRICT, |_| panic!(), |_| panic!()).unwrap();
+        assert_eq!(find_strokes("T-P", &mut container), vec!["tip"]);
+        assert_eq!(find_strokes("TP", &mut container), vec!["if"]);
+    }
+
+    #[test]
+    fn test_duplicate_strokes() {
+        let user = br##"{"50": "fifty", "TEFT": "test", "#AO": "number", "KA-T": "cat", "KAT": "kat", "TEFT": "test 2"}"##;
+        let main = br#"{"TEFT": "main test"}"#;
+
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(user);
+        buffer.extend_from_slice(main);
+        let lengths = [user.len(), main.len()];
+
+        let mut collisions = Vec::new();
+        let mut container = load_dictionary_stack_with_report::<Container, _, _>(&mut buffer.clone()[..], &lengths, LoadOptions::default(), |_| (), |collision| {
+            collisions.push((collision.dictionary, collision.entry,
+                             String::from_utf8(collision.strokes.to_vec()).unwrap(),
+                             String::from_utf8(collision.translation.to_vec()).unwrap(),
+                             String::from_utf8(collision.previous_translation.to_vec()).unwrap()));
+        }).unwrap();
+
+        // overrides between dictionaries of the stack aren't collisions
+        assert_eq!(collisions, vec![
+            (0, 2, "#AO".to_string(), "number".to_string(), "fifty".to_string()),
+            (0, 4, "KAT".to_string(), "kat".to_string(), "cat".to_string()),
+            (0, 5, "TEFT".to_string(), "test 2".to_string(), "test".to_string()),
+        ]);
+
+        assert_eq!(find_strokes("50", &mut container), vec!["number"]);
+        assert_eq!(find_strokes("KAT", &mut container), vec!["kat"]);
+        assert_eq!(find_strokes("TEFT", &mut container), vec!["test 2"]);
+
+        let mut container = load_dictionary_stack_with_report::<Container, _, _>(&mut buffer.clone()[..], &lengths, LoadOptions { duplicates: DuplicatePolicy::FirstWins, ..LoadOptions::default() }, |_| (), |_| ()).unwrap();
+        assert_eq!(find_strokes("50", &mut container), vec!["fifty"]);
+        assert_eq!(find_strokes("KAT", &mut container), vec!["cat"]);
+        assert_eq!(find_strokes("TEFT", &mut container), vec!["test"]);
+
+        let mut container = load_dictionary_stack_with_report::<Container, _, _>(&mut buffer[..], &lengths, LoadOptions { duplicates: DuplicatePolicy::KeepAll, ..LoadOptions::default() }, |_| (), |_| ()).unwrap();
+        assert_eq!(find_strokes("50", &mut container), vec!["fifty", "number"]);
+        assert_eq!(find_strokes("TEFT", &mut container), vec!["test", "test 2"]);
+        assert_eq!(lookup("main test", &mut container), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_steno_system() {
+        let mut description = br##"{"keys": "# S- T- A- * -E -T -S", "implicit_hyphen_keys": "A- * -E", "number_key": "#", "numbers": "S-:1 -T:9"}"##.to_vec();
+        let system = StenoSystem::from_json(&mut description[..]).unwrap();
+        let options = LoadOptions { system: &system, ..STRICT };
+
+        let mut dictionary = br#"{"STAT": "stat", "T-T": "tot", "1-9": "19"}"#.to_vec();
+        let length = dictionary.len();
+        let mut container = load_dictionary_stack_with_report::<Container, _, _>(&mut dictionary[..], &[length], options, |_| panic!(), |_| panic!()).unwrap();
+
+        let mut find = |strokes: &[u8]| {
+            let parsed_strokes: Vec<u8> = ParseStrokesIterator::new(&system, strokes).collect();
+            let mut results = Vec::new();
+            find_strokes_internal(&parsed_strokes, &mut container, |result| {
+                results.push(String::from_utf8(result.translation.to_vec()).unwrap());
+            }).unwrap();
+            results
+        };
+        assert_eq!(find(b"STAT"), vec!["stat"]);
+        assert_eq!(find(b"TT"), vec!["tot"]);
+        assert_eq!(find(b"#S-T"), vec!["19"]);
+
+        // K isn't a key in this system
+        let mut dictionary = br#"{"KAT": "cat"}"#.to_vec();
+        let mut problems = Vec::new();
+        validate_dictionary_stack_internal(&mut dictionary[..], &[14], options, |diagnostic| {
+            problems.push(diagnostic.problem);
+        }).unwrap();
+        assert_eq!(problems, vec![Problem::UnknownKey]);
+    }
+
+    #[test]
+    fn test_extended_stenotype() {
+        let options = LoadOptions { system: &EXTENDED_STENOTYPE, ..LoadOptions::default() };
+        let mut dictionary = br##"{"TEFT": "test", "^TEFT": "caret", "+TEFT": "plus", "TEFT#": "hash", "*": "star", "-*": "right star", "**": "stars"}"##.to_vec();
+        let length = dictionary.len();
+        let mut collisions = 0;
+        let mut container = load_dictionary_stack_with_report::<Container, _, _>(&mut dictionary[..], &[length], options, |_| (), |_| collisions += 1).unwrap();
+        assert_eq!(collisions, 0);
+
+        let parse = |strokes: &[u8]| -> Vec<u8> {
+            ParseStrokesIterator::new(&EXTENDED_STENOTYPE, strokes).collect()
+        };
+        assert_eq!(parse(b"^TEFT/TEFT#").len(), 8);
+
+        for (strokes, translation) in [(b"TEFT".as_ref(), "test"), (b"^TEFT", "caret"), (b"+TEFT", "plus"), (b"TEFT#", "hash"),
+                                       (b"*", "star"), (b"-*", "right star"), (b"**", "stars")] {
+            let mut results = Vec::new();
+            find_strokes_internal(&parse(strokes), &mut container, |result| {
+                results.push(String::from_utf8(result.translation.to_vec()).unwrap());
+            }).unwrap();
+            assert_eq!(results, vec![translation]);
+
+            let mut results = Vec::new();
+            query_internal(translation.as_bytes(), &mut container, |result| {
+                results.push(result.strokes.to_vec());
+            }).unwrap();
+            assert_eq!(results, vec![parse(strokes)]);
+        }
+    }
+
+    #[test]
+    fn test_palantype() {
+        let options = LoadOptions { system: &PALANTYPE, ..STRICT };
+        let mut dictionary = br#"{"PAL": "pal", "N": "in", "-N": "{^n}", "TH+EN/-S": "then is", "CRAUL": "crawl"}"#.to_vec();
+        let length = dictionary.len();
+        let mut container = load_dictionary_stack_with_report::<Container, _, _>(&mut dictionary[..], &[length], options, |_| panic!(), |_| panic!()).unwrap();
+
+        let parse = |strokes: &[u8]| -> Vec<u8> {
+            ParseStrokesIterator::new(&PALANTYPE, strokes).collect()
+        };
+
+        for (strokes, translation) in [(b"PAL".as_ref(), "pal"), (b"N", "in"), (b"-N", "{^n}"), (b"TH+EN/-S", "then is"), (b"CRAUL", "crawl")] {
+            let mut results = Vec::new();
+            find_strokes_internal(&parse(strokes), &mut container, |result| {
+                results.push(String::from_utf8(result.translation.to_vec()).unwrap());
+            }).unwrap();
+            assert_eq!(results, vec![translation]);
+
+            let mut results = Vec::new();
+            query_internal(translation.as_bytes(), &mut container, |result| {
+                results.push(result.strokes.to_vec());
+            }).unwrap();
+            assert_eq!(results, vec![parse(strokes)]);
+        }
+
+        // english strokes mostly aren't palantype
+        let mut problems = Vec::new();
+        validate_dictionary_stack_internal(&mut br#"{"KAT": "cat"}"#.to_vec()[..], &[14], options, |diagnostic| {
+            problems.push((diagnostic.offset, diagnostic.problem));
+        }).unwrap();
+        assert_eq!(problems, vec![(0, Problem::UnknownKey)]);
+    }
+
+    #[test]
+    fn test_non_ascii_keys() {
+        let mut description = r#"{"keys": "ㄱ- ㄴ- ㅏ- ㅗ- -ㄱ -ㄴ", "implicit_hyphen_keys": "ㅏ- ㅗ-"}"#.as_bytes().to_vec();
+        let system = StenoSystem::from_json(&mut description[..]).unwrap();
+        let options = LoadOptions { system: &system, ..LoadOptions::default() };
+
+        let mut dictionary = r#"{"ㄱㅏ": "가", "ㄱㅏㄱ": "각", "ㄴㅗ/ㄱ": "노가"}"#.as_bytes().to_vec();
+        let length = dictionary.len();
+        let mut container = load_dictionary_stack_with_report::<Container, _, _>(&mut dictionary[..], &[length], options, |_| panic!(), |_| panic!()).unwrap();
+
+        let parse = |strokes: &str| -> Vec<u8> {
+            ParseStrokesIterator::new(&system, strokes.as_bytes()).collect()
+        };
+
+        for (strokes, translation) in [("ㄱㅏ", "가"), ("ㄱㅏㄱ", "각"), ("ㄴㅗ/ㄱ", "노가")] {
+            let mut results = Vec::new();
+            find_strokes_internal(&parse(strokes), &mut container, |result| {
+                results.push(String::from_utf8(result.translation.to_vec()).unwrap());
+            }).unwrap();
+            assert_eq!(results, vec![translation]);
+
+            let mut results = Vec::new();
+            query_internal(translation.as_bytes(), &mut container, |result| {
+                results.push(result.strokes.to_vec());
+            }).unwrap();
+            assert_eq!(results, vec![parse(strokes)]);
+        }
+
+        // offsets are in bytes
+        let mut dictionary = r#"{"ㄱㅏㄷ": "x", "ㅏㄱㄱ": "y"}"#.as_bytes().to_vec();
+        let length = dictionary.len();
+        let mut problems = Vec::new();
+        validate_dictionary_stack_internal(&mut dictionary[..], &[length], options, |diagnostic| {
+            problems.push((diagnostic.offset, diagnostic.problem));
+        }).unwrap();
+        assert_eq!(problems, vec![(6, Problem::UnknownKey), (6, Problem::OutOfOrder)]);
+    }
+
+    #[test]
+    fn test_stroke_widths() {
+        let dictionary = br#"{"TEFT": "test", "TEFT/-G": "testing", "S": "is", "-Z": "{^s}"}"#;
+
+        for width in [StrokeWidth::ThreeBytes, StrokeWidth::FourBytes, StrokeWidth::Varint] {
+            let system = ENGLISH_STENOTYPE.with_stroke_width(width).unwrap();
+            let options = LoadOptions { system: &system, ..LoadOptions::default() };
+            let mut buffer = dictionary.to_vec();
+            let length = buffer.len();
+            let mut container = load_dictionary_stack_with_report::<Container, _, _>(&mut buffer[..], &[length], options, |_| (), |_| ()).unwrap();
+            assert_eq!(get_stroke_width(&container).unwrap(), width);
+
+            for (strokes, translation) in [(b"TEFT".as_ref(), "test"), (b"TEFT/-G", "testing"), (b"S", "is"), (b"-Z", "{^s}")] {
+                let parsed_strokes: Vec<u8> = ParseStrokesIterator::new(&system, strokes).collect();
+                let mut results = Vec::new();
+                find_strokes_internal(&parsed_strokes, &mut container, |result| {
+                    results.push(String::from_utf8(result.translation.to_vec()).unwrap());
+                }).unwrap();
+                assert_eq!(results, vec![translation]);
+
+                let mut results = Vec::new();
+                query_internal(translation.as_bytes(), &mut container, |result| {
+                    let mut pos = 0;
+                    let mut strokes = Vec::new();
+                    while pos < result.strokes.len() {
+                        strokes.push(width.unpack(result.strokes, &mut pos).unwrap());
+                    }
+                    results.push(strokes);
+                }).unwrap();
+
+                let mut pos = 0;
+                let mut expected = Vec::new();
+                while pos < strokes.len() {
+                    expected.push(system.parse_stroke(strokes, &mut pos));
+                }
+                assert_eq!(results, vec![expected]);
+            }
+        }
+
+        // "S" is 1 << 1, "-Z" is 1 << 22
+        let system = ENGLISH_STENOTYPE.with_stroke_width(StrokeWidth::Varint).unwrap();
+        assert_eq!(ParseStrokesIterator::new(&system, b"S").count(), 1);
+        assert_eq!(ParseStrokesIterator::new(&system, b"-Z").count(), 4);
+    }
+
+    #[test]
+    fn test_long_entries() {
+        // longer than the old limits of 64 KiB per entry and 1000 strokes
+        let long_translation = "long ".repeat(20000);
+        let many_strokes = vec!["TEFT"; 1500].join("/");
+        let json_dict = format!("{{\"TEFT\":\"{}\",\"{}\":\"many\",\"TEFTS\":\"tests\"}}", long_translation, many_strokes);
+
+        let mut buffer = json_dict.clone().into_bytes();
+        let mut container = load_json_internal::<Container>(&mut buffer[..]).unwrap();
+        assert_eq!(find_strokes("TEFT", &mut container), vec![long_translation.clone()]);
+        assert_eq!(find_strokes(&many_strokes, &mut container), vec!["many"]);
+        assert_eq!(find_strokes("TEFTS", &mut container), vec!["tests"]);
+
+        let mut loader = StreamingLoader::new(Vec::new(), LoadOptions::default());
+        loader.load_chunk(json_dict.as_bytes()).unwrap();
+        let mut container = loader.finish::<Container, _, _>(|_| (), |_| ()).unwrap();
+        assert_eq!(lookup(&long_translation, &mut container), vec!["TEFT"]);
+        assert_eq!(find_strokes(&many_strokes, &mut container), vec!["many"]);
+
+        let mut rtf_dict = format!("{{\\rtf1\\ansi{{\\*\\cxs TEFT}}{}{{\\*\\cxs {}}}many}}", long_translation, many_strokes).into_bytes();
+        let mut container = load_rtf_internal::<Container>(&mut rtf_dict[..], &LoadOptions::default()).unwrap();
+        assert_eq!(find_strokes("TEFT", &mut container), vec![long_translation]);
+        assert_eq!(find_strokes(&many_strokes, &mut container), vec!["many"]);
+    }
+
+    #[test]
+    fn test_streaming_loader() {
+        let json_dict = "{\"TEFT\": \"t\\u00e9st\", \"TEFT/-G\": \"testing \\ud83d\\ude00\",\n \"TEFTS\": \"tösts\"}";
+
+        // split the data at every possible chunk size, so that chunk
+        // boundaries end up everywhere, including inside of escapes
+        for chunk_size in 1..json_dict.len() {
+            let mut loader = StreamingLoader::new(Vec::new(), LoadOptions::default());
+            for chunk in json_dict.as_bytes().chunks(chunk_size) {
+                loader.load_chunk(chunk).unwrap();
+            }
+            let mut container = loader.finish::<Container, _, _>(|_| (), |_| ()).unwrap();
+
+            assert_eq!(lookup("tést", &mut container), vec!["TEFT"]);
+            assert_eq!(lookup("testing \u{1F600}", &mut container), vec!["TEFT/-G"]);
+            assert_eq!(find_strokes("TEFTS", &mut container), vec!["tösts"]);
+        }
+
+        let mut loader = StreamingLoader::new(Vec::new(), LoadOptions::default());
+        loader.load_chunk(b"{\"TEFT\": \"test\",\n").unwrap();
+        let error = loader.load_chunk(b" \"TEFTS\" \"tests\"}").err().unwrap();
+        let position = error.position.unwrap();
+        assert_eq!((position.offset, position.line, position.column), (26, 2, 10));
+        assert_eq!(position.excerpt(), b"\"tests\"}");
+
+        let mut loader = StreamingLoader::new(Vec::new(), LoadOptions::default());
+        loader.load_chunk(b"{\"TEFT\": \"te").unwrap();
+        assert!(loader.finish::<Container, _, _>(|_| (), |_| ()).is_err());
+
+        // the strokes get checked as well, so strict mode works without
+        // anything else to call
+        let dictionary = br#"{"TEFT": "test", "WKPT": "oops"}"#;
+        let mut loader = StreamingLoader::new(Vec::new(), STRICT);
+        loader.load_chunk(dictionary).unwrap();
+        let mut problems = 0;
+        assert!(loader.finish::<Container, _, _>(|_| problems += 1, |_| ()).is_err());
+        assert!(problems > 0);
+
+        let mut loader = StreamingLoader::new(Vec::new(), LoadOptions::default());
+        loader.load_chunk(dictionary).unwrap();
+        assert!(loader.finish::<Container, _, _>(|_| (), |_| ()).is_ok());
+    }
+
+    #[test]
+    fn test_json_quirks() {
+        let tolerant = LoadOptions { tolerant: true, ..LoadOptions::default() };
+        let load = |json: &[u8], options: LoadOptions| {
+            let mut buffer = json.to_vec();
+            let length = buffer.len();
+            load_dictionary_stack_with_report::<Container, _, _>(&mut buffer[..], &[length], options, |_| (), |_| ())
+        };
+
+        // empty dictionaries and byte order marks are fine in any case
+        assert!(load(b"{}", LoadOptions::default()).is_ok());
+        assert!(load(b" {\n} ", LoadOptions::default()).is_ok());
+        let mut container = load(b"\xEF\xBB\xBF{\"TEFT\": \"test\"}", LoadOptions::default()).unwrap();
+        assert_eq!(find_strokes("TEFT", &mut container), vec!["test"]);
+        let mut container = load(b"\xEF\xBB\xBF{\\rtf1\\ansi{\\*\\cxs TEFT}test}", LoadOptions::default()).unwrap();
+        assert_eq!(find_strokes("TEFT", &mut container), vec!["test"]);
+
+        // trailing commas and comments only in tolerant mode
+        let quirky = b"// my dictionary\n{\n  \"TEFT\": \"test\", // a comment\n  \"TEFTS\": \"tests\",\n}\n";
+        assert!(load(quirky, LoadOptions::default()).is_err());
+        let mut container = load(quirky, tolerant).unwrap();
+        assert_eq!(find_strokes("TEFT", &mut container), vec!["test"]);
+        assert_eq!(find_strokes("TEFTS", &mut container), vec!["tests"]);
+
+        // comments in strings are just part of the string
+        let mut container = load(b"{\"TEFT\": \"//test\"}", tolerant).unwrap();
+        assert_eq!(find_strokes("TEFT", &mut container), vec!["//test"]);
+
+        let mut loader = StreamingLoader::new(Vec::new(), tolerant);
+        for chunk in quirky.chunks(3) {
+            loader.load_chunk(chunk).unwrap();
+        }
+        let mut container = loader.finish::<Container, _, _>(|_| (), |_| ()).unwrap();
+        assert_eq!(find_strokes("TEFTS", &mut container), vec!["tests"]);
+
+        // everything but strings is rejected, even in tolerant mode
+        let error = load(b"{\"TEFT\": 5}", tolerant).err().unwrap();
+        assert_eq!(error.details, b"Parser error: translations have to be strings, but this one is a number");
+        let error = load(b"{\"TEFT\": null}", tolerant).err().unwrap();
+        assert_eq!(error.details, b"Parser error: translations have to be strings, but this one is null");
+        let error = load(b"{\"TEFT\": {\"a\": \"b\"}}", tolerant).err().unwrap();
+        assert_eq!(error.details, b"Parser error: translations have to be strings, but this one is a nested object or array");
+        assert!(load(b"{,}", tolerant).is_err());
+        assert!(load(b"{\"TEFT\": \"test\" /* comment */}", tolerant).is_err());
+    }
+
+    #[test]
+    fn test_error_position() {
+        let mut json_dict = b"{\n  \"TEFT\": \"test\",\n  \"TEFTS\" \"tests\"\n}".to_vec();
+        let error = load_json_internal::<Container>(&mut json_dict[..]).err().unwrap();
+        let position = error.position.unwrap();
+
+        assert_eq!(error.details, b"Parser error: expected ':'");
+        assert_eq!(position.offset, 30);
+        assert_eq!(position.line, 3);
+        assert_eq!(position.column, 11);
+        assert_eq!(position.excerpt(), b"\"tests\"");
+
+        let mut unterminated = "{\"TEFT\": \"täst".as_bytes().to_vec();
+        let error = load_json_internal::<Container>(&mut unterminated[..]).err().unwrap();
+        let position = error.position.unwrap();
+        assert_eq!(position.offset, 15);
+        assert_eq!((position.line, position.column), (1, 16));
+        assert_eq!(position.excerpt(), b"");
+    }
+
+    #[test]
+    fn test_prefix_search() {
+        let user = br#"{"EUPL/PHREUS/-T": "implicitly", "KAT": "kitten"}"#;
+        let main = br#"{"EUPL/PHREUS": "implicit", "KAT": "cat", "KAT/-S": "cats", "EUPL/PHREU": "implicit", "KA": "ca", "TEFT": "test"}"#;
+
+        let mut container = load_stack(&[user, main]);
+
+        let search = |prefix: &str, skip: usize, limit: usize, container: &mut Container| {
+            collect(|found| prefix_search_internal(prefix.as_bytes(), container, skip, limit, found))
+        };
+
+        assert_eq!(search("implic", 0, 10, &mut container), (3, vec![
+            found("EUPL/PHREUS", "implicit", 1, false),
+            found("EUPL/PHREU", "implicit", 1, false),
+            found("EUPL/PHREUS/-T", "implicitly", 0, false),
+        ]));
+
+        // "KAT" is shadowed by the user dictionary, so "cat" doesn't show up
+        assert_eq!(search("ca", 0, 10, &mut container), (2, vec![
+            found("KA", "ca", 1, true),
+            found("KAT/-S", "cats", 1, false),
+        ]));
+
+        // paging
+        assert_eq!(search("implicit", 1, 1, &mut container), (3, vec![found("EUPL/PHREU", "implicit", 1, true)]));
+        assert_eq!(search("implicit", 3, 10, &mut container), (3, vec![]));
+        assert_eq!(search("", 0, 0, &mut container).0, 7);
+        assert_eq!(search("x", 0, 10, &mut container), (0, vec![]));
+        assert_eq!(search("tests", 0, 10, &mut container), (0, vec![]));
+    }
+
+    #[test]
+    fn test_folded_query() {
+        let user = br#"{"PHOPB": "Monday", "KAUF": "caf\u00e9"}"#;
+        let main = br#"{"PHOPBD": "monday", "PHOPB/TKAEU": "MONDAY", "KAEF": "cafe\u0301", "KAF": "CAF\u00c9", "STRAS": "Stra\u00dfe", "PHOPB": "monday"}"#;
+
+        let mut container = load_stack(&[user, main]);
+
+        let search = |query: &str, container: &mut Container| {
+            let ((), mut results) = collect(|found| folded_query_internal(query.as_bytes(), container, found));
+            results.sort();
+            results
+        };
+
+        // the main dictionary's "PHOPB" is shadowed by the user's
+        let monday = vec![
+            found("PHOPB", "Monday", 0, false),
+            found("PHOPB/TKAEU", "MONDAY", 1, false),
+            found("PHOPBD", "monday", 1, true),
+        ];
+        assert_eq!(search("monday", &mut container), monday);
+        assert_eq!(search("MonDay", &mut container).len(), 3);
+
+        assert_eq!(search("caf\u{e9}", &mut container), vec![
+            found("KAEF", "cafe\u{301}", 1, false),
+            found("KAF", "CAF\u{c9}", 1, false),
+            found("KAUF", "caf\u{e9}", 0, true),
+        ]);
+        assert_eq!(search("cafe\u{301}", &mut container).len(), 3);
+        assert_eq!(search("strasse", &mut container), vec![found("STRAS", "Stra\u{df}e", 1, false)]);
+        assert_eq!(search("tuesday", &mut container), vec![]);
+
+        // the normal lookup stays exact
+        assert_eq!(lookup("monday", &mut container), vec!["PHOPBD"]);
+    }
+
+    #[test]
+    fn test_visible_text_query() {
+        let mut json_dict = br#"{
+            "-G": "{^ing}",
+            "-PBLG": "{^ing}{-|}",
+            "TPH-G": "ing",
+            "KW-BG": "{,}",
+            "A*": "{&a}",
+            "R*E": "{re^}",
+            "KPA": "{-|}",
+            "TEFT": "test"
+        }"#.to_vec();
+        let mut container = load_json_internal::<Container>(&mut json_dict[..]).unwrap();
+
+        let search = |query: &str, container: &mut Container| {
+            let ((), mut results) = collect(|found| visible_text_query_internal(query.as_bytes(), container, found));
+            results.sort();
+            results.into_iter().map(|found| {
+                let attachment = attachment(found.translation.as_bytes());
+                (found, attachment)
+            }).collect::<Vec<_>>()
+        };
+
+        assert_eq!(search("ing", &mut container), vec![
+            (found("-G", "{^ing}", 0, false), Attachment::Suffix),
+            (found("-PBLG", "{^ing}{-|}", 0, false), Attachment::Suffix),
+            (found("TPH-G", "ing", 0, true), Attachment::None),
+        ]);
+        assert_eq!(search(",", &mut container), vec![(found("KW-BG", "{,}", 0, false), Attachment::Punctuation)]);
+        assert_eq!(search("a", &mut container), vec![(found("A*", "{&a}", 0, false), Attachment::Glue)]);
+        assert_eq!(search("re", &mut container), vec![(found("R*E", "{re^}", 0, false), Attachment::Prefix)]);
+        assert_eq!(search("{^ing}", &mut container), vec![(found("-G", "{^ing}", 0, true), Attachment::Suffix)]);
+        assert_eq!(search("test", &mut container), vec![(found("TEFT", "test", 0, true), Attachment::None)]);
+        assert_eq!(search("", &mut container), vec![]);
+    }
+
+    #[test]
+    fn test_fuzzy_query() {
+        let user = br#"{"RAOEF": "receipt", "TKEFT": "DETEST"}"#;
+        let main = br#"{
+            "RE/SAOEF": "receive",
+            "RE/SAOEFD": "received",
+            "RAOEF": "receive",
+            "TKEFT": "detest",
+            "TKEF/TPHEUT/HREU": "definitely",
+            "TEFT": "test",
+            "TEFTS": "tests",
+            "TE": "the",
+            "TO": "to",
+            "A*EUB": "abcdefghijklmnopqrstuvwxyz012345",
+            "HRAOPBG": "this translation is much too long to be found fuzzily"
+        }"#;
+        let mut container = load_stack(&[user, main]);
+
+        let search = |query: &str, max_distance: usize, limit: usize, container: &mut Container| {
+            let mut distances = Vec::new();
+            let ((), results) = collect(|found| fuzzy_query_internal(query.as_bytes(), container, max_distance, limit, |result, distance| {
+                distances.push(distance);
+                found(result);
+            }));
+            results.into_iter().zip(distances).collect::<Vec<_>>()
+        };
+
+        // the main dictionary's "RAOEF" is shadowed by the user's
+        assert_eq!(search("recieve", 2, 10, &mut container), vec![
+            (found("RE/SAOEF", "receive", 1, false), 1),
+            (found("RE/SAOEFD", "received", 1, false), 2),
+        ]);
+        assert_eq!(search("recieve", 1, 10, &mut container).len(), 1);
+        assert_eq!(search("recieve", 2, 1, &mut container), vec![(found("RE/SAOEF", "receive", 1, false), 1)]);
+        assert_eq!(search("definately", 2, 10, &mut container), vec![(found("TKEF/TPHEUT/HREU", "definitely", 1, false), 1)]);
+
+        // "detest" only has a shadowed entry
+        assert_eq!(search("detest", 1, 10, &mut container), vec![]);
+
+        // closer matches come first, shorter ones first if they're as close
+        assert_eq!(search("test", 1, 10, &mut container), vec![
+            (found("TEFT", "test", 1, true), 0),
+            (found("TEFTS", "tests", 1, false), 1),
+        ]);
+        assert_eq!(search("tset", 2, 10, &mut container), vec![
+            (found("TEFT", "test", 1, false), 1),
+            (found("TE", "the", 1, false), 2),
+            (found("TEFTS", "tests", 1, false), 2),
+        ]);
+        assert_eq!(search("tset", 2, 2, &mut container), vec![(found("TEFT", "test", 1, false), 1), (found("TE", "the", 1, false), 2)]);
+        assert_eq!(search("test", 0, 10, &mut container), vec![(found("TEFT", "test", 1, true), 0)]);
+        assert_eq!(search("test", 1, 0, &mut container), vec![]);
+
+        // everything up to max_distance characters long is close to nothing
+        assert_eq!(search("", 2, 10, &mut container), vec![(found("TO", "to", 1, false), 2)]);
+        assert_eq!(search("", 1, 10, &mut container), vec![]);
+
+        // as long as it gets
+        assert_eq!(search("abcdefghijklmnopqrstuvwxyz01234", 1, 10, &mut container), vec![(found("A*EUB", "abcdefghijklmnopqrstuvwxyz012345", 1, false), 1)]);
+        assert_eq!(search("this translation is much too long to be found fuzzily", 2, 10, &mut container), vec![]);
+        assert_eq!(search("xyz", 1, 10, &mut container), vec![]);
+
+        let usage = get_memory_usage(&mut container).unwrap();
+        // one slot for each short translation, except "detest"
+        assert_eq!(usage.length_index, 10 * core::mem::size_of::<usize>());
+        assert!(usage.strokes_table > 0 && usage.strings_table > 0);
+        assert!(usage.total >= usage.strokes_table + usage.strings_table + usage.translation_index + usage.length_index);
+    }
+
+    #[test]
+    fn test_ranked_query() {
+        let user = br#"{"TH*E": "the", "-T": "the"}"#;
+        let main = br#"{"TH/E": "the", "THE": "the", "-T": "the", "T*": "the", "THAOE": "thee", "TEFT": "test"}"#;
+        let mut container = load_stack(&[user, main]);
+
+        let search = |query: &str, skip: usize, limit: usize, container: &mut Container| {
+            collect(|found| ranked_query_internal(query.as_bytes(), container, &ENGLISH_STENOTYPE, skip, limit, found))
+        };
+
+        // fewest strokes, then fewest keys. the main dictionary's "-T" is
+        // shadowed by the user's
+        assert_eq!(search("the", 0, 10, &mut container), (5, vec![
+            found("-T", "the", 0, true),
+            found("T*", "the", 1, true),
+            found("THE", "the", 1, true),
+            found("TH*E", "the", 0, true),
+            found("TH/E", "the", 1, true),
+        ]));
+        assert_eq!(search("the", 2, 2, &mut container), (5, vec![found("THE", "the", 1, true), found("TH*E", "the", 0, true)]));
+        assert_eq!(search("the", 5, 2, &mut container), (5, vec![]));
+        assert_eq!(search("the", 7, usize::MAX, &mut container), (5, vec![]));
+        assert_eq!(search("them", 0, 10, &mut container), (0, vec![]));
+        assert_eq!(search("", 0, 10, &mut container), (0, vec![]));
+
+        let find = |strokes: &str, skip: usize, limit: usize, container: &mut Container| {
+            collect(|found| ranked_find_strokes_internal(&pack(strokes), container, &ENGLISH_STENOTYPE, skip, limit, found))
+        };
+
+        assert_eq!(find("-T", 0, 10, &mut container), (1, vec![found("-T", "the", 0, true)]));
+        assert_eq!(find("THAOE", 0, 10, &mut container), (1, vec![found("THAOE", "thee", 1, true)]));
+        assert_eq!(find("THAOE", 1, 10, &mut container), (1, vec![]));
+
+        // the system has to be the one the dictionary was loaded with
+        assert!(ranked_query_internal(b"the", &mut container, &PALANTYPE, 0, 10, |_| ()).is_err());
+        assert!(ranked_find_strokes_internal(&pack("-T"), &mut container, &EXTENDED_STENOTYPE, 0, 10, |_| ()).is_err());
+        let varint = ENGLISH_STENOTYPE.with_stroke_width(StrokeWidth::Varint).unwrap();
+        assert_eq!(ranked_query_internal(b"the", &mut container, &varint, 0, 10, |_| ()).unwrap(), 5);
+        // as many keys isn't enough, they have to be the same ones
+        let mut description = br##"{
+            "keys": "# S- T- K- P- W- H- R- A- O- * -E -U -F -R -P -B -L -G -T -S -D -X",
+            "implicit_hyphen_keys": "A- O- * -E -U",
+            "number_key": "#"
+        }"##.to_vec();
+        let other = StenoSystem::from_json(&mut description[..]).unwrap();
+        assert_eq!(other.num_keys(), ENGLISH_STENOTYPE.num_keys());
+        assert!(ranked_query_internal(b"the", &mut container, &other, 0, 10, |_| ()).is_err());
+    }
+
+    #[test]
+    fn test_outline_prefix_search() {
+        let user = br#"{"KAUPB/TKPWREU": "Congress"}"#;
+        let main = br#"{
+            "KAUPB/SAOEUPB": "consign",
+            "KAUPB/TPHREUBGT/-D": "conflicted",
+            "KAUPB": "con",
+            "KAUPB/TPHREUBGT": "conflict",
+            "KAUPBS": "cons",
+            "KAUPB/TKPWREU": "congress",
+            "TEFT": "test"
+        }"#;
+        let mut container = load_stack(&[user, main]);
+
+        let search = |prefix: &str, skip: usize, limit: usize, container: &mut Container| {
+            collect(|found| outline_prefix_search_internal(&pack(prefix), container, skip, limit, found))
+        };
+
+        // shortest first, then by the values of the strokes. the main
+        // dictionary's "KAUPB/TKPWREU" is shadowed by the user's
+        assert_eq!(search("KAUPB", 0, 10, &mut container), (5, vec![
+            found("KAUPB", "con", 1, true),
+            found("KAUPB/TKPWREU", "Congress", 0, false),
+            found("KAUPB/SAOEUPB", "consign", 1, false),
+            found("KAUPB/TPHREUBGT", "conflict", 1, false),
+            found("KAUPB/TPHREUBGT/-D", "conflicted", 1, false),
+        ]));
+        assert_eq!(search("KAUPB", 1, 2, &mut container), (5, vec![
+            found("KAUPB/TKPWREU", "Congress", 0, false),
+            found("KAUPB/SAOEUPB", "consign", 1, false),
+        ]));
+        assert_eq!(search("KAUPB/TPHREUBGT", 0, 10, &mut container), (2, vec![
+            found("KAUPB/TPHREUBGT", "conflict", 1, true),
+            found("KAUPB/TPHREUBGT/-D", "conflicted", 1, false),
+        ]));
+        assert_eq!(search("KAUPB/TPH", 0, 10, &mut container), (0, vec![]));
+        assert_eq!(search("KAUPBS", 0, 10, &mut container), (1, vec![found("KAUPBS", "cons", 1, true)]));
+        assert_eq!(search("PWAOEU", 0, 10, &mut container), (0, vec![]));
+
+        // without a prefix, everything matches, still shortest first
+        let (total, results) = search("", 0, 10, &mut container);
+        assert_eq!(total, 7);
+        let lengths: Vec<usize> = results.iter().map(|found| found.strokes.split('/').count()).collect();
+        assert_eq!(lengths, vec![1, 1, 1, 2, 2, 2, 3]);
+        assert_eq!(search("", 2, 2, &mut container).1, results[2..4].to_vec());
+
+        // pages past the end are empty
+        assert_eq!(search("KAUPB", 5, 10, &mut container), (5, vec![]));
+        assert_eq!(search("KAUPB", 7, usize::MAX, &mut container), (5, vec![]));
+        assert_eq!(search("KAUPB", 4, usize::MAX, &mut container), (5, vec![found("KAUPB/TPHREUBGT/-D", "conflicted", 1, false)]));
+
+        let usage = get_memory_usage(&mut container).unwrap();
+        assert_eq!(usage.outline_index, 7 * core::mem::size_of::<usize>());
+    }
+
+    #[test]
+    fn test_pattern_search() {
+        let user = br#"{"KAT": "cat"}"#;
+        let main = br#"{"KAT": "kat", "KAUT": "caught", "SKAT": "scat", "KA": "can", "KAT/-S": "cats", "PHA*FRPBLG": "marriage", "TEFT/-FRPBLGS": "test changes"}"#;
+        let mut container = load_stack(&[user, main]);
+
+        let system = &ENGLISH_STENOTYPE;
+        let pattern = |required: &[u8], forbidden: &[u8]| StrokePattern {
+            required: system.parse_stroke(required, &mut 0),
+            forbidden: system.parse_stroke(forbidden, &mut 0)
+        };
+
+        let search = |patterns: &[StrokePattern], position: PatternPosition, container: &mut Container| {
+            let ((), results) = collect(|found| pattern_search_internal(patterns, position, container, found));
+            let mut translations: Vec<String> = results.into_iter().map(|found| found.translation).collect();
+            translations.sort();
+            translations
+        };
+
+        // exactly KA on the left bank, anything on the right (the main
+        // dictionary's "KAT" is shadowed by the user's)
+        let ka = [pattern(b"KA", b"#STPWHROE*U")];
+        assert_eq!(search(&ka, PatternPosition::Whole, &mut container), vec!["can", "cat"]);
+        assert_eq!(search(&ka, PatternPosition::At(0), &mut container), vec!["can", "cat", "cats"]);
+
+        let frpblg = [pattern(b"-FRPBLG", b"")];
+        assert_eq!(search(&frpblg, PatternPosition::Anywhere, &mut container), vec!["marriage", "test changes"]);
+        assert_eq!(search(&frpblg, PatternPosition::At(1), &mut container), vec!["test changes"]);
+
+        let k_then_s = [pattern(b"K", b""), pattern(b"-S", b"")];
+        assert_eq!(search(&k_then_s, PatternPosition::Anywhere, &mut container), vec!["cats"]);
+        assert_eq!(search(&[pattern(b"W", b"")], PatternPosition::Anywhere, &mut container), Vec::<String>::new());
+
+        // no patterns match every outline, but only the ones without
+        // strokes as a whole
+        assert_eq!(search(&[], PatternPosition::Anywhere, &mut container).len(), 7);
+        assert_eq!(search(&[], PatternPosition::Whole, &mut container), Vec::<String>::new());
+        // there's nothing past the end
+        assert_eq!(search(&ka, PatternPosition::At(5), &mut container), Vec::<String>::new());
+        assert_eq!(search(&[pattern(b"-S", b"")], PatternPosition::At(1), &mut container), vec!["cats", "test changes"]);
+    }
+
+    #[test]
+    fn test_substring_search() {
+        let long = format!("{}the end", "long ".repeat(100));
+        let user = br#"{"PHA*T/PHA*T": "matters, matters", "TKPWRAOEPB": "green"}"#;
+        let main = format!(r#"{{
+            "PHAT/ER": "as a matter of fact",
+            "PHAERT": "as a matter of fact",
+            "PHA*T": "matter",
+            "PHA*T/PHA*T": "matter, matter",
+            "KR-RT": "{{#Control_L(t)}}",
+            "TKPWRAOEPB": "gr\u00fcn",
+            "HROPBG": "{long}",
+            "TEFT": "test"
+        }}"#);
+        let mut container = load_stack(&[user, main.as_bytes()]);
+
+        let search = |query: &str, container: &mut Container| {
+            let mut spans = Vec::new();
+            let ((), results) = collect(|found| substring_search_internal(query.as_bytes(), container, |result, span| {
+                spans.push(span);
+                found(result);
+            }));
+            let mut results: Vec<_> = results.into_iter().zip(spans).collect();
+            results.sort_by(|a, b| a.0.cmp(&b.0));
+            results
+        };
+
+        assert_eq!(search("a matter", &mut container), vec![
+            (found("PHAERT", "as a matter of fact", 1, false), 3..11),
+            (found("PHAT/ER", "as a matter of fact", 1, false), 3..11),
+        ]);
+        // only the first place counts, and the main dictionary's
+        // "PHA*T/PHA*T" is shadowed by the user's
+        assert_eq!(search("matter", &mut container), vec![
+            (found("PHA*T", "matter", 1, true), 0..6),
+            (found("PHA*T/PHA*T", "matters, matters", 0, false), 0..6),
+            (found("PHAERT", "as a matter of fact", 1, false), 5..11),
+            (found("PHAT/ER", "as a matter of fact", 1, false), 5..11),
+        ]);
+        assert_eq!(search("matter, matter", &mut container), vec![]);
+        assert_eq!(search("{#Control", &mut container), vec![(found("KR-RT", "{#Control_L(t)}", 1, false), 0..9)]);
+        // "gr\u{fc}n" is shadowed by the user's "green"
+        assert_eq!(search("\u{fc}n", &mut container), vec![]);
+        assert_eq!(search("t", &mut container).len(), 7);
+        assert_eq!(search("fact", &mut container).len(), 2);
+        assert_eq!(search("facts", &mut container), vec![]);
+        assert_eq!(search("", &mut container), vec![]);
+
+        // long translations can be found everywhere (well past byte 256),
+        // with long queries too
+        assert_eq!(search("the end", &mut container), vec![(found("HROPBG", &long, 1, false), 500..507)]);
+        assert_eq!(search(&long[400..], &mut container), vec![(found("HROPBG", &long, 1, false), 400..507)]);
+        assert_eq!(search(&long, &mut container), vec![(found("HROPBG", &long, 1, true), 0..507)]);
+        assert_eq!(search("long the", &mut container), vec![(found("HROPBG", &long, 1, false), 495..503)]);
+
+        // a match doesn't go on into the data after a translation
+        assert_eq!(search("testx", &mut container), vec![]);
+
+        // one place for each character of each visible translation, and
+        // one entry for each of those translations
+        let usage = get_memory_usage(&mut container).unwrap();
+        let places = 16 + 5 + 19 + 6 + 15 + long.len() + 4;
+        let width = packed::width(container.usize_buffer[HEADER_STRINGS_DATA]);
+        assert_eq!(width, 2);
+        assert_eq!(usage.substring_index, places * width + 7 * core::mem::size_of::<usize>());
     }
 }
@@ -0,0 +1,242 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// plover's formatting syntax. translations can have commands in curly
+// braces, which say how the text attaches to the words around it
+// ("{^ing}", "{re^}", "{^-^}"), add punctuation ("{,}", "{.}"), glue
+// letters together ("{&a}"), change the case of the next word ("{-|}",
+// "{>}"), or do things that don't show up as text at all ("{#Return}",
+// "{PLOVER:TOGGLE}"). the visible text is what's left once all of that is
+// taken away, so that "{^ing}" can be found by searching for "ing".
+
+// punctuation that plover attaches to the word before it
+const PUNCTUATION: &[u8] = b",.?!:;";
+
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+#[repr(u8)]
+pub enum Attachment {
+    // plain text, or a translation without any visible text
+    None = 0,
+    // attached to the word before, like "{^ing}"
+    Suffix = 1,
+    // attached to the word after, like "{re^}"
+    Prefix = 2,
+    // attached on both sides, like "{^-^}"
+    Infix = 3,
+    // glued to other glued strokes, like "{&a}"
+    Glue = 4,
+    // punctuation, like "{,}" or "{?}"
+    Punctuation = 5
+}
+
+// what's between a pair of curly braces
+struct Command<'a> {
+    attach_before: bool,
+    attach_after: bool,
+    glue: bool,
+    // the part that shows up as text
+    text: &'a [u8]
+}
+
+impl<'a> Command<'a> {
+    // content is everything between the braces
+    fn parse(content: &'a [u8]) -> Command<'a> {
+        // "{^}" attaches on both sides
+        let attach_before = content.first() == Some(&b'^');
+        let attach_after = content.last() == Some(&b'^');
+
+        let start = attach_before as usize;
+        let end = (content.len() - attach_after as usize).max(start);
+        let mut text = &content[start..end];
+
+        // carrying capitalization, like "{~|'^}"
+        if text.starts_with(b"~|") {
+            text = &text[2..];
+        }
+
+        let glue = text.first() == Some(&b'&');
+        if glue {
+            text = &text[1..];
+        }
+
+        // case changes ("-|", ">", "<", "*-|", ...), key combinations
+        // ("#Return") and commands ("PLOVER:TOGGLE", ":retro_case")
+        // have no text of their own
+        let is_invisible = !glue && match text.first() {
+            Some(b'-') => text.get(1) == Some(&b'|'),
+            Some(b'>') | Some(b'<') | Some(b'*') | Some(b'#') => true,
+            _ => text.contains(&b':') && text.len() > 1
+        };
+
+        Command {
+            attach_before,
+            attach_after,
+            glue,
+            text: if is_invisible { b"" } else { text }
+        }
+    }
+
+    fn is_punctuation(&self) -> bool {
+        self.text.len() == 1 && PUNCTUATION.contains(&self.text[0])
+    }
+}
+
+// finds the command starting at the '{' at pos. returns its content and
+// the position after the closing brace, or None if it isn't closed.
+fn find_command(translation: &[u8], pos: usize) -> Option<(&[u8], usize)> {
+    let length = translation[pos + 1..].iter().position(|&byte| byte == b'}')?;
+    Some((&translation[pos + 1..pos + 1 + length], pos + length + 2))
+}
+
+// the visible text of a translation, one byte at a time
+#[derive(Clone)]
+pub struct VisibleText<'a> {
+    translation: &'a [u8],
+    read_pos: usize,
+    // the text of the command we're in the middle of
+    command_text: &'a [u8]
+}
+
+impl<'a> VisibleText<'a> {
+    pub fn new(translation: &'a [u8]) -> VisibleText<'a> {
+        VisibleText {
+            translation,
+            read_pos: 0,
+            command_text: b""
+        }
+    }
+}
+
+impl<'a> Iterator for VisibleText<'a> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        loop {
+            if let Some((&byte, rest)) = self.command_text.split_first() {
+                self.command_text = rest;
+                return Some(byte);
+            }
+
+            let &byte = self.translation.get(self.read_pos)?;
+
+            match byte {
+                // escaped braces
+                b'\\' if matches!(self.translation.get(self.read_pos + 1), Some(b'{') | Some(b'}')) => {
+                    self.read_pos += 2;
+                    return Some(self.translation[self.read_pos - 1]);
+                }
+                b'{' => match find_command(self.translation, self.read_pos) {
+                    Some((content, end)) => {
+                        self.command_text = Command::parse(content).text;
+                        self.read_pos = end;
+                    }
+                    // plover leaves those alone
+                    None => {
+                        self.read_pos += 1;
+                        return Some(byte);
+                    }
+                },
+                _ => {
+                    self.read_pos += 1;
+                    return Some(byte);
+                }
+            }
+        }
+    }
+}
+
+// whether the translation has any formatting that VisibleText takes away
+pub fn has_markup(translation: &[u8]) -> bool {
+    !VisibleText::new(translation).eq(translation.iter().copied())
+}
+
+// how a translation attaches to the words around it. this only looks at
+// the first and the last command, and only if they're at the very start
+// or end of the translation.
+pub fn attachment(translation: &[u8]) -> Attachment {
+    let first = if translation.first() == Some(&b'{') {
+        find_command(translation, 0).map(|(content, end)| (Command::parse(content), end))
+    }
+    else {
+        None
+    };
+
+    let last = match translation.iter().rposition(|&byte| byte == b'{') {
+        Some(start) if translation.last() == Some(&b'}') => find_command(translation, start).map(|(content, _)| Command::parse(content)),
+        _ => None
+    };
+
+    if let Some((command, end)) = &first {
+        if command.glue {
+            return Attachment::Glue;
+        }
+        if *end == translation.len() && command.is_punctuation() {
+            return Attachment::Punctuation;
+        }
+    }
+
+    let attach_before = first.is_some_and(|(command, _)| command.attach_before);
+    let attach_after = last.is_some_and(|command| command.attach_after);
+
+    match (attach_before, attach_after) {
+        (true, true) => Attachment::Infix,
+        (true, false) => Attachment::Suffix,
+        (false, true) => Attachment::Prefix,
+        (false, false) => Attachment::None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn visible(translation: &str) -> String {
+        String::from_utf8(VisibleText::new(translation.as_bytes()).collect()).unwrap()
+    }
+
+    #[test]
+    fn test_visible_text() {
+        assert_eq!(visible("test"), "test");
+        assert_eq!(visible("{^ing}"), "ing");
+        assert_eq!(visible("{re^}"), "re");
+        assert_eq!(visible("{^-^}"), "-");
+        assert_eq!(visible("{,}"), ",");
+        assert_eq!(visible("{^,^}"), ",");
+        assert_eq!(visible("{.}"), ".");
+        assert_eq!(visible("{&a}"), "a");
+        assert_eq!(visible("{-|}"), "");
+        assert_eq!(visible("{>}"), "");
+        assert_eq!(visible("{^}"), "");
+        assert_eq!(visible("{~|'^}"), "'");
+        assert_eq!(visible("{#Return}{^}"), "");
+        assert_eq!(visible("{PLOVER:TOGGLE}"), "");
+        assert_eq!(visible("{*-|}"), "");
+        assert_eq!(visible("{:}"), ":");
+        assert_eq!(visible("hello{,}"), "hello,");
+        assert_eq!(visible("{.}{-|}"), ".");
+        assert_eq!(visible("{^}\n{^}"), "\n");
+        assert_eq!(visible("\\{not a command\\}"), "{not a command}");
+        assert_eq!(visible("{unclosed"), "{unclosed");
+
+        assert!(has_markup(b"{^ing}"));
+        assert!(has_markup(b"{^}"));
+        assert!(!has_markup(b"plain"));
+    }
+
+    #[test]
+    fn test_attachment() {
+        assert_eq!(attachment(b"test"), Attachment::None);
+        assert_eq!(attachment(b"{^ing}"), Attachment::Suffix);
+        assert_eq!(attachment(b"{re^}"), Attachment::Prefix);
+        assert_eq!(attachment(b"{^-^}"), Attachment::Infix);
+        assert_eq!(attachment(b"{^}"), Attachment::Infix);
+        assert_eq!(attachment(b"{&a}"), Attachment::Glue);
+        assert_eq!(attachment(b"{,}"), Attachment::Punctuation);
+        assert_eq!(attachment(b"{?}"), Attachment::Punctuation);
+        assert_eq!(attachment(b"{-|}"), Attachment::None);
+        assert_eq!(attachment(b"{^ing}{-|}"), Attachment::Suffix);
+        assert_eq!(attachment(b"mid{dle^}"), Attachment::Prefix);
+    }
+}
@@ -0,0 +1,97 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// lists of offsets that take up as few bytes as possible: every offset
+// gets width bytes, lowest byte first, where width is just enough for the
+// largest offset the list can have. for offsets into a few hundred KiB of
+// data, that's 3 bytes instead of the 4 or 8 of a usize.
+
+use core::cmp::Ordering;
+
+// the number of bytes for offsets below limit (at least one)
+pub fn width(limit: usize) -> usize {
+    let bits = (usize::BITS - limit.leading_zeros()) as usize;
+    bits.div_ceil(8).max(1)
+}
+
+fn read_bytes(bytes: &[u8]) -> usize {
+    bytes.iter().rev().fold(0, |offset, &byte| offset << 8 | byte as usize)
+}
+
+pub fn read(packed: &[u8], width: usize, index: usize) -> usize {
+    read_bytes(&packed[index * width..(index + 1) * width])
+}
+
+pub fn write(packed: &mut [u8], width: usize, index: usize, offset: usize) {
+    for (byte_index, byte) in packed[index * width..(index + 1) * width].iter_mut().enumerate() {
+        *byte = (offset >> (8 * byte_index)) as u8;
+    }
+}
+
+// the first index for which is_before is false, like slice::partition_point
+pub fn partition_point(packed: &[u8], width: usize, is_before: impl Fn(usize) -> bool) -> usize {
+    let mut start = 0;
+    let mut end = packed.len() / width;
+
+    while start < end {
+        let middle = start + (end - start) / 2;
+        if is_before(read(packed, width, middle)) {
+            start = middle + 1;
+        }
+        else {
+            end = middle;
+        }
+    }
+
+    start
+}
+
+// sorts the offsets in place. the width has to be known at compile time for
+// that, so there's one version for every width an offset can have.
+pub fn sort_by(packed: &mut [u8], width: usize, compare: impl Fn(usize, usize) -> Ordering) {
+    fn sort<const WIDTH: usize>(packed: &mut [u8], compare: impl Fn(usize, usize) -> Ordering) {
+        let (offsets, _) = packed.as_chunks_mut::<WIDTH>();
+        offsets.sort_unstable_by(|a, b| compare(read_bytes(a), read_bytes(b)));
+    }
+
+    match width {
+        1 => sort::<1>(packed, compare),
+        2 => sort::<2>(packed, compare),
+        3 => sort::<3>(packed, compare),
+        4 => sort::<4>(packed, compare),
+        5 => sort::<5>(packed, compare),
+        6 => sort::<6>(packed, compare),
+        7 => sort::<7>(packed, compare),
+        _ => sort::<8>(packed, compare)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packed_offsets() {
+        assert_eq!(width(0), 1);
+        assert_eq!(width(255), 1);
+        assert_eq!(width(256), 2);
+        assert_eq!(width(70000), 3);
+
+        let width = width(70000);
+        let mut packed = [0u8; 12];
+        for (index, &offset) in [69999, 5, 256, 300].iter().enumerate() {
+            write(&mut packed, width, index, offset);
+        }
+        assert_eq!(read(&packed, width, 0), 69999);
+        assert_eq!(read(&packed, width, 2), 256);
+
+        sort_by(&mut packed, width, |a, b| a.cmp(&b));
+        let offsets: Vec<usize> = (0..4).map(|index| read(&packed, width, index)).collect();
+        assert_eq!(offsets, vec![5, 256, 300, 69999]);
+
+        assert_eq!(partition_point(&packed, width, |offset| offset < 300), 2);
+        assert_eq!(partition_point(&packed, width, |_| true), 4);
+        assert_eq!(partition_point(&packed[..0], width, |_| true), 0);
+    }
+}
@@ -0,0 +1,140 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// patterns for finding outlines by their keys, like "every stroke with
+// -FRPBLG in it", or "single strokes with exactly KA on the left bank".
+// each stroke of the pattern says which keys have to be pressed and which
+// mustn't be, the others can be either way.
+
+use crate::StrokeWidth;
+
+// the same bits as in the strokes (see StenoSystem::parse_stroke). this
+// is laid out like two u32s, so the wasm module can read a list of these
+// straight out of memory.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct StrokePattern {
+    pub required: u32,
+    pub forbidden: u32
+}
+
+impl StrokePattern {
+    pub fn matches(&self, stroke: u32) -> bool {
+        stroke & self.required == self.required && stroke & self.forbidden == 0
+    }
+}
+
+// which strokes of an outline a list of patterns has to match
+#[derive(Clone, Copy, PartialEq)]
+pub enum PatternPosition {
+    // all of them: the outline has one stroke for each pattern
+    Whole,
+    // consecutive strokes anywhere in the outline
+    Anywhere,
+    // consecutive strokes, starting with the given one (counting from 0)
+    At(usize)
+}
+
+// whether the strokes starting at pos match the patterns one by one.
+// returns the position after them if they do.
+fn match_strokes(patterns: &[StrokePattern], stroke_width: StrokeWidth, strokes: &[u8], mut pos: usize) -> Option<usize> {
+    for pattern in patterns {
+        if pos >= strokes.len() {
+            return None;
+        }
+
+        let stroke = stroke_width.unpack(strokes, &mut pos)?;
+        if !pattern.matches(stroke) {
+            return None;
+        }
+    }
+
+    Some(pos)
+}
+
+// whether the packed strokes (see StrokeWidth) of an outline match the patterns
+pub fn matches_outline(patterns: &[StrokePattern], position: PatternPosition, stroke_width: StrokeWidth, strokes: &[u8]) -> bool {
+    match position {
+        PatternPosition::Whole => match_strokes(patterns, stroke_width, strokes, 0) == Some(strokes.len()),
+        PatternPosition::At(index) => {
+            let mut pos = 0;
+            for _ in 0..index {
+                if pos >= strokes.len() || stroke_width.unpack(strokes, &mut pos).is_none() {
+                    return false;
+                }
+            }
+            match_strokes(patterns, stroke_width, strokes, pos).is_some()
+        }
+        PatternPosition::Anywhere => {
+            let mut pos = 0;
+            while pos < strokes.len() {
+                if match_strokes(patterns, stroke_width, strokes, pos).is_some() {
+                    return true;
+                }
+                if stroke_width.unpack(strokes, &mut pos).is_none() {
+                    return false;
+                }
+            }
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ENGLISH_STENOTYPE;
+
+    fn pattern(required: &[u8], forbidden: &[u8]) -> StrokePattern {
+        let system = &ENGLISH_STENOTYPE;
+        StrokePattern {
+            required: system.parse_stroke(required, &mut 0),
+            forbidden: system.parse_stroke(forbidden, &mut 0)
+        }
+    }
+
+    fn pack(text: &[u8]) -> Vec<u8> {
+        let system = &ENGLISH_STENOTYPE;
+        let mut strokes = Vec::new();
+        let mut pos = 0;
+        while pos < text.len() {
+            let stroke = system.parse_stroke(text, &mut pos);
+            let mut packed = [0u8; crate::MAX_STROKE_SIZE];
+            let length = system.stroke_width().pack(stroke, &mut packed);
+            strokes.extend_from_slice(&packed[..length]);
+        }
+        strokes
+    }
+
+    #[test]
+    fn test_matches_outline() {
+        let width = ENGLISH_STENOTYPE.stroke_width();
+        let matches = |patterns: &[StrokePattern], position, strokes: &[u8]| matches_outline(patterns, position, width, &pack(strokes));
+
+        let frpblg = [pattern(b"-FRPBLG", b"")];
+        assert!(matches(&frpblg, PatternPosition::Anywhere, b"PHA*FRPBLG"));
+        assert!(matches(&frpblg, PatternPosition::Anywhere, b"TEFT/-FRPBLGS"));
+        assert!(!matches(&frpblg, PatternPosition::Anywhere, b"-FRPBL"));
+        assert!(!matches(&frpblg, PatternPosition::Whole, b"TEFT/-FRPBLG"));
+        assert!(matches(&frpblg, PatternPosition::At(1), b"TEFT/-FRPBLG"));
+        assert!(!matches(&frpblg, PatternPosition::At(0), b"TEFT/-FRPBLG"));
+        assert!(!matches(&frpblg, PatternPosition::At(2), b"TEFT/-FRPBLG"));
+
+        // exactly KA on the left, anything on the right
+        let ka = [pattern(b"KA", b"#STPWHROE*U")];
+        assert!(matches(&ka, PatternPosition::Whole, b"KAT"));
+        assert!(matches(&ka, PatternPosition::Whole, b"KA"));
+        assert!(!matches(&ka, PatternPosition::Whole, b"SKAT"));
+        assert!(!matches(&ka, PatternPosition::Whole, b"KAT/-S"));
+
+        // several strokes in a row
+        let two = [pattern(b"T", b""), pattern(b"-G", b"")];
+        assert!(matches(&two, PatternPosition::Anywhere, b"KPA/TEFT/-G"));
+        assert!(!matches(&two, PatternPosition::Anywhere, b"TEFT/KPA/-G"));
+        assert!(matches(&two, PatternPosition::Whole, b"TEFT/-G"));
+
+        assert!(!matches(&[], PatternPosition::Whole, b"TEFT"));
+        assert!(matches(&[], PatternPosition::Anywhere, b"TEFT"));
+    }
+}
@@ -0,0 +1,149 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// puts lookup results in a useful order, best first: short outlines before
+// long ones, outlines without the asterisk (which is often used for
+// misstrokes and less common words) before the ones with, and entries from
+// dictionaries with a higher priority before the others.
+//
+// there's no allocator, so the results can't be collected and sorted.
+// instead, yield_ranked runs the lookup again for every result it passes
+// on, and picks the best one that comes after the one before. that's fine
+// for the few dozen outlines a translation can have.
+
+use crate::{QueryResult, StenoSystem, StrokeWidth};
+
+// smaller is better, field by field
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Rank {
+    pub strokes: usize,
+    pub keys: u32,
+    pub asterisk: bool,
+    pub source: u8
+}
+
+pub struct Ranking {
+    stroke_width: StrokeWidth,
+    // the keys labelled "*"
+    asterisks: u32
+}
+
+impl Ranking {
+    // system has to be the one the strokes were packed with
+    pub fn new(system: &StenoSystem) -> Ranking {
+        let asterisks = (0..system.num_keys())
+            .filter(|&key| system.key_label(key) == b"*")
+            .fold(0, |asterisks, key| asterisks | 1 << key);
+
+        Ranking {
+            stroke_width: system.stroke_width(),
+            asterisks
+        }
+    }
+
+    pub fn rank(&self, result: &QueryResult) -> Rank {
+        let strokes = || self.stroke_width.unpack_all(result.strokes);
+
+        Rank {
+            strokes: strokes().count(),
+            keys: strokes().map(u32::count_ones).sum(),
+            asterisk: strokes().any(|stroke| stroke & self.asterisks != 0),
+            source: result.source
+        }
+    }
+}
+
+// passes the results from matches() to yield_result, best first, skipping
+// the first skip ones and stopping after limit. matches has to return the
+// same results in the same order every time. returns the number of results.
+pub fn yield_ranked<'a, I, F>(ranking: &Ranking, matches: impl Fn() -> I, skip: usize, limit: usize, mut yield_result: F) -> usize
+    where I: Iterator<Item = QueryResult<'a>>, F: FnMut(QueryResult<'a>)
+{
+    let total = matches().count();
+    let end = skip.saturating_add(limit).min(total);
+
+    // where each result comes in matches() breaks ties, so every result
+    // has its own place
+    let mut previous: Option<(Rank, usize)> = None;
+
+    for position in 0..end {
+        let next = matches()
+            .enumerate()
+            .map(|(index, result)| ((ranking.rank(&result), index), result))
+            .filter(|(key, _)| previous.is_none_or(|previous| *key > previous))
+            .min_by_key(|(key, _)| *key);
+
+        let (key, result) = match next {
+            Some(next) => next,
+            None => break
+        };
+        previous = Some(key);
+
+        if position >= skip {
+            yield_result(result);
+        }
+    }
+
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ENGLISH_STENOTYPE, EXTENDED_STENOTYPE};
+
+    fn result(strokes: &[u8], source: u8) -> QueryResult<'_> {
+        QueryResult {
+            strokes,
+            translation: b"",
+            source,
+            exact: true
+        }
+    }
+
+    #[test]
+    fn test_rank() {
+        let ranking = Ranking::new(&ENGLISH_STENOTYPE);
+
+        // "TEFT" and "T*EFT"
+        let teft = [0x04, 0x28, 0x08];
+        let t_eft = [0x04, 0x2C, 0x08];
+        // "TEFT/-G"
+        let teft_g = [0x04, 0x28, 0x08, 0x00, 0x00, 0x04];
+
+        assert_eq!(ranking.rank(&result(&teft, 0)), Rank { strokes: 1, keys: 4, asterisk: false, source: 0 });
+        assert_eq!(ranking.rank(&result(&t_eft, 1)), Rank { strokes: 1, keys: 5, asterisk: true, source: 1 });
+        assert_eq!(ranking.rank(&result(&teft_g, 0)).strokes, 2);
+        assert!(ranking.rank(&result(&teft, 1)) < ranking.rank(&result(&teft_g, 0)));
+        assert!(ranking.rank(&result(&teft, 0)) < ranking.rank(&result(&teft, 1)));
+
+        // extended English Stenotype has two
+        let ranking = Ranking::new(&EXTENDED_STENOTYPE);
+        assert_eq!(ranking.asterisks.count_ones(), 2);
+    }
+
+    #[test]
+    fn test_yield_ranked() {
+        let ranking = Ranking::new(&ENGLISH_STENOTYPE);
+        let outlines: [(&[u8], u8); 4] = [
+            (&[0x04, 0x28, 0x08, 0x00, 0x00, 0x04], 0),
+            (&[0x04, 0x2C, 0x08], 0),
+            (&[0x04, 0x28, 0x08], 1),
+            (&[0x04, 0x28, 0x08], 0),
+        ];
+        let matches = || outlines.iter().map(|&(strokes, source)| result(strokes, source));
+
+        let ranked = |skip, limit| {
+            let mut results = Vec::new();
+            let total = yield_ranked(&ranking, matches, skip, limit, |result| results.push((result.strokes.len(), result.source)));
+            (total, results)
+        };
+
+        assert_eq!(ranked(0, 10), (4, vec![(3, 0), (3, 1), (3, 0), (6, 0)]));
+        assert_eq!(ranked(1, 2), (4, vec![(3, 1), (3, 0)]));
+        assert_eq!(ranked(3, usize::MAX), (4, vec![(6, 0)]));
+        assert_eq!(ranked(5, 10), (4, vec![]));
+    }
+}
@@ -0,0 +1,414 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// loader for RTF/CRE dictionaries, the format that Plover, Eclipse
+// and Case CATalyst use to exchange dictionaries. a dictionary looks
+// roughly like this:
+//
+//   {\rtf1\ansi{\*\cxrev100}\cxdict{\*\cxsystem Plover}{\stylesheet{\s0 Normal;}}
+//   {\*\cxs TEFT}test
+//   {\*\cxs TEFT/-G}testing\par
+//   }
+//
+// every entry starts with a {\*\cxs ...} group containing the strokes,
+// and the translation is everything up to the next entry. we only
+// understand the subset of RTF that steno software actually writes, and
+// translate the control words that have a Plover equivalent into
+// Plover's translation syntax, so that the results look the same as
+// for a json dictionary.
+
+use crate::{InternalError, InternalResult, InputPosition, LineCounter, DataStructuresContainer, LoadOptions, Collision, Diagnostic, PARSER_ERROR};
+use crate::{build_container, parser_error, write_utf8, varint, validation};
+
+static ENTRY_START: &[u8] = b"{\\*\\cxs";
+
+// loads an RTF/CRE dictionary into our custom memory format.
+// like load_json_internal, this converts the dictionary in-place into
+// the binary intermediate format first, and then builds the hash
+// tables from that.
+pub fn load_rtf_internal<ContainerType>(buffer: &mut [u8], options: &LoadOptions) -> InternalResult<ContainerType>
+    where ContainerType: DataStructuresContainer
+{
+    load_rtf_with_report(buffer, *options, |_| {}, |_| {})
+}
+
+// like load_rtf_internal, but reports problems with the strokes and
+// collisions the same way load_dictionary_stack_with_report does
+pub fn load_rtf_with_report<ContainerType, F, G>(buffer: &mut [u8], options: LoadOptions, report: F, report_collision: G) -> InternalResult<ContainerType>
+    where ContainerType: DataStructuresContainer, F: FnMut(Diagnostic), G: FnMut(Collision)
+{
+    let mut read_pos = 0;
+    let mut write_pos = 0;
+    let mut lines = LineCounter::new();
+
+    parse_rtf(buffer, &mut read_pos, &mut write_pos, &mut lines)
+        .map_err(|error| lines.locate(error))?;
+
+    let found_problems = validation::validate_intermediate(&buffer[..write_pos], &[write_pos], options.system, options.strict, report);
+    if options.strict && found_problems {
+        return Err(validation::strict_error());
+    }
+
+    build_container(&buffer[..write_pos], &[write_pos], options, report_collision)
+}
+
+// converts the dictionary starting at read_pos into the intermediate format
+pub(crate) fn parse_rtf(buffer: &mut [u8], read_pos: &mut usize, write_pos: &mut usize, lines: &mut LineCounter) -> InternalResult<()> {
+
+    if !buffer[*read_pos..].starts_with(b"{\\rtf") {
+        return Err(parser_error!(buffer, *read_pos, b"Parser error: this is not an RTF file"));
+    }
+
+    // everything before the first entry is the document header
+    // (stylesheets, cxsystem, ...), which we don't need.
+    while !buffer[*read_pos..].starts_with(ENTRY_START) {
+        let byte = *buffer.get(*read_pos).ok_or(parser_error!(buffer, *read_pos, b"Parser error: no entries found"))?;
+        *read_pos += 1;
+        lines.count(byte, *read_pos);
+    }
+
+    // INVARIANT: read_pos >= write_pos + 7 (we're looking at an entry start)
+
+    loop {
+        // the entry start plus the closing '}' is 8 bytes, and we
+        // usually only need 2 to 4 bytes for the two length headers,
+        // so we always gain space with every entry.
+        read_strokes(buffer, read_pos, write_pos, lines)?;
+        let reached_end = read_translation(buffer, read_pos, write_pos, lines)?;
+
+        if reached_end {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// reads the {\*\cxs ...} group and writes the strokes to the output,
+// with a length header.
+fn read_strokes(buffer: &mut [u8], read_pos: &mut usize, write_pos: &mut usize, lines: &mut LineCounter) -> InternalResult<()> {
+    *read_pos += ENTRY_START.len();
+
+    // the control word is delimited by a single space
+    if buffer.get(*read_pos) == Some(&b' ') {
+        *read_pos += 1;
+    }
+
+    // placeholder for the header, see varint.rs
+    let length_header_offset = *write_pos;
+    *write_pos += 1;
+
+    loop {
+        let byte = *buffer.get(*read_pos).ok_or(parser_error!(buffer, *read_pos, b"Parser error: data ended in the middle of a stroke definition"))?;
+        *read_pos += 1;
+        lines.count(byte, *read_pos);
+
+        match byte {
+            b'}' => break,
+            b'{' | b'\\' => return Err(parser_error!(buffer, *read_pos - 1, b"Parser error: unexpected formatting in stroke definition")),
+            b'\r' | b'\n' => (),
+            _ => {
+                buffer[*write_pos] = byte;
+                *write_pos += 1;
+            }
+        }
+    }
+
+    write_length_header(buffer, *read_pos, length_header_offset, write_pos)
+}
+
+// reads the translation text following a stroke definition, up to the
+// next entry or the end of the document. returns true if this was the
+// last entry.
+fn read_translation(buffer: &mut [u8], read_pos: &mut usize, write_pos: &mut usize, lines: &mut LineCounter) -> InternalResult<bool> {
+    let length_header_offset = *write_pos;
+    *write_pos += 1;
+
+    // groups opened within the translation. we don't care about
+    // formatting, so we only need this to find the end of the document.
+    let mut depth = 0;
+    let reached_end;
+
+    loop {
+        let byte = match buffer.get(*read_pos) {
+            Some(&byte) => byte,
+            // be lenient about a missing final '}'
+            None => {
+                reached_end = true;
+                break;
+            }
+        };
+
+        if byte == b'{' {
+            if buffer[*read_pos..].starts_with(ENTRY_START) {
+                reached_end = false;
+                break;
+            }
+
+            if buffer[*read_pos..].starts_with(b"{\\*") {
+                // an ignorable destination, like {\*\cxcomment ...}
+                skip_group(buffer, read_pos, lines)?;
+            }
+            else if buffer[*read_pos..].starts_with(b"{\\cxp") {
+                read_punctuation(buffer, read_pos, write_pos, lines)?;
+            }
+            else {
+                *read_pos += 1;
+                depth += 1;
+            }
+        }
+        else if byte == b'}' {
+            *read_pos += 1;
+            if depth == 0 {
+                // this closes the document
+                reached_end = true;
+                break;
+            }
+            depth -= 1;
+        }
+        else if byte == b'\\' {
+            read_control(buffer, read_pos, write_pos, lines)?;
+        }
+        else {
+            *read_pos += 1;
+            lines.count(byte, *read_pos);
+
+            // line breaks in the file have no meaning in RTF
+            if byte != b'\r' && byte != b'\n' {
+                write_bytes(buffer, *read_pos, write_pos, &[byte])?;
+            }
+        }
+    }
+
+    write_length_header(buffer, *read_pos, length_header_offset, write_pos)?;
+
+    Ok(reached_end)
+}
+
+// handles a control symbol (like \{) or control word (like \par)
+// starting at read_pos.
+fn read_control(buffer: &mut [u8], read_pos: &mut usize, write_pos: &mut usize, lines: &mut LineCounter) -> InternalResult<()> {
+    let start = *read_pos;
+    *read_pos += 1;
+
+    let symbol = *buffer.get(*read_pos).ok_or(parser_error!(buffer, start, b"Parser error: data ended in the middle of a control word"))?;
+
+    if !symbol.is_ascii_alphabetic() {
+        *read_pos += 1;
+        lines.count(symbol, *read_pos);
+
+        return match symbol {
+            b'\\' | b'{' | b'}' => write_bytes(buffer, *read_pos, write_pos, &[symbol]),
+            // non-breaking space
+            b'~' => write_code_point(buffer, *read_pos, write_pos, 0xA0),
+            // non-breaking hyphen
+            b'_' => write_code_point(buffer, *read_pos, write_pos, 0x2011),
+            b'\'' => {
+                let value = read_hex_byte(buffer, read_pos)?;
+                write_code_point(buffer, *read_pos, write_pos, windows_1252_to_unicode(value))
+            }
+            // optional hyphens, escaped line breaks and everything else
+            // we don't know about don't show up in the output
+            _ => Ok(())
+        };
+    }
+
+    let name_start = *read_pos;
+    while buffer.get(*read_pos).is_some_and(|byte| byte.is_ascii_alphabetic()) {
+        *read_pos += 1;
+    }
+    let name_end = *read_pos;
+
+    let parameter = read_parameter(buffer, read_pos);
+
+    // a single space after a control word belongs to the control word
+    if buffer.get(*read_pos) == Some(&b' ') {
+        *read_pos += 1;
+    }
+
+    let output: &[u8] = match &buffer[name_start..name_end] {
+        b"cxds" => b"{^}",
+        b"cxfc" => b"{-|}",
+        b"cxfl" => b"{>}",
+        b"line" => b"{^\n^}",
+        b"par" => b"{^\n\n^}",
+        b"tab" => b"{^\t^}",
+        b"u" => {
+            // unicode character, followed by a fallback character
+            // for readers that don't understand \u
+            let value = parameter.unwrap_or(0x3F);
+            let code_point = if value < 0 { value + 0x10000 } else { value } as u32;
+            skip_unicode_fallback(buffer, read_pos);
+            return write_code_point(buffer, *read_pos, write_pos, code_point);
+        }
+        _ => b""
+    };
+
+    write_bytes(buffer, *read_pos, write_pos, output)
+}
+
+// reads the (optional) numeric parameter of a control word
+fn read_parameter(buffer: &[u8], read_pos: &mut usize) -> Option<i32> {
+    let negative = buffer.get(*read_pos) == Some(&b'-');
+    let digits_start = if negative { *read_pos + 1 } else { *read_pos };
+
+    let mut pos = digits_start;
+    let mut value: i32 = 0;
+    while let Some(&digit) = buffer.get(pos).filter(|byte| byte.is_ascii_digit()) {
+        value = value.saturating_mul(10).saturating_add((digit - b'0') as i32);
+        pos += 1;
+    }
+
+    if pos == digits_start {
+        return None;
+    }
+
+    *read_pos = pos;
+    Some(if negative { -value } else { value })
+}
+
+fn skip_unicode_fallback(buffer: &[u8], read_pos: &mut usize) {
+    match buffer.get(*read_pos) {
+        Some(b'\\') if buffer.get(*read_pos + 1) == Some(&b'\'') => *read_pos += 4,
+        Some(b'\\') | Some(b'{') | Some(b'}') | None => (),
+        Some(_) => *read_pos += 1
+    }
+}
+
+fn read_hex_byte(buffer: &[u8], read_pos: &mut usize) -> InternalResult<u8> {
+    let digits = buffer.get(*read_pos .. *read_pos + 2).ok_or(parser_error!(buffer, *read_pos, b"Parser error: data ended in the middle of a hex escape"))?;
+
+    let mut value = 0;
+    for &digit in digits {
+        let digit_value = match digit {
+            b'0' ..= b'9' => digit - b'0',
+            b'a' ..= b'f' => digit - b'a' + 10,
+            b'A' ..= b'F' => digit - b'A' + 10,
+            _ => return Err(parser_error!(buffer, *read_pos, b"Parser error: invalid hex escape"))
+        };
+        value = (value << 4) | digit_value;
+    }
+
+    *read_pos += 2;
+    Ok(value)
+}
+
+// {\cxp. } is punctuation, which is {.} in Plover
+fn read_punctuation(buffer: &mut [u8], read_pos: &mut usize, write_pos: &mut usize, lines: &mut LineCounter) -> InternalResult<()> {
+    let group_start = *read_pos;
+    *read_pos += b"{\\cxp".len();
+
+    let content_start = *read_pos;
+    while let Some(&byte) = buffer.get(*read_pos) {
+        if byte == b'}' || byte == b'{' || byte == b'\\' {
+            break;
+        }
+        *read_pos += 1;
+        lines.count(byte, *read_pos);
+    }
+    let content_end = *read_pos;
+
+    if buffer.get(*read_pos) != Some(&b'}') {
+        return Err(parser_error!(buffer, group_start, b"Parser error: unexpected formatting in punctuation group"));
+    }
+    *read_pos += 1;
+
+    // Plover doesn't want the spaces around the punctuation
+    let mut start = content_start;
+    let mut end = content_end;
+    while start < end && buffer[start] == b' ' {
+        start += 1;
+    }
+    while end > start && buffer[end - 1] == b' ' {
+        end -= 1;
+    }
+
+    // {\cxp is 5 bytes, and we only add two braces, so there's enough space
+    write_bytes(buffer, *read_pos, write_pos, b"{")?;
+    buffer.copy_within(start..end, *write_pos);
+    *write_pos += end - start;
+    write_bytes(buffer, *read_pos, write_pos, b"}")
+}
+
+// skips over a group, including all nested groups
+fn skip_group(buffer: &[u8], read_pos: &mut usize, lines: &mut LineCounter) -> InternalResult<()> {
+    let group_start = *read_pos;
+    let mut depth = 0;
+
+    loop {
+        let byte = *buffer.get(*read_pos).ok_or(parser_error!(buffer, group_start, b"Parser error: group is never closed"))?;
+        *read_pos += 1;
+        lines.count(byte, *read_pos);
+
+        match byte {
+            b'\\' => *read_pos += 1,
+            b'{' => depth += 1,
+            b'}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(());
+                }
+            }
+            _ => ()
+        }
+    }
+}
+
+// some control words are replaced by something longer (\par is
+// replaced by {^\n\n^}), so we can't be sure that the output never
+// overtakes the input. in practice, every entry frees up a few
+// bytes, so this should only fail for really weird dictionaries.
+fn ensure_space(buffer: &[u8], read_pos: usize, write_pos: usize, length: usize) -> InternalResult<()> {
+    if write_pos + length > read_pos {
+        return Err(InternalError {
+            message: b"I'm sorry, but we can't handle your dictionary.",
+            details: b"There is nothing wrong with it, except that it uses a lot of formatting in a small space, and we ran out of room while converting it.",
+            line: line!(),
+            position: Some(InputPosition::new(buffer, read_pos))
+        });
+    }
+
+    Ok(())
+}
+
+fn write_bytes(buffer: &mut [u8], read_pos: usize, write_pos: &mut usize, bytes: &[u8]) -> InternalResult<()> {
+    ensure_space(buffer, read_pos, *write_pos, bytes.len())?;
+
+    buffer[*write_pos .. *write_pos + bytes.len()].copy_from_slice(bytes);
+    *write_pos += bytes.len();
+    Ok(())
+}
+
+fn write_code_point(buffer: &mut [u8], read_pos: usize, write_pos: &mut usize, code_point: u32) -> InternalResult<()> {
+    let length = char::from_u32(code_point).unwrap_or('\u{FFFD}').len_utf8();
+    ensure_space(buffer, read_pos, *write_pos, length)?;
+
+    write_utf8(buffer, write_pos, code_point);
+    Ok(())
+}
+
+fn write_length_header(buffer: &mut [u8], read_pos: usize, length_header_offset: usize, write_pos: &mut usize) -> InternalResult<()> {
+    let length = *write_pos - length_header_offset - 1;
+    ensure_space(buffer, read_pos, *write_pos, varint::size(length) - 1)?;
+
+    varint::finish_header(buffer, length_header_offset, write_pos);
+    Ok(())
+}
+
+// RTF's \'hh escapes are in the document's code page, which is
+// windows-1252 for all the dictionaries we care about. this is the
+// same as latin-1, except for 0x80 - 0x9F.
+fn windows_1252_to_unicode(byte: u8) -> u32 {
+    static HIGH_CONTROL_RANGE: [u16; 32] = [
+        0x20AC, 0xFFFD, 0x201A, 0x0192, 0x201E, 0x2026, 0x2020, 0x2021,
+        0x02C6, 0x2030, 0x0160, 0x2039, 0x0152, 0xFFFD, 0x017D, 0xFFFD,
+        0xFFFD, 0x2018, 0x2019, 0x201C, 0x201D, 0x2022, 0x2013, 0x2014,
+        0x02DC, 0x2122, 0x0161, 0x203A, 0x0153, 0xFFFD, 0x017E, 0x0178,
+    ];
+
+    match byte {
+        0x80 ..= 0x9F => HIGH_CONTROL_RANGE[(byte - 0x80) as usize] as u32,
+        _ => byte as u32
+    }
+}
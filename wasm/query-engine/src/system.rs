@@ -0,0 +1,899 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// steno systems, ie which keys a steno machine has, and how strokes
+// are written down. the keys are described the same way plover does
+// it: "S-" is a key on the left bank, "-F" a key on the right bank,
+// and "*" a key that can go on either side. every key gets one bit in
+// the packed stroke, in steno order, so with English Stenotype, '#' is
+// bit 0, 'S-' is bit 1, and so on.
+//
+// everything in here is a const fn, so that the tables for the built-in
+// systems can be generated at build time. systems that are loaded at
+// runtime (see from_json) go through the same code.
+
+use core::hash::Hasher;
+use wyhash::WyHash;
+use crate::{InternalError, InternalResult, BufferIterator, error, json, varint};
+
+// a stroke has to fit into a u32, so this is a hard limit: systems with
+// more keys than this can't be loaded at all
+pub const MAX_KEYS: usize = 32;
+
+const NO_KEY: u8 = 0xFF;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Side {
+    Left,
+    // keys without a hyphen, like '*' or '#'
+    Either,
+    Right
+}
+
+// how strokes are packed into bytes, lowest bits first. varints are
+// shorter for systems with lots of keys that are rarely used, but with
+// the other two, every stroke has the same length.
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+#[repr(u8)]
+pub enum StrokeWidth {
+    Varint = 0,
+    ThreeBytes = 3,
+    FourBytes = 4
+}
+
+// the most bytes a packed stroke can take up
+pub const MAX_STROKE_SIZE: usize = varint::MAX_SIZE;
+
+impl StrokeWidth {
+    pub fn from_u8(width: u8) -> Option<StrokeWidth> {
+        match width {
+            0 => Some(StrokeWidth::Varint),
+            3 => Some(StrokeWidth::ThreeBytes),
+            4 => Some(StrokeWidth::FourBytes),
+            _ => None
+        }
+    }
+
+    // writes stroke into buffer, returns the number of bytes written
+    pub fn pack(self, stroke: u32, buffer: &mut [u8; MAX_STROKE_SIZE]) -> usize {
+        match self {
+            StrokeWidth::Varint => varint::write(buffer, 0, stroke as usize),
+            _ => {
+                let width = self as usize;
+                buffer[..width].copy_from_slice(&stroke.to_le_bytes()[..width]);
+                width
+            }
+        }
+    }
+
+    // reads the stroke starting at pos and moves pos past it. returns
+    // None if the buffer ends in the middle of the stroke.
+    pub fn unpack(self, buffer: &[u8], pos: &mut usize) -> Option<u32> {
+        let mut stroke = 0;
+        let mut index = 0;
+
+        loop {
+            let byte = *buffer.get(*pos + index)?;
+            match self {
+                StrokeWidth::Varint => {
+                    stroke |= ((byte & 0x7F) as u32) << (7 * index);
+                    index += 1;
+                    if byte & 0x80 == 0 || index == MAX_STROKE_SIZE {
+                        break;
+                    }
+                }
+                _ => {
+                    stroke |= (byte as u32) << (8 * index);
+                    index += 1;
+                    if index == self as usize {
+                        break;
+                    }
+                }
+            }
+        }
+
+        *pos += index;
+        Some(stroke)
+    }
+
+    // all strokes in buffer, one after the other (up to the first one
+    // that isn't complete)
+    pub fn unpack_all(self, buffer: &[u8]) -> impl Iterator<Item = u32> + '_ {
+        let mut pos = 0;
+        core::iter::from_fn(move || self.unpack(buffer, &mut pos))
+    }
+}
+
+// key labels are single characters, so they take up at most 4 bytes
+const MAX_LABEL_LENGTH: usize = 4;
+
+#[derive(Clone, Copy)]
+struct Key {
+    label: [u8; MAX_LABEL_LENGTH],
+    label_length: u8,
+    side: Side,
+    // keys like the vowels, which make the hyphen unnecessary
+    implicit_hyphen: bool,
+    // the digit this key stands for together with the number key, or 0
+    digit: u8
+}
+
+impl Key {
+    const fn label(&self) -> &[u8] {
+        self.label.split_at(self.label_length as usize).0
+    }
+
+    const EMPTY: Key = Key {
+        label: [0; MAX_LABEL_LENGTH],
+        label_length: 0,
+        side: Side::Either,
+        implicit_hyphen: false,
+        digit: 0
+    };
+}
+
+#[derive(Clone, Copy)]
+pub struct StenoSystem {
+    keys: [Key; MAX_KEYS],
+    num_keys: usize,
+    // the first key that can only be reached with a hyphen
+    right_bank_start: usize,
+    // the first right-side key, which is where a hyphen takes us
+    first_right_key: usize,
+    number_key: u8,
+    // the key that each digit stands for
+    digit_keys: [u8; 10],
+    undo_stroke: u32,
+    stroke_width: StrokeWidth,
+
+    // this is the parse table: the first key for each ascii character,
+    // and for every key, the next one with the same label (so for
+    // English Stenotype, 'S' leads to 'S-', and from there to '-S').
+    // keys with non-ascii labels aren't in first_key, they have to be
+    // searched for.
+    first_key: [u8; 128],
+    next_key_with_label: [u8; MAX_KEYS]
+}
+
+// plover's English Stenotype, as most dictionaries are written for it
+pub const ENGLISH_STENOTYPE: StenoSystem = match StenoSystem::new(
+    b"# S- T- K- P- W- H- R- A- O- * -E -U -F -R -P -B -L -G -T -S -D -Z",
+    b"A- O- * -E -U",
+    b"#",
+    b"S-:1 T-:2 P-:3 H-:4 A-:5 O-:0 -F:6 -P:7 -L:8 -T:9",
+    b"*") {
+    Ok(system) => system,
+    Err(_) => panic!("invalid definition for English Stenotype")
+};
+
+// English Stenotype with the '^' and '+' keys that plover 4 and some
+// theories use, and a second number and star key on the right. the
+// right star on its own is written "-*", so it doesn't get mixed up
+// with the left one. strokes need 4 bytes for this one.
+pub const EXTENDED_STENOTYPE: StenoSystem = match StenoSystem::new(
+    b"#- ^- +- S- T- K- P- W- H- R- A- O- *- -* -E -U -F -R -P -B -L -G -T -S -D -Z -#",
+    b"A- O- *- -* -E -U",
+    b"#-",
+    b"S-:1 T-:2 P-:3 H-:4 A-:5 O-:0 -F:6 -P:7 -L:8 -T:9",
+    b"*") {
+    Ok(system) => system,
+    Err(_) => panic!("invalid definition for extended English Stenotype")
+};
+
+// Palantype, with the keys in plover's order. most consonants are on
+// both sides, so they need a hyphen on the right unless there's a vowel
+// or the '.' key before them. there's no number key.
+pub const PALANTYPE: StenoSystem = match StenoSystem::new(
+    b"S- C- P- T- H- +- M- F- R- N- L- Y- O- E- A- U -I -. -N -L -C -M -F -R -P -T -+ -S -H",
+    b"O- E- A- U -I -.",
+    b"",
+    b"",
+    b"") {
+    Ok(system) => system,
+    Err(_) => panic!("invalid definition for Palantype")
+};
+
+// returns the start and end of the next space-separated word in list,
+// starting the search at pos
+const fn next_word(list: &[u8], pos: usize) -> Option<(usize, usize)> {
+    let mut start = pos;
+    while start < list.len() && list[start] == b' ' {
+        start += 1;
+    }
+
+    if start == list.len() {
+        return None;
+    }
+
+    let mut end = start;
+    while end < list.len() && list[end] != b' ' {
+        end += 1;
+    }
+
+    Some((start, end))
+}
+
+// the length of the utf-8 character starting at pos. bytes that can't
+// start a character count as one character each.
+pub const fn character_length(buffer: &[u8], pos: usize) -> usize {
+    let length = match buffer[pos] {
+        0xC0 ..= 0xDF => 2,
+        0xE0 ..= 0xEF => 3,
+        0xF0 ..= 0xF7 => 4,
+        _ => 1
+    };
+
+    if pos + length > buffer.len() {
+        buffer.len() - pos
+    }
+    else {
+        length
+    }
+}
+
+const fn subslice(buffer: &[u8], start: usize, end: usize) -> &[u8] {
+    buffer.split_at(end).0.split_at(start).1
+}
+
+const fn labels_equal(label: &[u8], other: &[u8]) -> bool {
+    if label.len() != other.len() {
+        return false;
+    }
+
+    let mut index = 0;
+    while index < label.len() {
+        if label[index] != other[index] {
+            return false;
+        }
+        index += 1;
+    }
+
+    true
+}
+
+// parses a key like "S-", "-F" or "*". returns the label and side.
+const fn parse_key(list: &[u8], start: usize, end: usize) -> Result<(&[u8], Side), &'static [u8]> {
+    let (label_start, label_end, side) = if end - start > 1 && list[start] == b'-' {
+        (start + 1, end, Side::Right)
+    }
+    else if end - start > 1 && list[end - 1] == b'-' {
+        (start, end - 1, Side::Left)
+    }
+    else {
+        (start, end, Side::Either)
+    };
+
+    let label = subslice(list, label_start, label_end);
+    if label.len() != character_length(label, 0) {
+        return Err(b"Key labels have to be single characters, optionally with a hyphen before or after them.");
+    }
+
+    let mut index = 1;
+    while index < label.len() {
+        if label[index] & 0xC0 != 0x80 {
+            return Err(b"One of the key labels isn't valid UTF-8.");
+        }
+        index += 1;
+    }
+
+    let first = label[0];
+    if label.len() == 1 && first >= 0x80 {
+        return Err(b"One of the key labels isn't valid UTF-8.");
+    }
+
+    if first == b'-' || first == b'/' || first == b':' || first.is_ascii_digit() {
+        return Err(b"Hyphens, slashes, colons and digits can't be used as key labels.");
+    }
+
+    Ok((label, side))
+}
+
+impl StenoSystem {
+    const EMPTY: StenoSystem = StenoSystem {
+        keys: [Key::EMPTY; MAX_KEYS],
+        num_keys: 0,
+        right_bank_start: 0,
+        first_right_key: 0,
+        number_key: NO_KEY,
+        digit_keys: [NO_KEY; 10],
+        undo_stroke: 0,
+        stroke_width: StrokeWidth::ThreeBytes,
+        first_key: [NO_KEY; 128],
+        next_key_with_label: [NO_KEY; MAX_KEYS]
+    };
+
+    // builds a system from plover-style definitions:
+    //  - keys: all keys in steno order, separated by spaces
+    //  - implicit_hyphen_keys: the keys that make a hyphen unnecessary
+    //  - number_key: the key that turns other keys into digits (or "")
+    //  - numbers: which key stands for which digit, like "S-:1 -F:6"
+    //  - undo_stroke: the stroke that undoes the last one
+    pub const fn new(keys: &[u8], implicit_hyphen_keys: &[u8], number_key: &[u8], numbers: &[u8], undo_stroke: &[u8]) -> Result<StenoSystem, &'static [u8]> {
+        let mut system = StenoSystem::EMPTY;
+
+        let mut pos = 0;
+        while let Some((start, end)) = next_word(keys, pos) {
+            if let Err(error) = system.add_key(keys, start, end) {
+                return Err(error);
+            }
+            pos = end;
+        }
+
+        if system.num_keys == 0 {
+            return Err(b"A steno system needs at least one key.");
+        }
+
+        let mut pos = 0;
+        while let Some((start, end)) = next_word(implicit_hyphen_keys, pos) {
+            match system.find_key_by_name(implicit_hyphen_keys, start, end) {
+                Ok(key) => system.keys[key].implicit_hyphen = true,
+                Err(error) => return Err(error)
+            }
+            pos = end;
+        }
+
+        // the right bank starts at the first right-side key that needs a
+        // hyphen, so for English Stenotype, that's -F, not -E.
+        system.right_bank_start = system.num_keys;
+        system.first_right_key = system.num_keys;
+        let mut index = system.num_keys;
+        let mut after_implicit_hyphen_keys = true;
+        while index > 0 {
+            index -= 1;
+            let key = system.keys[index];
+            if key.implicit_hyphen {
+                after_implicit_hyphen_keys = false;
+            }
+            if key.side as u8 == Side::Right as u8 {
+                system.first_right_key = index;
+                if after_implicit_hyphen_keys {
+                    system.right_bank_start = index;
+                }
+            }
+        }
+
+        if let Some((start, end)) = next_word(number_key, 0) {
+            match system.find_key_by_name(number_key, start, end) {
+                Ok(key) => system.number_key = key as u8,
+                Err(error) => return Err(error)
+            }
+        }
+
+        let mut pos = 0;
+        while let Some((start, end)) = next_word(numbers, pos) {
+            // key:digit
+            if end - start < 3 || numbers[end - 2] != b':' || !numbers[end - 1].is_ascii_digit() {
+                return Err(b"Numbers have to be written like \"S-:1\".");
+            }
+
+            let digit = numbers[end - 1];
+            match system.find_key_by_name(numbers, start, end - 2) {
+                Ok(key) => {
+                    system.keys[key].digit = digit;
+                    system.digit_keys[(digit - b'0') as usize] = key as u8;
+                }
+                Err(error) => return Err(error)
+            }
+            pos = end;
+        }
+
+        let mut pos = 0;
+        system.undo_stroke = system.parse_stroke(undo_stroke, &mut pos);
+
+        // 3 bytes as long as the keys fit, so that English Stenotype
+        // doesn't get any bigger
+        system.stroke_width = if system.num_keys <= 24 {
+            StrokeWidth::ThreeBytes
+        }
+        else {
+            StrokeWidth::FourBytes
+        };
+
+        Ok(system)
+    }
+
+    const fn add_key(&mut self, list: &[u8], start: usize, end: usize) -> Result<(), &'static [u8]> {
+        if self.num_keys == MAX_KEYS {
+            return Err(b"This steno system has too many keys, the most we support is 32.");
+        }
+
+        let (label, side) = match parse_key(list, start, end) {
+            Ok(key) => key,
+            Err(error) => return Err(error)
+        };
+
+        let index = self.num_keys;
+        let first = self.first_key_with_label(label);
+
+        let mut key = Key::EMPTY;
+        key.side = side;
+        key.label_length = label.len() as u8;
+        let mut byte = 0;
+        while byte < label.len() {
+            key.label[byte] = label[byte];
+            byte += 1;
+        }
+        self.keys[index] = key;
+        self.num_keys += 1;
+
+        // add it to the end of the chain of keys with the same label
+        if first == NO_KEY {
+            if label.len() == 1 {
+                self.first_key[label[0] as usize] = index as u8;
+            }
+        }
+        else {
+            let mut last = first as usize;
+            while self.next_key_with_label[last] != NO_KEY {
+                last = self.next_key_with_label[last] as usize;
+            }
+            self.next_key_with_label[last] = index as u8;
+        }
+
+        Ok(())
+    }
+
+    // finds a key that has been added already, by the name it was added with
+    const fn find_key_by_name(&self, list: &[u8], start: usize, end: usize) -> Result<usize, &'static [u8]> {
+        let (label, side) = match parse_key(list, start, end) {
+            Ok(key) => key,
+            Err(error) => return Err(error)
+        };
+
+        let mut key = self.first_key_with_label(label);
+        while key != NO_KEY {
+            let candidate = self.keys[key as usize];
+            if candidate.side as u8 == side as u8 {
+                return Ok(key as usize);
+            }
+            key = self.next_key_with_label[key as usize];
+        }
+
+        Err(b"One of the keys used in the definition of this steno system doesn't exist.")
+    }
+
+    pub const fn num_keys(&self) -> usize {
+        self.num_keys
+    }
+
+    pub const fn right_bank_start(&self) -> usize {
+        self.right_bank_start
+    }
+
+    pub const fn first_right_key(&self) -> usize {
+        self.first_right_key
+    }
+
+    pub const fn stroke_width(&self) -> StrokeWidth {
+        self.stroke_width
+    }
+
+    // the same system with strokes packed differently
+    pub const fn with_stroke_width(mut self, stroke_width: StrokeWidth) -> Result<StenoSystem, &'static [u8]> {
+        if stroke_width as u8 == StrokeWidth::ThreeBytes as u8 && self.num_keys > 24 {
+            return Err(b"Strokes can only be packed into 3 bytes if there are at most 24 keys.");
+        }
+
+        self.stroke_width = stroke_width;
+        Ok(self)
+    }
+
+    // a hash of everything that decides what a packed stroke means: the
+    // keys with their sides, which of them make the hyphen unnecessary,
+    // and the number key. how the strokes are packed doesn't count.
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = WyHash::with_seed(1);
+        for key in &self.keys[..self.num_keys] {
+            hasher.write(&[key.label_length]);
+            hasher.write(key.label());
+            hasher.write(&[key.side as u8, key.implicit_hyphen as u8]);
+        }
+        hasher.write(&[self.number_key]);
+        hasher.finish()
+    }
+
+    pub const fn undo_stroke(&self) -> u32 {
+        self.undo_stroke
+    }
+
+    // the number key, if the system has one
+    pub const fn number_key(&self) -> Option<usize> {
+        if self.number_key == NO_KEY {
+            None
+        }
+        else {
+            Some(self.number_key as usize)
+        }
+    }
+
+    pub const fn is_number_key_label(&self, label: &[u8]) -> bool {
+        self.number_key != NO_KEY && labels_equal(self.keys[self.number_key as usize].label(), label)
+    }
+
+    // how the key is written, without the hyphen
+    pub const fn key_label(&self, key: usize) -> &[u8] {
+        self.keys[key].label()
+    }
+
+    // the digit (as an ascii character) that the key stands for
+    // together with the number key
+    pub const fn key_digit(&self, key: usize) -> Option<u8> {
+        match self.keys[key].digit {
+            0 => None,
+            digit => Some(digit)
+        }
+    }
+
+    pub const fn is_implicit_hyphen_key(&self, key: usize) -> bool {
+        self.keys[key].implicit_hyphen
+    }
+
+    // whether an earlier key has the same label, like the right star
+    // in extended English Stenotype. these need a hyphen in front of
+    // them if nothing else puts them on the right.
+    pub const fn has_repeated_label(&self, key: usize) -> bool {
+        self.first_key_with_label(self.keys[key].label()) as usize != key
+    }
+
+    pub const fn is_right_side_key(&self, key: usize) -> bool {
+        self.keys[key].side as u8 == Side::Right as u8
+    }
+
+    // whether label is a digit that stands for one of the keys
+    pub const fn is_digit(&self, label: &[u8]) -> bool {
+        label.len() == 1 && label[0].is_ascii_digit() && self.digit_keys[(label[0] - b'0') as usize] != NO_KEY
+    }
+
+    // the first key with this label. ascii labels are looked up in the
+    // table, everything else takes the slow path.
+    const fn first_key_with_label(&self, label: &[u8]) -> u8 {
+        if label.len() == 1 && label[0] < 0x80 {
+            return self.first_key[label[0] as usize];
+        }
+
+        let mut key = 0;
+        while key < self.num_keys {
+            if labels_equal(self.keys[key].label(), label) {
+                return key as u8;
+            }
+            key += 1;
+        }
+
+        NO_KEY
+    }
+
+    // finds the first key at or after next_key that is written as label,
+    // which is one (utf-8) character. digits are found as the key they
+    // stand for.
+    pub const fn find_key(&self, label: &[u8], next_key: usize) -> Option<usize> {
+        let mut key = self.first_key_with_label(label);
+        while key != NO_KEY {
+            if key as usize >= next_key {
+                return Some(key as usize);
+            }
+            key = self.next_key_with_label[key as usize];
+        }
+
+        if self.is_digit(label) {
+            let key = self.digit_keys[(label[0] - b'0') as usize] as usize;
+            if key >= next_key {
+                return Some(key);
+            }
+        }
+
+        None
+    }
+
+    // all keys that label could stand for, in steno order
+    // (this is only used for error messages, so it doesn't have to be fast)
+    pub fn candidates<'a>(&'a self, label: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+        (0..self.num_keys).filter(move |&key| {
+            self.keys[key].label() == label
+                || (self.is_digit(label) && key == self.digit_keys[(label[0] - b'0') as usize] as usize)
+        })
+    }
+
+    // parses one stroke starting at pos, up to the next slash or the end
+    // of the buffer. pos is a pointer, so the calling code can pick up
+    // where we left off. this is built for speed, so it doesn't complain
+    // about anything: keys that are out of order are taken from wherever
+    // they fit, and characters that aren't keys are ignored. (see
+    // validation.rs for the picky version.)
+    pub const fn parse_stroke(&self, buffer: &[u8], pos: &mut usize) -> u32 {
+        let mut stroke = 0;
+        let mut next_key = 0;
+
+        while *pos < buffer.len() {
+            let length = character_length(buffer, *pos);
+            let label = subslice(buffer, *pos, *pos + length);
+            *pos += length;
+
+            if label[0] == b'/' {
+                break;
+            }
+
+            if label[0] == b'-' {
+                if next_key < self.first_right_key {
+                    next_key = self.first_right_key;
+                }
+                continue;
+            }
+
+            let key = match self.find_key(label, next_key) {
+                Some(key) => {
+                    next_key = key + 1;
+                    key
+                }
+                // out of order, so just take the first one
+                None => match self.find_key(label, 0) {
+                    Some(key) => key,
+                    None => continue
+                }
+            };
+
+            stroke |= 1 << key;
+
+            if self.is_digit(label) && self.number_key != NO_KEY {
+                stroke |= 1 << self.number_key;
+            }
+        }
+
+        stroke
+    }
+
+    // loads a system description. this is a json object of strings, with
+    // the same fields as the parameters of StenoSystem::new, like this:
+    //
+    //   {
+    //     "name": "English Stenotype",
+    //     "keys": "# S- T- K- P- W- H- R- A- O- * -E -U -F -R -P -B -L -G -T -S -D -Z",
+    //     "implicit_hyphen_keys": "A- O- * -E -U",
+    //     "number_key": "#",
+    //     "numbers": "S-:1 T-:2 P-:3 H-:4 A-:5 O-:0 -F:6 -P:7 -L:8 -T:9",
+    //     "undo_stroke": "*",
+    //     "stroke_width": "3"
+    //   }
+    //
+    // the name is optional and only there for people reading the file.
+    // stroke_width is "3", "4" or "varint", and it's optional as well.
+    // the buffer is overwritten in the process.
+    pub fn from_json(buffer: &mut [u8]) -> InternalResult<StenoSystem> {
+        let mut read_pos = 0;
+        let mut write_pos = 0;
+        json::parse_json_in_place(buffer, &mut read_pos, &mut write_pos, true)?;
+
+        let mut keys = None;
+        let mut implicit_hyphen_keys: &[u8] = b"";
+        let mut number_key: &[u8] = b"";
+        let mut numbers: &[u8] = b"";
+        let mut undo_stroke: &[u8] = b"";
+        let mut stroke_width = None;
+
+        let mut fields = BufferIterator::new(&buffer[..write_pos]);
+        while let (Some(name), Some(value)) = (fields.next(), fields.next()) {
+            match name {
+                b"name" => (),
+                b"keys" => keys = Some(value),
+                b"implicit_hyphen_keys" => implicit_hyphen_keys = value,
+                b"number_key" => number_key = value,
+                b"numbers" => numbers = value,
+                b"undo_stroke" => undo_stroke = value,
+                b"stroke_width" => stroke_width = Some(match value {
+                    b"3" => StrokeWidth::ThreeBytes,
+                    b"4" => StrokeWidth::FourBytes,
+                    b"varint" => StrokeWidth::Varint,
+                    _ => return Err(system_error(b"The stroke width has to be \"3\", \"4\" or \"varint\"."))
+                }),
+                _ => return Err(system_error(b"The steno system description has a field we don't know."))
+            }
+        }
+
+        let keys = keys.ok_or(system_error(b"The steno system description doesn't say which keys there are."))?;
+
+        let system = StenoSystem::new(keys, implicit_hyphen_keys, number_key, numbers, undo_stroke)
+            .map_err(system_error)?;
+
+        match stroke_width {
+            Some(stroke_width) => system.with_stroke_width(stroke_width).map_err(system_error),
+            None => Ok(system)
+        }
+    }
+}
+
+fn system_error(details: &'static [u8]) -> InternalError<'static> {
+    error!(b"Sorry, we couldn't load your steno system.", details)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_english_stenotype() {
+        let system = &ENGLISH_STENOTYPE;
+        assert_eq!(system.num_keys(), 23);
+        assert_eq!(system.right_bank_start(), 13);
+        assert_eq!(system.number_key(), Some(0));
+        assert_eq!(system.undo_stroke(), 1 << 10);
+
+        assert_eq!(system.find_key(b"S", 0), Some(1));
+        assert_eq!(system.find_key(b"S", 2), Some(20));
+        assert_eq!(system.find_key(b"S", 21), None);
+        assert_eq!(system.find_key(b"6", 0), Some(13));
+        assert_eq!(system.find_key(b"C", 0), None);
+
+        let parse = |stroke: &[u8]| {
+            let mut pos = 0;
+            system.parse_stroke(stroke, &mut pos)
+        };
+
+        // keys that can only be on the right don't need a hyphen
+        assert_eq!(parse(b"TFT"), parse(b"T-FT"));
+        assert_eq!(parse(b"TKT"), (1 << 2) | (1 << 3) | (1 << 19));
+        assert_eq!(parse(b"50"), parse(b"#AO"));
+        assert_eq!(parse(b"1-9"), parse(b"#S-T"));
+    }
+
+    #[test]
+    fn test_extended_stenotype() {
+        let system = &EXTENDED_STENOTYPE;
+        assert_eq!(system.num_keys(), 27);
+        assert_eq!(system.stroke_width(), StrokeWidth::FourBytes);
+        assert_eq!(ENGLISH_STENOTYPE.stroke_width(), StrokeWidth::ThreeBytes);
+        assert_eq!(system.right_bank_start(), 16);
+        assert_eq!(system.first_right_key(), 13);
+
+        let parse = |stroke: &[u8]| {
+            let mut pos = 0;
+            system.parse_stroke(stroke, &mut pos)
+        };
+
+        assert_eq!(parse(b"^TEFT"), (1 << 1) | parse(b"TEFT"));
+        assert_eq!(parse(b"+TEFT"), (1 << 2) | parse(b"TEFT"));
+        assert_eq!(parse(b"TEFT#"), (1 << 26) | parse(b"TEFT"));
+        assert_eq!(parse(b"*"), 1 << 12);
+        assert_eq!(parse(b"-*"), 1 << 13);
+        assert_eq!(parse(b"**"), (1 << 12) | (1 << 13));
+        assert_eq!(parse(b"#^+STKPWHRAO**EUFRPBLGTSDZ#"), (1 << 27) - 1);
+        assert_eq!(parse(b"50"), parse(b"#-AO"));
+    }
+
+    #[test]
+    fn test_palantype() {
+        let system = &PALANTYPE;
+        assert_eq!(system.num_keys(), 29);
+        assert_eq!(system.stroke_width(), StrokeWidth::FourBytes);
+        assert_eq!(system.number_key(), None);
+        assert_eq!(system.right_bank_start(), 18);
+
+        let parse = |stroke: &[u8]| {
+            let mut pos = 0;
+            system.parse_stroke(stroke, &mut pos)
+        };
+
+        assert_eq!(parse(b"SCPTH+MFRNLYOEAUI.NLCMFRPT+SH"), (1 << 29) - 1);
+        assert_eq!(parse(b"N"), 1 << 9);
+        assert_eq!(parse(b"-N"), 1 << 18);
+        assert_eq!(parse(b"PAL"), (1 << 2) | (1 << 14) | (1 << 19));
+        assert_eq!(parse(b"PA-L"), parse(b"PAL"));
+        assert_eq!(parse(b"+-+"), (1 << 5) | (1 << 26));
+        // these aren't keys in palantype
+        assert_eq!(parse(b"K#"), 0);
+    }
+
+    #[test]
+    fn test_stroke_width() {
+        let mut buffer = [0u8; MAX_STROKE_SIZE];
+        for width in [StrokeWidth::ThreeBytes, StrokeWidth::FourBytes, StrokeWidth::Varint] {
+            for stroke in [0, 1, 0x7F, 0x80, 0x12_3456, 0xFF_FFFF] {
+                let length = width.pack(stroke, &mut buffer);
+                let mut pos = 0;
+                assert_eq!(width.unpack(&buffer[..length], &mut pos), Some(stroke));
+                assert_eq!(pos, length);
+            }
+        }
+
+        assert_eq!(StrokeWidth::FourBytes.pack(u32::MAX, &mut buffer), 4);
+        assert_eq!(StrokeWidth::Varint.pack(1 << 10, &mut buffer), 2);
+        assert_eq!(StrokeWidth::Varint.pack(u32::MAX, &mut buffer), 5);
+
+        let mut pos = 0;
+        assert_eq!(StrokeWidth::ThreeBytes.unpack(&[1, 2], &mut pos), None);
+        assert_eq!(StrokeWidth::Varint.unpack(&[0x81], &mut pos), None);
+        assert_eq!(StrokeWidth::ThreeBytes.unpack_all(&[1, 0, 0, 2, 0, 0, 3]).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(StrokeWidth::Varint.unpack_all(&[0x81, 0x01, 0x05]).collect::<Vec<_>>(), vec![0x81, 5]);
+
+        assert!(ENGLISH_STENOTYPE.with_stroke_width(StrokeWidth::Varint).is_ok());
+        assert!(PALANTYPE.with_stroke_width(StrokeWidth::ThreeBytes).is_err());
+
+        // the fingerprint only depends on what the strokes mean
+        let varint = ENGLISH_STENOTYPE.with_stroke_width(StrokeWidth::Varint).unwrap();
+        assert_eq!(varint.fingerprint(), ENGLISH_STENOTYPE.fingerprint());
+        assert_ne!(EXTENDED_STENOTYPE.fingerprint(), ENGLISH_STENOTYPE.fingerprint());
+        let mut description = br#"{"keys": "S- T- -E"}"#.to_vec();
+        let system = StenoSystem::from_json(&mut description[..]).unwrap();
+        let mut description = br#"{"keys": "S- T- -T"}"#.to_vec();
+        assert_ne!(StenoSystem::from_json(&mut description[..]).unwrap().fingerprint(), system.fingerprint());
+        let mut description = br#"{"keys": "S- T- -E", "implicit_hyphen_keys": "-E"}"#.to_vec();
+        assert_ne!(StenoSystem::from_json(&mut description[..]).unwrap().fingerprint(), system.fingerprint());
+
+        let mut description = br#"{"keys": "S- T- -E", "stroke_width": "varint"}"#.to_vec();
+        assert_eq!(StenoSystem::from_json(&mut description[..]).unwrap().stroke_width(), StrokeWidth::Varint);
+        let mut description = br#"{"keys": "S- T- -E", "stroke_width": "5"}"#.to_vec();
+        assert!(StenoSystem::from_json(&mut description[..]).is_err());
+    }
+
+    #[test]
+    fn test_system_from_json() {
+        let mut description = br##"{
+            "name": "Tiny",
+            "keys": "# S- T- A- * -E -T -S",
+            "implicit_hyphen_keys": "A- * -E",
+            "number_key": "#",
+            "numbers": "S-:1 A-:5 -T:9",
+            "undo_stroke": "*"
+        }"##.to_vec();
+        let system = StenoSystem::from_json(&mut description[..]).unwrap();
+
+        assert_eq!(system.num_keys(), 8);
+        assert_eq!(system.right_bank_start(), 6);
+        assert_eq!(system.undo_stroke(), 1 << 4);
+
+        let mut pos = 0;
+        assert_eq!(system.parse_stroke(b"STAETS/", &mut pos), 0b1110_1110);
+        assert_eq!(pos, 7);
+        let mut pos = 0;
+        assert_eq!(system.parse_stroke(b"15-9", &mut pos), 0b0100_1011);
+
+        let mut unknown_key = br#"{"keys": "S- T-", "numbers": "K-:1"}"#.to_vec();
+        assert!(StenoSystem::from_json(&mut unknown_key[..]).is_err());
+
+        let mut no_keys = br##"{"number_key": "#"}"##.to_vec();
+        assert!(StenoSystem::from_json(&mut no_keys[..]).is_err());
+
+        let mut bad_label = br#"{"keys": "S- TH-"}"#.to_vec();
+        assert!(StenoSystem::from_json(&mut bad_label[..]).is_err());
+        let mut bad_label = b"{\"keys\": \"S- \xC3-\"}".to_vec();
+        assert!(StenoSystem::from_json(&mut bad_label[..]).is_err());
+
+        // one key more than fits into a stroke
+        let labels = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefg";
+        let description = |count: usize| {
+            let keys: Vec<String> = labels[..count].iter().map(|&label| format!("{}-", label as char)).collect();
+            format!(r#"{{"keys": "{}"}}"#, keys.join(" ")).into_bytes()
+        };
+        assert_eq!(StenoSystem::from_json(&mut description(MAX_KEYS)[..]).unwrap().num_keys(), MAX_KEYS);
+        let error = StenoSystem::from_json(&mut description(MAX_KEYS + 1)[..]).err().unwrap();
+        assert_eq!(error.details, b"This steno system has too many keys, the most we support is 32.");
+    }
+
+    #[test]
+    fn test_non_ascii_labels() {
+        let mut description = r#"{
+            "keys": "ㄱ- ㄴ- S- ㅏ- ㅗ- -ㄱ -ㄴ -S -é",
+            "implicit_hyphen_keys": "ㅏ- ㅗ-"
+        }"#.as_bytes().to_vec();
+        let system = StenoSystem::from_json(&mut description[..]).unwrap();
+        assert_eq!(system.num_keys(), 9);
+        assert_eq!(system.right_bank_start(), 5);
+
+        let parse = |stroke: &str| {
+            let mut pos = 0;
+            system.parse_stroke(stroke.as_bytes(), &mut pos)
+        };
+
+        assert_eq!(parse("ㄱ"), 1 << 0);
+        assert_eq!(parse("-ㄱ"), 1 << 5);
+        assert_eq!(parse("ㄱㅏㄱ"), (1 << 0) | (1 << 3) | (1 << 5));
+        assert_eq!(parse("ㄴSㅗㄴSé"), 0b1_1101_0110);
+        assert_eq!(parse("é"), 1 << 8);
+        // characters that aren't keys are skipped as a whole
+        assert_eq!(parse("ㄱxéü"), (1 << 0) | (1 << 8));
+
+        let mut pos = 0;
+        assert_eq!(system.parse_stroke("ㄱ/ㄴ".as_bytes(), &mut pos), 1);
+        assert_eq!(pos, 4);
+    }
+}
@@ -0,0 +1,362 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// checks the stroke definitions of a dictionary for problems that the
+// loader doesn't catch. StenoSystem::parse_stroke is built for speed,
+// so it just ignores keys it doesn't know and puts keys that are out of
+// order wherever they fit, which means that a typo can silently turn a
+// stroke into a different one. this goes through the whole dictionary and reports
+// every problem it finds, instead of stopping at the first one.
+
+use crate::{BufferIterator, AllSourcesIterator, InternalError, error};
+use crate::system::{StenoSystem, character_length};
+
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+#[repr(u8)]
+pub enum Severity {
+    // the stroke is probably not what the author meant, but we know
+    // what to do with it
+    Warning = 0,
+    // the stroke doesn't make sense, and gets loaded as something else
+    Error = 1
+}
+
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+#[repr(u8)]
+pub enum Problem {
+    // a character that isn't a steno key, like 'C' or 'X'
+    UnknownKey = 0,
+    // a key that would have had to come earlier in the stroke
+    OutOfOrder = 1,
+    // nothing between two slashes, or no strokes at all
+    EmptyStroke = 2,
+    // the same key twice in one stroke
+    DuplicateKey = 3,
+    // the following are only reported in strict mode:
+    // a hyphen that isn't needed or isn't between the banks
+    MisplacedHyphen = 4,
+    // right-bank keys without a vowel, star or hyphen before them
+    MissingHyphen = 5,
+    // the number key out of place, or used together with digits
+    MisplacedNumberKey = 6
+}
+
+impl Problem {
+    pub fn severity(&self) -> Severity {
+        match self {
+            Problem::DuplicateKey => Severity::Warning,
+            _ => Severity::Error
+        }
+    }
+
+    // for error messages
+    pub fn description(&self) -> &'static [u8] {
+        match self {
+            Problem::UnknownKey => b"This isn't a key of the steno system.",
+            Problem::OutOfOrder => b"This key is out of steno order.",
+            Problem::EmptyStroke => b"One of the strokes is empty.",
+            Problem::DuplicateKey => b"This key is used twice in the same stroke.",
+            Problem::MisplacedHyphen => b"This hyphen is in the wrong place.",
+            Problem::MissingHyphen => b"There's a hyphen missing before this key.",
+            Problem::MisplacedNumberKey => b"The number key is in the wrong place."
+        }
+    }
+}
+
+pub struct Diagnostic<'a> {
+    // index of the dictionary in the stack
+    pub dictionary: u8,
+    // index of the entry within its dictionary
+    pub entry: usize,
+    // the strokes and translation as written in the dictionary
+    pub strokes: &'a [u8],
+    pub translation: &'a [u8],
+    // byte offset of the problem within strokes
+    pub offset: usize,
+    pub severity: Severity,
+    pub problem: Problem
+}
+
+// splits a stroke into (utf-8) characters, with their offsets
+fn characters(stroke: &[u8]) -> impl Iterator<Item = (usize, &[u8])> {
+    let mut offset = 0;
+    core::iter::from_fn(move || {
+        if offset == stroke.len() {
+            return None;
+        }
+
+        let start = offset;
+        offset += character_length(stroke, offset);
+        Some((start, &stroke[start..offset]))
+    })
+}
+
+// checks a single stroke (without slashes). returns the offset of the
+// first problem within the stroke and what the problem is.
+pub fn check_stroke(system: &StenoSystem, stroke: &[u8]) -> Option<(usize, Problem)> {
+    // keys before this index can't be used anymore
+    let mut next_key = 0;
+    let mut keys = 0u32;
+
+    for (offset, label) in characters(stroke) {
+        if label == b"-" {
+            if next_key > system.right_bank_start() {
+                return Some((offset, Problem::OutOfOrder));
+            }
+            next_key = next_key.max(system.first_right_key());
+            continue;
+        }
+
+        match system.find_key(label, next_key) {
+            Some(key) => {
+                keys |= 1 << key;
+                next_key = key + 1;
+            }
+            // plover doesn't mind where the number key goes
+            None if system.is_number_key_label(label) => (),
+            None => return Some((offset, diagnose_key(system, label, keys)))
+        }
+
+        if system.is_digit(label) || system.is_number_key_label(label) {
+            keys |= number_key_bit(system);
+        }
+    }
+
+    if keys == 0 {
+        return Some((0, Problem::EmptyStroke));
+    }
+
+    None
+}
+
+fn number_key_bit(system: &StenoSystem) -> u32 {
+    system.number_key().map_or(0, |key| 1 << key)
+}
+
+// we couldn't place a key, let's find out why
+fn diagnose_key(system: &StenoSystem, label: &[u8], keys: u32) -> Problem {
+    let mut candidates = system.candidates(label).peekable();
+
+    if candidates.peek().is_none() {
+        Problem::UnknownKey
+    }
+    else if candidates.all(|key| keys & (1 << key) != 0) {
+        Problem::DuplicateKey
+    }
+    else {
+        Problem::OutOfOrder
+    }
+}
+
+// parses a single stroke (without slashes), but only accepts it if it's
+// written exactly the way plover would write it: keys in steno order,
+// a hyphen only if there are right-bank keys and no vowel or star
+// (or whatever else makes the hyphen unnecessary in this system) before
+// them, or in front of a right-side key that would be mistaken for a
+// left-side one otherwise, and the number key only in its place and never together with
+// digits. returns the packed stroke (same bits as
+// StenoSystem::parse_stroke), or the offset of the character that broke
+// the rules.
+pub fn parse_stroke_strict(system: &StenoSystem, stroke: &[u8]) -> Result<u32, (usize, Problem)> {
+    let mut next_key = 0;
+    let mut keys = 0u32;
+
+    // whether a vowel, star or hyphen came before
+    let mut separated = false;
+    let mut hyphen = None;
+    let mut number_key = None;
+    let mut digits = false;
+
+    for (offset, label) in characters(stroke) {
+        if label == b"-" {
+            if separated || next_key > system.right_bank_start() {
+                return Err((offset, Problem::MisplacedHyphen));
+            }
+            separated = true;
+            hyphen = Some(offset);
+            next_key = next_key.max(system.first_right_key());
+            continue;
+        }
+
+        let key = match system.find_key(label, next_key) {
+            Some(key) => key,
+            None if system.is_number_key_label(label) => return Err((offset, Problem::MisplacedNumberKey)),
+            None => return Err((offset, diagnose_key(system, label, keys)))
+        };
+
+        if system.is_digit(label) {
+            if let Some(number_key_offset) = number_key {
+                return Err((number_key_offset, Problem::MisplacedNumberKey));
+            }
+            digits = true;
+        }
+        else if system.number_key() == Some(key) {
+            if digits {
+                return Err((offset, Problem::MisplacedNumberKey));
+            }
+            number_key = Some(offset);
+        }
+
+        if key >= system.right_bank_start() {
+            if !separated {
+                return Err((offset, Problem::MissingHyphen));
+            }
+            hyphen = None;
+        }
+        else if system.is_implicit_hyphen_key(key) {
+            separated = true;
+            // like "-*" in extended English Stenotype
+            if system.is_right_side_key(key) && system.has_repeated_label(key) {
+                hyphen = None;
+            }
+        }
+
+        keys |= 1 << key;
+        next_key = key + 1;
+    }
+
+    // a hyphen has to be followed by right-bank keys
+    if let Some(offset) = hyphen {
+        return Err((offset, Problem::MisplacedHyphen));
+    }
+
+    if keys == 0 {
+        return Err((0, Problem::EmptyStroke));
+    }
+
+    if digits {
+        keys |= number_key_bit(system);
+    }
+
+    Ok(keys)
+}
+
+// checks all strokes of one entry, calling report for every stroke
+// that has a problem. in strict mode, every stroke that
+// parse_stroke_strict doesn't accept counts as a problem.
+fn check_strokes<F>(system: &StenoSystem, strokes: &[u8], strict: bool, mut report: F)
+    where F: FnMut(usize, Problem)
+{
+    let mut stroke_start = 0;
+
+    for stroke in strokes.split(|&byte| byte == b'/') {
+        let problem = if strict {
+            parse_stroke_strict(system, stroke).err()
+        }
+        else {
+            check_stroke(system, stroke)
+        };
+
+        if let Some((offset, problem)) = problem {
+            report(stroke_start + offset, problem);
+        }
+        stroke_start += stroke.len() + 1;
+    }
+}
+
+// what the loaders return in strict mode if there were any problems. the
+// details are in the diagnostics, which have been reported by then.
+pub(crate) fn strict_error() -> InternalError<'static> {
+    error!(b"Sorry, we couldn't load your dictionary because some of its strokes aren't written in steno order.",
+           b"Strict checking is enabled, so every stroke has to be written exactly the way Plover would write it.")
+}
+
+// goes through the intermediate format and reports every problem.
+// returns whether there were any.
+pub(crate) fn validate_intermediate<F>(intermediate: &[u8], dictionary_ends: &[usize], system: &StenoSystem, strict: bool, mut report: F) -> bool
+    where F: FnMut(Diagnostic)
+{
+    let mut entries = BufferIterator::new(intermediate);
+    let sources = AllSourcesIterator::new(intermediate, dictionary_ends);
+
+    let mut current_source = 0;
+    let mut entry = 0;
+    let mut found_problems = false;
+
+    for source in sources {
+        let strokes = entries.next().unwrap();
+        let translation = entries.next().unwrap();
+
+        if source != current_source {
+            current_source = source;
+            entry = 0;
+        }
+
+        check_strokes(system, strokes, strict, |offset, problem| {
+            found_problems = true;
+            report(Diagnostic {
+                dictionary: source,
+                entry,
+                strokes,
+                translation,
+                offset,
+                severity: problem.severity(),
+                problem
+            });
+        });
+
+        entry += 1;
+    }
+
+    found_problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::{ENGLISH_STENOTYPE, EXTENDED_STENOTYPE};
+
+    #[test]
+    fn test_check_stroke() {
+        assert_eq!(check_stroke(&ENGLISH_STENOTYPE, b"KPWHREPLGS"), None);
+        assert_eq!(check_stroke(&ENGLISH_STENOTYPE, b"K-FRBL"), None);
+        assert_eq!(check_stroke(&ENGLISH_STENOTYPE, b"#AO"), None);
+        assert_eq!(check_stroke(&ENGLISH_STENOTYPE, b"50"), None);
+        assert_eq!(check_stroke(&ENGLISH_STENOTYPE, b"TS"), None);
+        assert_eq!(check_stroke(&ENGLISH_STENOTYPE, b"1234EU6789"), None);
+
+        assert_eq!(check_stroke(&ENGLISH_STENOTYPE, b"KAT"), None);
+        assert_eq!(check_stroke(&ENGLISH_STENOTYPE, b"CAT"), Some((0, Problem::UnknownKey)));
+        assert_eq!(check_stroke(&ENGLISH_STENOTYPE, b"WKPT"), Some((1, Problem::OutOfOrder)));
+        assert_eq!(check_stroke(&ENGLISH_STENOTYPE, b"TPR-A"), Some((4, Problem::OutOfOrder)));
+        assert_eq!(check_stroke(&ENGLISH_STENOTYPE, b"RAEUR"), None);
+        assert_eq!(check_stroke(&ENGLISH_STENOTYPE, b"RAOA"), Some((3, Problem::DuplicateKey)));
+        assert_eq!(check_stroke(&ENGLISH_STENOTYPE, b"**"), Some((1, Problem::DuplicateKey)));
+        assert_eq!(check_stroke(&ENGLISH_STENOTYPE, b""), Some((0, Problem::EmptyStroke)));
+        assert_eq!(check_stroke(&ENGLISH_STENOTYPE, b"-"), Some((0, Problem::EmptyStroke)));
+    }
+
+    #[test]
+    fn test_parse_stroke_strict() {
+        assert_eq!(parse_stroke_strict(&ENGLISH_STENOTYPE, b"KPWHREPLGS"), Ok(1476856));
+        assert_eq!(parse_stroke_strict(&ENGLISH_STENOTYPE, b"K-FRBL"), Ok(221192));
+        assert_eq!(parse_stroke_strict(&ENGLISH_STENOTYPE, b"#AO"), Ok(769));
+        assert_eq!(parse_stroke_strict(&ENGLISH_STENOTYPE, b"50"), Ok(769));
+        assert_eq!(parse_stroke_strict(&ENGLISH_STENOTYPE, b"1-9"), Ok(1 | (1 << 1) | (1 << 19)));
+        assert_eq!(parse_stroke_strict(&ENGLISH_STENOTYPE, b"-G"), Ok(1 << 18));
+        assert_eq!(parse_stroke_strict(&ENGLISH_STENOTYPE, b"T-P"), Ok((1 << 2) | (1 << 15)));
+        assert_eq!(parse_stroke_strict(&ENGLISH_STENOTYPE, b"TP"), Ok((1 << 2) | (1 << 4)));
+        assert_eq!(parse_stroke_strict(&ENGLISH_STENOTYPE, b"#"), Ok(1));
+
+        assert_eq!(parse_stroke_strict(&ENGLISH_STENOTYPE, b"WKPT"), Err((1, Problem::OutOfOrder)));
+        assert_eq!(parse_stroke_strict(&ENGLISH_STENOTYPE, b"TKPWG"), Err((4, Problem::MissingHyphen)));
+        assert_eq!(parse_stroke_strict(&ENGLISH_STENOTYPE, b"KA-T"), Err((2, Problem::MisplacedHyphen)));
+        assert_eq!(parse_stroke_strict(&ENGLISH_STENOTYPE, b"K-"), Err((1, Problem::MisplacedHyphen)));
+        assert_eq!(parse_stroke_strict(&ENGLISH_STENOTYPE, b"-"), Err((0, Problem::MisplacedHyphen)));
+        assert_eq!(parse_stroke_strict(&ENGLISH_STENOTYPE, b"S#"), Err((1, Problem::MisplacedNumberKey)));
+        assert_eq!(parse_stroke_strict(&ENGLISH_STENOTYPE, b"#50"), Err((0, Problem::MisplacedNumberKey)));
+        assert_eq!(parse_stroke_strict(&ENGLISH_STENOTYPE, b"CAT"), Err((0, Problem::UnknownKey)));
+        assert_eq!(parse_stroke_strict(&ENGLISH_STENOTYPE, b"AA"), Err((1, Problem::DuplicateKey)));
+        assert_eq!(parse_stroke_strict(&ENGLISH_STENOTYPE, b""), Err((0, Problem::EmptyStroke)));
+
+        assert_eq!(parse_stroke_strict(&EXTENDED_STENOTYPE, b"^TEFT"), Ok((1 << 1) | (1 << 4) | (1 << 14) | (1 << 16) | (1 << 22)));
+        assert_eq!(parse_stroke_strict(&EXTENDED_STENOTYPE, b"-*"), Ok(1 << 13));
+        assert_eq!(parse_stroke_strict(&EXTENDED_STENOTYPE, b"**"), Ok((1 << 12) | (1 << 13)));
+        assert_eq!(parse_stroke_strict(&EXTENDED_STENOTYPE, b"-TS#"), Ok((1 << 22) | (1 << 23) | (1 << 26)));
+        assert_eq!(parse_stroke_strict(&EXTENDED_STENOTYPE, b"-E"), Err((0, Problem::MisplacedHyphen)));
+        assert_eq!(parse_stroke_strict(&EXTENDED_STENOTYPE, b"^^"), Err((1, Problem::DuplicateKey)));
+    }
+}
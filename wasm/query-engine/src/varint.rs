@@ -0,0 +1,106 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// the length headers in the intermediate format and in the hash table
+// entries. every byte holds 7 bits of the length, lowest bits first, and
+// the top bit is set if another byte follows. so lengths below 128 take
+// up one byte, lengths below 16384 two bytes, and so on, and there is no
+// upper limit on how long an entry can be.
+//
+// since we usually don't know how long a string is going to be before
+// we've read it, the parsers reserve one byte for the header, and move
+// the string back a bit if it turns out to need more than that.
+
+// the largest header we can get on 32-bit platforms
+pub const MAX_SIZE: usize = 5;
+
+// the number of bytes needed to encode length
+pub fn size(length: usize) -> usize {
+    let mut size = 1;
+    let mut rest = length >> 7;
+
+    while rest > 0 {
+        size += 1;
+        rest >>= 7;
+    }
+
+    size
+}
+
+// writes length into buffer starting at offset, returns the number of
+// bytes written. there has to be enough space for size(length) bytes.
+pub fn write(buffer: &mut [u8], offset: usize, length: usize) -> usize {
+    let size = size(length);
+
+    for index in 0..size {
+        let mut byte = ((length >> (7 * index)) & 0x7F) as u8;
+        if index + 1 < size {
+            byte |= 0x80;
+        }
+        buffer[offset + index] = byte;
+    }
+
+    size
+}
+
+// reads a length starting at offset. returns the length and the size
+// of the header.
+pub fn read(buffer: &[u8], offset: usize) -> (usize, usize) {
+    let mut length = 0;
+    let mut index = 0;
+
+    loop {
+        let byte = buffer[offset + index];
+        length |= ((byte & 0x7F) as usize) << (7 * index);
+        index += 1;
+
+        if byte & 0x80 == 0 {
+            return (length, index);
+        }
+    }
+}
+
+// for strings that were written after a one-byte placeholder at
+// header_offset: moves the string back if its length doesn't fit into
+// one byte, and fills in the header. the caller has to make sure that
+// there are size(length) - 1 bytes of space after write_pos.
+pub fn finish_header(buffer: &mut [u8], header_offset: usize, write_pos: &mut usize) {
+    let length = *write_pos - header_offset - 1;
+    let extra = size(length) - 1;
+
+    if extra > 0 {
+        buffer.copy_within(header_offset + 1 .. *write_pos, header_offset + 1 + extra);
+        *write_pos += extra;
+    }
+
+    write(buffer, header_offset, length);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_varint() {
+        let mut buffer = [0u8; MAX_SIZE];
+
+        for &length in &[0, 1, 127, 128, 300, 16383, 16384, 65535, 65536, 1 << 21, u32::MAX as usize] {
+            let written = write(&mut buffer, 0, length);
+            assert_eq!(written, size(length));
+            assert_eq!(read(&buffer, 0), (length, written));
+        }
+
+        assert_eq!(size(127), 1);
+        assert_eq!(size(128), 2);
+        assert_eq!(size(65535), 3);
+
+        let mut buffer = [0u8; 140];
+        buffer[1..130].fill(b'x');
+        let mut write_pos = 130;
+        finish_header(&mut buffer, 0, &mut write_pos);
+        assert_eq!(write_pos, 131);
+        assert_eq!(read(&buffer, 0), (129, 2));
+        assert!(buffer[2..131].iter().all(|&byte| byte == b'x'));
+    }
+}
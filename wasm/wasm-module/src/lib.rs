@@ -5,19 +5,31 @@
 #![no_std]
 
 use core::mem::size_of;
+use core::ops::Range;
 //use core::fmt::Write;
 //use core::convert::TryInto;
 //use core::borrow::Borrow;
-use query_engine::{self, error, InternalError, DataStructuresContainer};
+use query_engine::{self, error, InternalError, InternalResult, DataStructuresContainer, QueryResult, Diagnostic, Collision, DuplicatePolicy, LoadOptions};
+use query_engine::{StenoSystem, ENGLISH_STENOTYPE, FormatOptions, StrokePattern, PatternPosition};
+use query_engine::{IntermediateStorage, StreamingLoader};
 
 #[link(wasm_import_module = "env")]
-extern { fn logErr(message_offset: u32, message_length: u32, details_offset: u32, details_length: u32, line: u32); }
+extern { fn logErr(message_offset: u32, message_length: u32, details_offset: u32, details_length: u32, line: u32,
+                   input_offset: u32, input_line: u32, input_column: u32, excerpt_offset: u32, excerpt_length: u32); }
 
 fn log_err_internal(error: InternalError) {
+    // input_line is 0 if we don't know where in the input the error happened
+    let (input_offset, input_line, input_column, excerpt) = match error.position.as_ref() {
+        Some(position) => (position.offset, position.line, position.column, position.excerpt()),
+        None => (0, 0, 0, b"".as_ref())
+    };
+
     unsafe {
         logErr(error.message.as_ptr() as u32, error.message.len() as u32,
                error.details.as_ptr() as u32, error.details.len() as u32,
-               error.line);
+               error.line,
+               input_offset, input_line, input_column,
+               excerpt.as_ptr() as u32, excerpt.len() as u32);
     }
 }
 
@@ -36,7 +48,8 @@ fn panic(_info: &core::panic::PanicInfo) -> ! {
     // log_err_internal(InternalError {
     //     message: &message[..end],
     //     details: b"".as_ref(),
-    //     line: info.location().map_or(0, |loc| loc.line())
+    //     line: info.location().map_or(0, |loc| loc.line()),
+    //     position: None
     // });
     core::arch::wasm32::unreachable();
 }
@@ -46,7 +59,7 @@ fn handle_loader_error(error: InternalError) -> ! {
     panic!();
 }
 
-const FORMAT_VERSION: u32 = 0x00_02_00_00;
+const FORMAT_VERSION: u32 = 0x00_10_00_00;
 
 #[repr(packed(4))]
 struct Header {
@@ -142,34 +155,528 @@ impl DataStructuresContainer for Container {
     }
 }
 
+// strict, duplicates and tolerant are the fields of LoadOptions, see
+// load_options for how they're encoded. this is the same for all loaders.
+#[no_mangle]
+pub unsafe extern fn load_json(offset: u32, length: u32, strict: u32, duplicates: u32, tolerant: u32) -> u32 {
+    let buffer = core::slice::from_raw_parts_mut(
+        offset as *mut u8,
+        length as usize
+    );
+    let length = buffer.len();
+    let container = query_engine::load_dictionary_stack_with_report::<Container, _, _>(buffer, &[length], load_options(strict, duplicates, tolerant), yield_diagnostic_internal, yield_collision_internal).map_err(handle_loader_error).unwrap();
+
+    return (container.header as *const Header) as u32;
+}
+
+// same as load_json, but for RTF/CRE dictionaries
 #[no_mangle]
-pub unsafe extern fn load_json(offset: u32, length: u32) -> u32 {
+pub unsafe extern fn load_rtf(offset: u32, length: u32, strict: u32, duplicates: u32, tolerant: u32) -> u32 {
     let buffer = core::slice::from_raw_parts_mut(
         offset as *mut u8,
         length as usize
     );
-    let container = query_engine::load_json_internal::<Container>(buffer).map_err(handle_loader_error).unwrap();
+    let container = query_engine::load_rtf_with_report::<Container, _, _>(buffer, load_options(strict, duplicates, tolerant), yield_diagnostic_internal, yield_collision_internal).map_err(handle_loader_error).unwrap();
 
     return (container.header as *const Header) as u32;
 }
 
+// loads a stack of dictionaries (json or RTF/CRE) that are stored back-to-back
+// starting at offset. lengths_offset points to an array of u32s with the
+// length of each dictionary, highest priority first.
+#[no_mangle]
+pub unsafe extern fn load_dictionary_stack(offset: u32, length: u32, lengths_offset: u32, num_dictionaries: u32, strict: u32, duplicates: u32, tolerant: u32) -> u32 {
+    let buffer = core::slice::from_raw_parts_mut(
+        offset as *mut u8,
+        length as usize
+    );
+    // usize is u32 on wasm32
+    let dictionary_lengths = core::slice::from_raw_parts(
+        lengths_offset as *const usize,
+        num_dictionaries as usize
+    );
+    let container = query_engine::load_dictionary_stack_with_report::<Container, _, _>(buffer, dictionary_lengths, load_options(strict, duplicates, tolerant), yield_diagnostic_internal, yield_collision_internal).map_err(handle_loader_error).unwrap();
+
+    return (container.header as *const Header) as u32;
+}
+
+#[link(wasm_import_module = "env")]
+extern { fn yield_diagnostic(dictionary: u32, entry: u32, strokes_offset: u32, strokes_length: u32, offset: u32, severity: u32, problem: u32); }
+
+fn yield_diagnostic_internal(diagnostic: Diagnostic) {
+    unsafe {
+        yield_diagnostic(
+            diagnostic.dictionary as u32, diagnostic.entry as u32,
+            diagnostic.strokes.as_ptr() as u32, diagnostic.strokes.len() as u32,
+            diagnostic.offset as u32,
+            diagnostic.severity as u32, diagnostic.problem as u32);
+    }
+}
+
 #[link(wasm_import_module = "env")]
-extern { fn yield_result(string_offset: u32, string_length: u32, stroke_offset: u32, stroke_length: u32); }
+extern { fn yield_collision(dictionary: u32, entry: u32, strokes_offset: u32, strokes_length: u32,
+                            translation_offset: u32, translation_length: u32,
+                            previous_translation_offset: u32, previous_translation_length: u32); }
 
-fn yield_result_internal(strokes: &[u8], translation: &[u8]) {
+fn yield_collision_internal(collision: Collision) {
+    unsafe {
+        yield_collision(
+            collision.dictionary as u32, collision.entry as u32,
+            collision.strokes.as_ptr() as u32, collision.strokes.len() as u32,
+            collision.translation.as_ptr() as u32, collision.translation.len() as u32,
+            collision.previous_translation.as_ptr() as u32, collision.previous_translation.len() as u32);
+    }
+}
+
+// the steno system that the loaders use, see load_steno_system
+static mut STENO_SYSTEM: StenoSystem = ENGLISH_STENOTYPE;
+
+// sets the steno system for all dictionaries that are loaded after this.
+// the description is a json object (see StenoSystem::from_json) of length
+// bytes at offset, which gets overwritten. a length of 0 goes back to
+// English Stenotype.
+#[no_mangle]
+pub unsafe extern fn load_steno_system(offset: u32, length: u32) {
+    let system = if length == 0 {
+        ENGLISH_STENOTYPE
+    }
+    else {
+        let buffer = core::slice::from_raw_parts_mut(
+            offset as *mut u8,
+            length as usize
+        );
+        StenoSystem::from_json(buffer).map_err(handle_loader_error).unwrap()
+    };
+
+    *core::ptr::addr_of_mut!(STENO_SYSTEM) = system;
+}
+
+// strict and tolerant are 0 or 1, duplicates is one of the values of DuplicatePolicy
+unsafe fn load_options(strict: u32, duplicates: u32, tolerant: u32) -> LoadOptions<'static> {
+    let duplicates = match duplicates {
+        1 => DuplicatePolicy::FirstWins,
+        2 => DuplicatePolicy::KeepAll,
+        _ => DuplicatePolicy::LastWins
+    };
+
+    LoadOptions {
+        system: &*core::ptr::addr_of!(STENO_SYSTEM),
+        strict: strict != 0,
+        duplicates,
+        tolerant: tolerant != 0
+    }
+}
+
+// only checks the strokes of a stack of dictionaries, without building
+// anything. the parameters are the same as for load_dictionary_stack,
+// and every problem is passed to yield_diagnostic.
+#[no_mangle]
+pub unsafe extern fn validate_dictionary_stack(offset: u32, length: u32, lengths_offset: u32, num_dictionaries: u32, strict: u32, tolerant: u32) {
+    let buffer = core::slice::from_raw_parts_mut(
+        offset as *mut u8,
+        length as usize
+    );
+    let dictionary_lengths = core::slice::from_raw_parts(
+        lengths_offset as *const usize,
+        num_dictionaries as usize
+    );
+    query_engine::validate_dictionary_stack_internal(buffer, dictionary_lengths, load_options(strict, 0, tolerant), yield_diagnostic_internal).map_err(handle_loader_error).unwrap();
+}
+
+const WASM_PAGE_SIZE: usize = 65536;
+
+// the streaming loader keeps the intermediate format in a buffer at the
+// end of the wasm memory, which grows one page at a time. this only works
+// as long as nobody else grows the memory while we're loading, which is
+// why js has to copy the chunks into the buffer that load_begin returns.
+struct GrowingBuffer {
+    start: usize,
+    length: usize,
+    capacity: usize
+}
+
+impl IntermediateStorage for GrowingBuffer {
+    fn len(&self) -> usize {
+        self.length
+    }
+
+    fn push(&mut self, byte: u8) -> InternalResult<()> {
+        if self.length == self.capacity {
+            if core::arch::wasm32::memory_grow(0, 1) == usize::MAX {
+                return Err(error!(b"Sorry, we ran out of memory while loading your dictionary.", b""));
+            }
+            self.capacity += WASM_PAGE_SIZE;
+        }
+
+        unsafe {
+            *((self.start + self.length) as *mut u8) = byte;
+        }
+        self.length += 1;
+        Ok(())
+    }
+
+    fn set(&mut self, offset: usize, byte: u8) {
+        assert!(offset < self.length);
+        unsafe {
+            *((self.start + offset) as *mut u8) = byte;
+        }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(self.start as *const u8, self.length)
+        }
+    }
+}
+
+static mut STREAMING_LOADER: Option<StreamingLoader<'static, GrowingBuffer>> = None;
+
+// starts loading a json dictionary in chunks. returns the offset of a
+// buffer of WASM_PAGE_SIZE bytes that js should copy each chunk into
+// before calling load_chunk. the options are the same as for load_json.
+#[no_mangle]
+pub unsafe extern fn load_begin(strict: u32, duplicates: u32, tolerant: u32) -> u32 {
+    let chunk_buffer_start = core::arch::wasm32::memory_grow(0, 1) * WASM_PAGE_SIZE;
+    let intermediate_start = core::arch::wasm32::memory_size(0) * WASM_PAGE_SIZE;
+
+    let storage = GrowingBuffer {
+        start: intermediate_start,
+        length: 0,
+        capacity: 0
+    };
+
+    *core::ptr::addr_of_mut!(STREAMING_LOADER) = Some(StreamingLoader::new(storage, load_options(strict, duplicates, tolerant)));
+
+    return chunk_buffer_start as u32;
+}
+
+// feeds the next length bytes of the dictionary, which js has copied
+// into the chunk buffer.
+#[no_mangle]
+pub unsafe extern fn load_chunk(chunk_buffer_offset: u32, length: u32) {
+    let loader = (*core::ptr::addr_of_mut!(STREAMING_LOADER)).as_mut()
+        .unwrap_or_else(|| handle_loader_error(error!(b"Internal error: load_chunk was called before load_begin.", b"")));
+
+    // the chunk buffer is one page, anything longer would be read
+    // from whatever comes after it
+    if length as usize > WASM_PAGE_SIZE {
+        handle_loader_error(error!(b"Internal error: the chunk is larger than the chunk buffer.", b""));
+    }
+
+    let chunk = core::slice::from_raw_parts(
+        chunk_buffer_offset as *const u8,
+        length as usize
+    );
+
+    loader.load_chunk(chunk).map_err(handle_loader_error).unwrap();
+}
+
+// builds the hash tables once all chunks have been loaded. the return
+// value is the same as for load_json.
+#[no_mangle]
+pub unsafe extern fn load_finish() -> u32 {
+    let loader = (*core::ptr::addr_of_mut!(STREAMING_LOADER)).take()
+        .unwrap_or_else(|| handle_loader_error(error!(b"Internal error: load_finish was called before load_begin.", b"")));
+
+    let container = loader.finish::<Container, _, _>(yield_diagnostic_internal, yield_collision_internal).map_err(handle_loader_error).unwrap();
+
+    return (container.header as *const Header) as u32;
+}
+
+#[link(wasm_import_module = "env")]
+extern { fn yield_result(string_offset: u32, string_length: u32, stroke_offset: u32, stroke_length: u32, source: u32, exact: u32, attachment: u32,
+                         match_start: u32, match_length: u32); }
+
+fn yield_result_internal(result: QueryResult, span: Range<usize>) {
     unsafe {
         yield_result(
-            translation.as_ptr() as u32, translation.len() as u32,
-            strokes.as_ptr() as u32, strokes.len() as u32);
+            result.translation.as_ptr() as u32, result.translation.len() as u32,
+            result.strokes.as_ptr() as u32, result.strokes.len() as u32,
+            result.source as u32, result.exact as u32, result.attachment() as u32,
+            span.start as u32, span.len() as u32);
     }
 }
 
+// formatted strokes that don't fit into this are reported as an error
+const FORMAT_BUFFER_LENGTH: usize = 1024;
+
+#[link(wasm_import_module = "env")]
+extern { fn yield_normalized_strokes(text_offset: u32, text_length: u32, strokes_offset: u32, strokes_length: u32); }
+
+// parses the strokes that someone typed in (length bytes at offset, which
+// get overwritten) with the current steno system, and passes them to
+// yield_normalized_strokes, both written the way plover would write them
+// and packed, ready for query. returns 0 (after calling logErr) if the
+// strokes don't make sense, 1 otherwise.
+#[no_mangle]
+pub unsafe extern fn normalize_stroke_text(offset: u32, length: u32) -> u32 {
+    let input = core::slice::from_raw_parts_mut(
+        offset as *mut u8,
+        length as usize
+    );
+
+    let mut text = [0u8; FORMAT_BUFFER_LENGTH];
+    let mut strokes = [0u8; FORMAT_BUFFER_LENGTH];
+    let system = &*core::ptr::addr_of!(STENO_SYSTEM);
+
+    match query_engine::normalize_stroke_text(system, input, &FormatOptions::default(), &mut text, &mut strokes) {
+        Ok((text_length, strokes_length)) => {
+            yield_normalized_strokes(
+                text.as_ptr() as u32, text_length as u32,
+                strokes.as_ptr() as u32, strokes_length as u32);
+            1
+        }
+        Err(error) => {
+            log_err_internal(error);
+            0
+        }
+    }
+}
+
+fn yield_formatted_result(system: &StenoSystem, options: &FormatOptions, result: QueryResult, span: Range<usize>) -> InternalResult<()> {
+    let mut text = [0u8; FORMAT_BUFFER_LENGTH];
+    let length = query_engine::format_strokes(system, result.strokes, options, &mut text)?;
+
+    unsafe {
+        yield_result(
+            result.translation.as_ptr() as u32, result.translation.len() as u32,
+            text.as_ptr() as u32, length as u32,
+            result.source as u32, result.exact as u32, result.attachment() as u32,
+            span.start as u32, span.len() as u32);
+    }
+    Ok(())
+}
+
 // if find_stroke == 0, performs a normal lookup using the query term starting at the given offset
 //                      with the given length
 // if find_stroke == 1, performs a stroke lookup by interpreting the offset field as a stroke. length is unused.
+// if find_stroke == 2, performs a lookup like with 0, but ignores case and accents (see fold.rs in
+//                      query-engine). the exact parameter of yield_result says which results match
+//                      without that.
+// if find_stroke == 3, performs a lookup like with 0, but also finds translations with plover formatting
+//                      by their visible text, like "{^ing}" for "ing" (see markup.rs in query-engine).
+// if find_stroke == 4, finds every translation that contains the query, like "as a matter of fact" for
+//                      "a matter" (see substring_search_internal in query-engine).
+//
+// the attachment parameter of yield_result is one of the values of Attachment in markup.rs.
+// match_start and match_length say which bytes of the translation matched the query. that's the
+// whole translation, except for substring search.
+//
+// format says how the strokes are passed to yield_result: 0 for packed strokes (see StrokeWidth),
+// 1 for text the way plover writes it, and 2 for text that keeps the number key, like "#AO" instead
+// of "50". text is written for the current steno system (see load_steno_system), so that should
+// still be the one the dictionary was loaded with.
+#[no_mangle]
+pub unsafe extern fn query(offset: u32, length: u32, data_offset: usize, find_stroke: u8, format: u32) {
+
+    let mut container = container_at(data_offset);
+
+    let query = core::slice::from_raw_parts(
+        offset as *const u8,
+        length as usize
+    );
+
+    let mut yield_any_result = match result_formatter(&container, format) {
+        Ok(formatter) => formatter,
+        Err(error) => return log_err_internal(error)
+    };
+
+    let mut yield_whole_match = |result: QueryResult| {
+        let span = 0..result.translation.len();
+        yield_any_result(result, span);
+    };
+
+    match find_stroke {
+        0 => query_engine::query_internal(query, &mut container, &mut yield_whole_match).unwrap_or_else(log_err_internal),
+        1 => query_engine::find_strokes_internal(query, &mut container, &mut yield_whole_match).unwrap_or_else(log_err_internal),
+        2 => query_engine::folded_query_internal(query, &mut container, &mut yield_whole_match).unwrap_or_else(log_err_internal),
+        3 => query_engine::visible_text_query_internal(query, &mut container, &mut yield_whole_match).unwrap_or_else(log_err_internal),
+        _ => query_engine::substring_search_internal(query, &mut container, &mut yield_any_result).unwrap_or_else(log_err_internal)
+    }
+}
+
+// like query with find_stroke == 0 or 1, but passes the results to yield_result best
+// first (see ranking.rs in query-engine), and only the ones from skip to skip + limit.
+// the asterisk is looked up in the current steno system. returns the total number of
+// results.
+#[no_mangle]
+pub unsafe extern fn ranked_query(offset: u32, length: u32, data_offset: usize, find_stroke: u8, skip: u32, limit: u32, format: u32) -> u32 {
+
+    let mut container = container_at(data_offset);
+
+    let query = core::slice::from_raw_parts(
+        offset as *const u8,
+        length as usize
+    );
+
+    let mut yield_any_result = match result_formatter(&container, format) {
+        Ok(formatter) => formatter,
+        Err(error) => {
+            log_err_internal(error);
+            return 0;
+        }
+    };
+
+    let yield_whole_match = |result: QueryResult| {
+        let span = 0..result.translation.len();
+        yield_any_result(result, span);
+    };
+
+    let system = &*core::ptr::addr_of!(STENO_SYSTEM);
+    let total = match find_stroke {
+        0 => query_engine::ranked_query_internal(query, &mut container, system, skip as usize, limit as usize, yield_whole_match),
+        _ => query_engine::ranked_find_strokes_internal(query, &mut container, system, skip as usize, limit as usize, yield_whole_match)
+    };
+
+    total.unwrap_or_else(|error| {
+        log_err_internal(error);
+        0
+    }) as u32
+}
+
+// finds all translations starting with the length bytes at offset, and passes the
+// matches from skip to skip + limit to yield_result. data_offset and format are the
+// same as for query. returns the total number of matches.
+#[no_mangle]
+pub unsafe extern fn prefix_search(offset: u32, length: u32, data_offset: usize, skip: u32, limit: u32, format: u32) -> u32 {
+
+    let mut container = container_at(data_offset);
+
+    let prefix = core::slice::from_raw_parts(
+        offset as *const u8,
+        length as usize
+    );
+
+    let mut yield_any_result = match result_formatter(&container, format) {
+        Ok(formatter) => formatter,
+        Err(error) => {
+            log_err_internal(error);
+            return 0;
+        }
+    };
+
+    query_engine::prefix_search_internal(prefix, &mut container, skip as usize, limit as usize, |result| yield_any_result(result, 0..prefix.len()))
+        .unwrap_or_else(|error| {
+            log_err_internal(error);
+            0
+        }) as u32
+}
+
+// finds all outlines whose strokes match num_patterns patterns at patterns_offset (see
+// pattern.rs in query-engine). each pattern is two u32s: the keys the stroke must have,
+// and the keys it mustn't have, with the same bits as in the strokes. the offset must be
+// a multiple of 4. if position == 0, the patterns have to match the whole outline, stroke
+// by stroke, if position == 1, they can match anywhere in the outline, and if position ==
+// 2, they have to match starting at the stroke with the given index. data_offset and format
+// are the same as for query.
 #[no_mangle]
-pub unsafe extern fn query(offset: u32, length: u32, data_offset: usize, find_stroke: u8) {
+pub unsafe extern fn pattern_search(patterns_offset: u32, num_patterns: u32, position: u32, stroke_index: u32, data_offset: usize, format: u32) {
 
+    let mut container = container_at(data_offset);
+
+    let patterns = core::slice::from_raw_parts(
+        patterns_offset as *const StrokePattern,
+        num_patterns as usize
+    );
+
+    let position = match position {
+        0 => PatternPosition::Whole,
+        1 => PatternPosition::Anywhere,
+        _ => PatternPosition::At(stroke_index as usize)
+    };
+
+    let mut yield_any_result = match result_formatter(&container, format) {
+        Ok(formatter) => formatter,
+        Err(error) => return log_err_internal(error)
+    };
+
+    query_engine::pattern_search_internal(patterns, position, &mut container, |result| {
+        let span = 0..result.translation.len();
+        yield_any_result(result, span);
+    }).unwrap_or_else(log_err_internal);
+}
+
+// finds translations that are at most max_distance edits away from the length
+// bytes at offset, like "receive" for "recieve", and passes at most limit of them
+// to yield_result, the closest ones first. exact is set for the ones that match
+// the query. data_offset and format are the same as for query.
+#[no_mangle]
+pub unsafe extern fn fuzzy_query(offset: u32, length: u32, data_offset: usize, max_distance: u32, limit: u32, format: u32) {
+
+    let mut container = container_at(data_offset);
+
+    let query = core::slice::from_raw_parts(
+        offset as *const u8,
+        length as usize
+    );
+
+    let mut yield_any_result = match result_formatter(&container, format) {
+        Ok(formatter) => formatter,
+        Err(error) => return log_err_internal(error)
+    };
+
+    query_engine::fuzzy_query_internal(query, &mut container, max_distance as usize, limit as usize, |result, _distance| {
+        let span = 0..result.translation.len();
+        yield_any_result(result, span);
+    })
+        .unwrap_or_else(log_err_internal);
+}
+
+#[link(wasm_import_module = "env")]
+extern { fn yield_memory_usage(strokes_table: u32, strings_table: u32, folded_table: u32, visible_table: u32,
+                               translation_index: u32, length_index: u32, substring_index: u32, outline_index: u32,
+                               total: u32); }
+
+// passes the number of bytes that the dictionary at data_offset uses for each of
+// its tables and indexes to yield_memory_usage
+#[no_mangle]
+pub unsafe extern fn memory_usage(data_offset: usize) {
+
+    let mut container = container_at(data_offset);
+
+    match query_engine::get_memory_usage(&mut container) {
+        Ok(usage) => yield_memory_usage(
+            usage.strokes_table as u32, usage.strings_table as u32,
+            usage.folded_table as u32, usage.visible_table as u32,
+            usage.translation_index as u32, usage.length_index as u32,
+            usage.substring_index as u32, usage.outline_index as u32,
+            usage.total as u32),
+        Err(error) => log_err_internal(error)
+    }
+}
+
+// like prefix_search, but finds all outlines starting with the packed strokes (see
+// StrokeWidth) at offset, the shortest ones first. returns the total number of matches.
+#[no_mangle]
+pub unsafe extern fn outline_prefix_search(offset: u32, length: u32, data_offset: usize, skip: u32, limit: u32, format: u32) -> u32 {
+
+    let mut container = container_at(data_offset);
+
+    let prefix = core::slice::from_raw_parts(
+        offset as *const u8,
+        length as usize
+    );
+
+    let mut yield_any_result = match result_formatter(&container, format) {
+        Ok(formatter) => formatter,
+        Err(error) => {
+            log_err_internal(error);
+            return 0;
+        }
+    };
+
+    query_engine::outline_prefix_search_internal(prefix, &mut container, skip as usize, limit as usize, |result| {
+        let span = 0..result.translation.len();
+        yield_any_result(result, span);
+    })
+        .unwrap_or_else(|error| {
+            log_err_internal(error);
+            0
+        }) as u32
+}
+
+// the dictionary data that one of the loaders returned
+unsafe fn container_at(data_offset: usize) -> Container {
     let offset_info = &*(data_offset as *const Header);
 
     if offset_info.version != FORMAT_VERSION {
@@ -191,21 +698,30 @@ pub unsafe extern fn query(offset: u32, length: u32, data_offset: usize, find_st
         offset_info.u8_buffer_length
     );
 
-    let mut container = Container {
+    Container {
         header: offset_info,
         usize_buffer: usize_buffer,
         u8_buffer: u8_buffer
-    };
+    }
+}
 
-    let query = core::slice::from_raw_parts(
-        offset as *const u8,
-        length as usize
-    );
+// the callback for the query functions, which passes the results on to
+// yield_result in the given format (see query), along with the bytes of
+// the translation that matched
+unsafe fn result_formatter(container: &Container, format: u32) -> InternalResult<impl FnMut(QueryResult, Range<usize>)> {
+    let system = query_engine::get_container_system(container, &*core::ptr::addr_of!(STENO_SYSTEM))?;
 
-    if find_stroke == 0 {
-        query_engine::query_internal(query, &mut container, yield_result_internal).unwrap_or_else(log_err_internal);
-    }
-    else {
-        query_engine::find_strokes_internal(query, &mut container, yield_result_internal).unwrap_or_else(log_err_internal);
-    }
+    let options = FormatOptions {
+        numbers: format != 2,
+        ..FormatOptions::default()
+    };
+
+    Ok(move |result: QueryResult, span: Range<usize>| {
+        if format == 0 {
+            yield_result_internal(result, span);
+        }
+        else {
+            yield_formatted_result(&system, &options, result, span).unwrap_or_else(log_err_internal);
+        }
+    })
 }